@@ -1,7 +1,11 @@
 use super::MDNS_PORT;
+use crate::config::Config;
+use crate::interfaces::Interfaces;
+use crate::socket::Socket;
+use get_if_addrs::{IfAddr, Interface};
 #[cfg(not(windows))]
 use net2::unix::UnixUdpBuilderExt;
-use net2::UdpBuilder;
+use net2::{UdpBuilder, UdpSocketExt};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
@@ -9,26 +13,80 @@ pub enum Inet {}
 pub enum Inet6 {}
 
 pub trait AddressFamily {
-    fn bind() -> io::Result<UdpSocket> {
-        let addr = SocketAddr::new(Self::any_addr(), MDNS_PORT);
-        let builder = Self::socket_builder()?;
+    fn bind(config: &Config) -> io::Result<UdpSocket> {
+        Self::bind_port(MDNS_PORT, config)
+    }
+
+    fn bind_port(port: u16, config: &Config) -> io::Result<UdpSocket> {
+        let addr = SocketAddr::new(Self::any_addr(), port);
+        let builder = Self::socket_builder(config)?;
         builder.reuse_address(true)?;
         #[cfg(not(windows))]
         let _ = builder.reuse_port(true);
-        let socket = builder.bind(&addr)?;
-        Self::join_multicast(&socket)?;
+        let socket = builder.bind(addr)?;
+        if let Some(size) = config.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = config.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if config.unicast_via_arrival_interface {
+                crate::socket::enable_pktinfo(&socket, Self::v6())?;
+            }
+            if config.socket_per_interface {
+                crate::socket::disable_multicast_all(&socket, Self::v6())?;
+            }
+        }
+        Ok(socket)
+    }
+
+    /// Binds like `bind`, but only receiving what arrives on the interface
+    /// named `name`
+    fn bind_interface(config: &Config, name: &str) -> io::Result<UdpSocket> {
+        let socket = Self::bind(config)?;
+        bind_to_device(&socket, name)?;
         Ok(socket)
     }
 
-    fn socket_builder() -> io::Result<UdpBuilder>;
+    fn socket_builder(config: &Config) -> io::Result<UdpBuilder>;
     fn any_addr() -> IpAddr;
     fn mdns_group() -> IpAddr;
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()>;
+    /// The group to join and multicast to, `mdns_group` unless configured
+    /// otherwise
+    fn group(config: &Config) -> IpAddr;
+    /// Joins the group on whichever interface the OS picks
+    fn join_multicast(socket: &dyn Socket, config: &Config) -> io::Result<()>;
+    /// Joins the group on a specific interface, which must have an address
+    /// of this family (see `has_addr`)
+    fn join_multicast_on(
+        socket: &dyn Socket,
+        iface: &Interface,
+        interfaces: &dyn Interfaces,
+        config: &Config,
+    ) -> io::Result<()>;
+    /// Leaves the group joined by `join_multicast`
+    fn leave_multicast(socket: &dyn Socket, config: &Config) -> io::Result<()>;
+    /// Leaves the group joined by `join_multicast_on`
+    fn leave_multicast_on(
+        socket: &dyn Socket,
+        iface: &Interface,
+        interfaces: &dyn Interfaces,
+        config: &Config,
+    ) -> io::Result<()>;
+    /// Makes datagrams sent to the group leave through `iface`
+    fn set_multicast_if(
+        socket: &dyn Socket,
+        iface: &Interface,
+        interfaces: &dyn Interfaces,
+    ) -> io::Result<()>;
+    fn has_addr(iface: &Interface) -> bool;
     fn v6() -> bool;
 }
 
 impl AddressFamily for Inet {
-    fn socket_builder() -> io::Result<UdpBuilder> {
+    fn socket_builder(_config: &Config) -> io::Result<UdpBuilder> {
         UdpBuilder::new_v4()
     }
     fn any_addr() -> IpAddr {
@@ -37,8 +95,45 @@ impl AddressFamily for Inet {
     fn mdns_group() -> IpAddr {
         IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251))
     }
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()> {
-        socket.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &Ipv4Addr::new(0, 0, 0, 0))
+    fn group(config: &Config) -> IpAddr {
+        config
+            .multicast_group_v4
+            .map_or_else(Self::mdns_group, IpAddr::V4)
+    }
+    fn join_multicast(socket: &dyn Socket, config: &Config) -> io::Result<()> {
+        socket.join_multicast_v4(v4_group(config), Ipv4Addr::new(0, 0, 0, 0))
+    }
+    fn join_multicast_on(
+        socket: &dyn Socket,
+        iface: &Interface,
+        _interfaces: &dyn Interfaces,
+        config: &Config,
+    ) -> io::Result<()> {
+        socket.join_multicast_v4(v4_group(config), v4_addr(iface)?)
+    }
+    fn leave_multicast(socket: &dyn Socket, config: &Config) -> io::Result<()> {
+        socket.leave_multicast_v4(v4_group(config), Ipv4Addr::new(0, 0, 0, 0))
+    }
+    fn leave_multicast_on(
+        socket: &dyn Socket,
+        iface: &Interface,
+        _interfaces: &dyn Interfaces,
+        config: &Config,
+    ) -> io::Result<()> {
+        socket.leave_multicast_v4(v4_group(config), v4_addr(iface)?)
+    }
+    fn set_multicast_if(
+        socket: &dyn Socket,
+        iface: &Interface,
+        _interfaces: &dyn Interfaces,
+    ) -> io::Result<()> {
+        socket.set_multicast_if_v4(v4_addr(iface)?)
+    }
+    fn has_addr(iface: &Interface) -> bool {
+        match iface.addr {
+            IfAddr::V4(_) => true,
+            IfAddr::V6(_) => false,
+        }
     }
     fn v6() -> bool {
         false
@@ -46,8 +141,11 @@ impl AddressFamily for Inet {
 }
 
 impl AddressFamily for Inet6 {
-    fn socket_builder() -> io::Result<UdpBuilder> {
-        UdpBuilder::new_v6()
+    fn socket_builder(config: &Config) -> io::Result<UdpBuilder> {
+        let builder = UdpBuilder::new_v6()?;
+        // Set explicitly, as the default differs between platforms
+        builder.only_v6(config.v6_only)?;
+        Ok(builder)
     }
     fn any_addr() -> IpAddr {
         IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
@@ -55,10 +153,138 @@ impl AddressFamily for Inet6 {
     fn mdns_group() -> IpAddr {
         IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb))
     }
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()> {
-        socket.join_multicast_v6(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb), 0)
+    fn group(config: &Config) -> IpAddr {
+        config
+            .multicast_group_v6
+            .map_or_else(Self::mdns_group, IpAddr::V6)
+    }
+    fn join_multicast(socket: &dyn Socket, config: &Config) -> io::Result<()> {
+        socket.join_multicast_v6(&v6_group(config), 0)
+    }
+    fn join_multicast_on(
+        socket: &dyn Socket,
+        iface: &Interface,
+        interfaces: &dyn Interfaces,
+        config: &Config,
+    ) -> io::Result<()> {
+        let index = interfaces.index(&iface.name)?;
+        socket.join_multicast_v6(&v6_group(config), index)
+    }
+    fn leave_multicast(socket: &dyn Socket, config: &Config) -> io::Result<()> {
+        socket.leave_multicast_v6(&v6_group(config), 0)
+    }
+    fn leave_multicast_on(
+        socket: &dyn Socket,
+        iface: &Interface,
+        interfaces: &dyn Interfaces,
+        config: &Config,
+    ) -> io::Result<()> {
+        let index = interfaces.index(&iface.name)?;
+        socket.leave_multicast_v6(&v6_group(config), index)
+    }
+    fn set_multicast_if(
+        socket: &dyn Socket,
+        iface: &Interface,
+        interfaces: &dyn Interfaces,
+    ) -> io::Result<()> {
+        // ff02::fb is link-local, so without this the OS picks the link
+        socket.set_multicast_if_v6(interfaces.index(&iface.name)?)
+    }
+    fn has_addr(iface: &Interface) -> bool {
+        match iface.addr {
+            IfAddr::V4(_) => false,
+            IfAddr::V6(_) => true,
+        }
     }
     fn v6() -> bool {
         true
     }
 }
+
+/// Restricts `socket` to interface `name` with `SO_BINDTODEVICE`, which
+/// kernels before 5.7 only allow with `CAP_NET_RAW`
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, name: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+fn v4_group(config: &Config) -> Ipv4Addr {
+    config
+        .multicast_group_v4
+        .unwrap_or(Ipv4Addr::new(224, 0, 0, 251))
+}
+
+fn v6_group(config: &Config) -> Ipv6Addr {
+    config
+        .multicast_group_v6
+        .unwrap_or(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb))
+}
+
+fn v4_addr(iface: &Interface) -> io::Result<Ipv4Addr> {
+    match iface.addr {
+        IfAddr::V4(ref addr) => Ok(addr.ip),
+        IfAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "IPv6 interface address for an IPv4 socket",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AddressFamily, Inet, Inet6};
+    use crate::config::Config;
+    use net2::UdpSocketExt;
+
+    #[test]
+    fn v6_socket_is_v6_only_by_default() {
+        let socket = Inet6::bind_port(0, &Config::default()).unwrap();
+        assert!(socket.only_v6().unwrap());
+    }
+
+    #[test]
+    fn v6_only_can_be_disabled() {
+        let config = Config {
+            v6_only: false,
+            ..Config::default()
+        };
+        let socket = Inet6::bind_port(0, &config).unwrap();
+        assert!(!socket.only_v6().unwrap());
+    }
+
+    #[test]
+    fn applies_configured_buffer_sizes() {
+        let config = Config {
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: Some(32 * 1024),
+            ..Config::default()
+        };
+        let socket = Inet::bind_port(0, &config).unwrap();
+        // Linux reports twice what was set, unless capped below that
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 32 * 1024);
+    }
+}
@@ -0,0 +1,1053 @@
+use crate::address_family::AddressFamily;
+use crate::clock::{Clock, Sleep, SystemClock};
+use crate::config::Config;
+use crate::dns_parser::{self, Name, QueryClass, QueryType, RRData};
+use crate::socket::Socket;
+use log::{debug, trace, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, mpsc::error::TrySendError, oneshot},
+};
+
+use super::MDNS_PORT;
+
+/// The port a `Browser` sends its queries from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryPort {
+    /// Port 5353, shared with any responder on the host. Answers are
+    /// multicast, so other browsers on the network see them too.
+    Mdns,
+    /// A port picked by the OS, for hosts where 5353 can't be bound. Such
+    /// "legacy" queriers get unicast answers (RFC 6762 section 6.7).
+    Ephemeral,
+}
+
+impl QueryPort {
+    /// The port to bind, 0 leaving the choice to the OS
+    fn port(self) -> u16 {
+        match self {
+            QueryPort::Mdns => MDNS_PORT,
+            QueryPort::Ephemeral => 0,
+        }
+    }
+}
+
+/// Tunables for a `Browser`
+#[derive(Clone, Debug)]
+pub struct BrowserConfig {
+    pub query_port: QueryPort,
+    /// Multicast groups to query on instead of mDNS's own, finding the
+    /// responders with the same `Config::multicast_group_v4` and
+    /// `Config::multicast_group_v6`. Must be multicast addresses.
+    pub multicast_group_v4: Option<Ipv4Addr>,
+    pub multicast_group_v6: Option<Ipv6Addr>,
+}
+
+impl Default for BrowserConfig {
+    fn default() -> Self {
+        BrowserConfig {
+            query_port: QueryPort::Mdns,
+            multicast_group_v4: None,
+            multicast_group_v6: None,
+        }
+    }
+}
+
+impl BrowserConfig {
+    /// The options of a responder's socket that apply to ours too
+    fn socket_config(&self) -> Config {
+        Config {
+            multicast_group_v4: self.multicast_group_v4,
+            multicast_group_v6: self.multicast_group_v6,
+            ..Config::default()
+        }
+    }
+}
+
+/// What has been learned about a service instance so far
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiscoveredService {
+    /// The full instance name, e.g. "Printer._ipp._tcp.local"
+    pub name: String,
+    /// Host the service runs on, from its SRV record
+    pub target: Option<String>,
+    pub port: Option<u16>,
+    /// Raw TXT record data
+    pub txt: Option<Vec<u8>>,
+    /// Addresses of `target`, from A and AAAA records
+    pub addresses: Vec<IpAddr>,
+}
+
+/// A change to the instances a `Browser` knows of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BrowseEvent {
+    /// An instance was found, and its SRV record is known
+    Added(DiscoveredService),
+    /// The SRV or TXT record of a known instance changed
+    Updated(DiscoveredService),
+    /// An instance said goodbye, with what was known about it
+    Removed(DiscoveredService),
+}
+
+/// Percentages of an instance's TTL after which to ask for it again, before
+/// giving up on it once all of it has passed (RFC 6762 section 5.2)
+const REFRESH_AT: [u32; 4] = [80, 85, 90, 95];
+
+/// How long an instance being reconfirmed has to answer before it is
+/// forgotten, and when the second of the queries for it goes out (RFC 6762
+/// section 10.3)
+const RECONFIRM_WINDOW: Duration = Duration::from_secs(10);
+const RECONFIRM_REPEAT: Duration = Duration::from_secs(1);
+
+/// How far an instance has been looked up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resolution {
+    /// Only its PTR record is known, so it isn't reported yet
+    Pending,
+    /// Its SRV record is known, telling where to connect to
+    Resolved,
+}
+
+/// An instance, and how long it is good for
+#[derive(Clone, Debug)]
+pub struct Cached {
+    pub service: DiscoveredService,
+    resolution: Resolution,
+    /// When its PTR record was last received
+    received: Instant,
+    ttl: Duration,
+    /// How many of the `REFRESH_AT` points have passed since
+    refreshes: usize,
+    /// Set while the application doubts the instance is still there
+    reconfirm: Option<Reconfirm>,
+}
+
+/// An instance being reconfirmed, see `Browser::reconfirm`
+#[derive(Clone, Copy, Debug)]
+struct Reconfirm {
+    since: Instant,
+    /// Whether the second query went out
+    repeated: bool,
+}
+
+impl Cached {
+    pub fn is_resolved(&self) -> bool {
+        self.resolution == Resolution::Resolved
+    }
+
+    /// When it is forgotten, unless heard from again
+    fn expires(&self) -> Instant {
+        let expires = self.received + self.ttl;
+        match self.reconfirm {
+            Some(reconfirm) => expires.min(reconfirm.since + RECONFIRM_WINDOW),
+            None => expires,
+        }
+    }
+
+    /// When the next refresh or reconfirmation query is due, or it expires
+    fn deadline(&self) -> Instant {
+        let refresh = match REFRESH_AT.get(self.refreshes) {
+            Some(&percent) => self.received + self.ttl * percent / 100,
+            None => self.expires(),
+        };
+        match self.reconfirm {
+            Some(reconfirm) if !reconfirm.repeated => {
+                refresh.min(reconfirm.since + RECONFIRM_REPEAT)
+            }
+            _ => refresh.min(self.expires()),
+        }
+    }
+}
+
+/// Instances found by the query tasks, by name
+///
+/// Shared between the IPv4 and IPv6 tasks, so an instance only expires once
+/// neither of them has heard of it for its TTL.
+pub type Discovered = Arc<Mutex<HashMap<String, Cached>>>;
+
+/// Binds a socket for queries from the configured port
+pub fn bind<AF: AddressFamily>(config: &BrowserConfig) -> io::Result<std::net::UdpSocket> {
+    AF::bind_port(config.query_port.port(), &config.socket_config())
+}
+
+/// Where to send queries, see `BrowserConfig::multicast_group_v4`
+fn group<AF: AddressFamily>(config: &BrowserConfig) -> SocketAddr {
+    SocketAddr::new(AF::group(&config.socket_config()), MDNS_PORT)
+}
+
+/// Sends a query for one service type and collects the answers
+pub struct Query<AF: AddressFamily> {
+    socket: Box<dyn Socket>,
+    service_type: Name<'static>,
+    /// Where queries are sent, see `BrowserConfig::multicast_group_v4`
+    group: SocketAddr,
+    discovered: Discovered,
+    events: mpsc::Sender<BrowseEvent>,
+    /// Completes, with an error, once the `Browser` is dropped
+    stop: oneshot::Receiver<()>,
+    /// Names of instances to reconfirm, from `Browser::reconfirm`
+    reconfirms: mpsc::UnboundedReceiver<String>,
+    /// Instances to send a reconfirmation query for
+    unconfirmed: Vec<String>,
+    /// Whether the query has been sent since it was last due
+    sent: bool,
+    /// Whether records are missing for some instance, to be asked for
+    follow_up: bool,
+    clock: Arc<dyn Clock>,
+    /// Wakes the task when the next instance needs refreshing or expires
+    wakeup: Option<(Instant, Sleep)>,
+    _af: PhantomData<AF>,
+}
+
+impl<AF: AddressFamily> Query<AF> {
+    // Will panic if called from outside the context of a runtime
+    pub fn new(
+        service_type: Name<'static>,
+        config: &BrowserConfig,
+        discovered: &Discovered,
+        events: &mpsc::Sender<BrowseEvent>,
+        stop: oneshot::Receiver<()>,
+        reconfirms: mpsc::UnboundedReceiver<String>,
+    ) -> io::Result<Query<AF>> {
+        config.socket_config().check()?;
+        let socket = UdpSocket::from_std(bind::<AF>(config)?)?;
+        if config.query_port == QueryPort::Mdns {
+            AF::join_multicast(&socket, &config.socket_config())?;
+        }
+        Ok(Query {
+            group: group::<AF>(config),
+            ..Self::with_socket(
+                Box::new(socket),
+                service_type,
+                discovered,
+                events,
+                stop,
+                reconfirms,
+                Arc::new(SystemClock),
+            )
+        })
+    }
+
+    pub fn with_socket(
+        socket: Box<dyn Socket>,
+        service_type: Name<'static>,
+        discovered: &Discovered,
+        events: &mpsc::Sender<BrowseEvent>,
+        stop: oneshot::Receiver<()>,
+        reconfirms: mpsc::UnboundedReceiver<String>,
+        clock: Arc<dyn Clock>,
+    ) -> Query<AF> {
+        Query {
+            socket,
+            service_type,
+            // mDNS's own, `new` putting the configured one in its place
+            group: group::<AF>(&BrowserConfig::default()),
+            discovered: discovered.clone(),
+            events: events.clone(),
+            stop,
+            reconfirms,
+            unconfirmed: Vec::new(),
+            sent: false,
+            follow_up: false,
+            clock,
+            wakeup: None,
+            _af: PhantomData,
+        }
+    }
+
+    fn send_query(&mut self, cx: &mut Context) {
+        let packet = dns_parser::Builder::new_query(0, false)
+            .add_question(&self.service_type, QueryType::PTR, QueryClass::IN)
+            .build()
+            .unwrap_or_else(|x| x);
+        match self.socket.poll_send_to(cx, &packet, &self.group) {
+            Poll::Ready(Ok(_)) => self.sent = true,
+            Poll::Ready(Err(err)) => {
+                warn!("failed to send query: {}", err);
+                self.sent = true;
+            }
+            // Tried again once the socket is writable
+            Poll::Pending => (),
+        }
+    }
+
+    /// Asks for the records still missing for some instance: SRV and TXT of
+    /// pending ones, and the addresses of the targets of the others
+    fn send_follow_up(&mut self, cx: &mut Context) {
+        let mut questions = Vec::new();
+        {
+            let discovered = self.discovered.lock().unwrap();
+            for cached in discovered.values() {
+                let service = &cached.service;
+                if !cached.is_resolved() {
+                    questions.push((service.name.clone(), QueryType::SRV));
+                    questions.push((service.name.clone(), QueryType::TXT));
+                } else if let Some(ref target) = service.target {
+                    if service.addresses.is_empty() {
+                        let qtype = if AF::v6() {
+                            QueryType::AAAA
+                        } else {
+                            QueryType::A
+                        };
+                        let question = (target.clone(), qtype);
+                        if !questions.contains(&question) {
+                            questions.push(question);
+                        }
+                    }
+                }
+            }
+        }
+        let mut builder = dns_parser::Builder::new_query(0, false);
+        for (name, qtype) in questions {
+            match Name::from_str(name) {
+                Ok(name) => builder = builder.add_question(&name, qtype, QueryClass::IN),
+                Err(err) => warn!("can't ask about invalid name: {}", err),
+            }
+        }
+        let packet = builder.build().unwrap_or_else(|x| x);
+        match self.socket.poll_send_to(cx, &packet, &self.group) {
+            Poll::Ready(Ok(_)) => self.follow_up = false,
+            Poll::Ready(Err(err)) => {
+                warn!("failed to send follow-up query: {}", err);
+                self.follow_up = false;
+            }
+            // Tried again once the socket is writable
+            Poll::Pending => (),
+        }
+    }
+
+    /// Starts reconfirming the instances the application asked about
+    fn recv_reconfirms(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(name)) = self.reconfirms.poll_recv(cx) {
+            let mut discovered = self.discovered.lock().unwrap();
+            let cached = match discovered.get_mut(&name) {
+                Some(cached) => cached,
+                None => continue,
+            };
+            // Already underway, through the other address family maybe
+            if cached.reconfirm.is_none() {
+                cached.reconfirm = Some(Reconfirm {
+                    since: self.clock.now(),
+                    repeated: false,
+                });
+            }
+            if !self.unconfirmed.contains(&name) {
+                self.unconfirmed.push(name);
+            }
+        }
+    }
+
+    /// Asks the instances being reconfirmed for their SRV records
+    fn send_reconfirmation(&mut self, cx: &mut Context) {
+        let mut builder = dns_parser::Builder::new_query(0, false);
+        for name in &self.unconfirmed {
+            match Name::from_str(name.clone()) {
+                Ok(name) => builder = builder.add_question(&name, QueryType::SRV, QueryClass::IN),
+                Err(err) => warn!("can't ask about invalid name: {}", err),
+            }
+        }
+        let packet = builder.build().unwrap_or_else(|x| x);
+        match self.socket.poll_send_to(cx, &packet, &self.group) {
+            Poll::Ready(Ok(_)) => self.unconfirmed.clear(),
+            Poll::Ready(Err(err)) => {
+                warn!("failed to send reconfirmation query: {}", err);
+                self.unconfirmed.clear();
+            }
+            // Tried again once the socket is writable
+            Poll::Pending => (),
+        }
+    }
+
+    fn recv_packets(&mut self, cx: &mut Context) {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.socket.poll_recv_from(cx, &mut buf) {
+                Poll::Ready(Ok((bytes, addr))) => self.handle_packet(&buf[..bytes], addr),
+                Poll::Ready(Err(err)) => {
+                    warn!("failed to receive answers: {}", err);
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr) {
+        let packet = match dns_parser::Packet::parse(buffer) {
+            Ok(packet) => packet,
+            Err(error) => {
+                warn!("couldn't parse packet from {:?}: {}", addr, error);
+                return;
+            }
+        };
+        if packet.header.query {
+            return;
+        }
+        trace!("received answers from {:?}", addr);
+
+        let mut discovered = self.discovered.lock().unwrap();
+        let mut events = Vec::new();
+        // Instances first, so their SRV and TXT records find them whatever
+        // order they are in
+        let mut seen = Vec::new();
+        let mut found = false;
+        for rr in &packet.answers {
+            if let RRData::PTR(ref instance) = rr.data {
+                if rr.name != self.service_type {
+                    continue;
+                }
+                let name = instance.to_string();
+                if rr.ttl == 0 {
+                    match discovered.remove(&name) {
+                        Some(cached) if cached.is_resolved() => {
+                            events.push(BrowseEvent::Removed(cached.service))
+                        }
+                        _ => (),
+                    }
+                } else {
+                    let ttl = Duration::from_secs(rr.ttl.into());
+                    let now = self.clock.now();
+                    if let Some(cached) = discovered.get_mut(&name) {
+                        cached.received = now;
+                        cached.ttl = ttl;
+                        cached.refreshes = 0;
+                        cached.reconfirm = None;
+                        continue;
+                    }
+                    found = true;
+                    let service = DiscoveredService {
+                        name: name.clone(),
+                        ..DiscoveredService::default()
+                    };
+                    discovered.insert(
+                        name,
+                        Cached {
+                            service,
+                            resolution: Resolution::Pending,
+                            received: now,
+                            ttl,
+                            refreshes: 0,
+                            reconfirm: None,
+                        },
+                    );
+                }
+            }
+        }
+        // Responders may send SRV and TXT records as additional records
+        let records: Vec<_> = packet.answers.iter().chain(&packet.additional).collect();
+        for rr in &records {
+            let name = rr.name.to_string();
+            let cached = match discovered.get_mut(&name) {
+                Some(cached) => cached,
+                None => continue,
+            };
+            // What a reconfirmation waits for
+            if let RRData::SRV { .. } = rr.data {
+                cached.reconfirm = None;
+            }
+            let svc = &mut cached.service;
+            let before = svc.clone();
+            match rr.data {
+                RRData::SRV {
+                    port, ref target, ..
+                } => {
+                    if svc.target.as_deref() != Some(&target.to_string()[..]) {
+                        svc.addresses.clear();
+                    }
+                    svc.port = Some(port);
+                    svc.target = Some(target.to_string());
+                }
+                RRData::TXT(txt) => svc.txt = Some(txt.to_vec()),
+                _ => (),
+            }
+            if *svc != before && !seen.contains(&name) {
+                seen.push(name);
+            }
+        }
+        // Then the addresses of the hosts they are on
+        for rr in &records {
+            let ip = match rr.data {
+                RRData::A(ip) => IpAddr::V4(ip),
+                RRData::AAAA(ip) => IpAddr::V6(ip),
+                _ => continue,
+            };
+            let host = rr.name.to_string();
+            for (name, cached) in discovered.iter_mut() {
+                let svc = &mut cached.service;
+                if svc.target.as_ref() == Some(&host) && !svc.addresses.contains(&ip) {
+                    svc.addresses.push(ip);
+                    if !seen.contains(name) {
+                        seen.push(name.clone());
+                    }
+                }
+            }
+        }
+
+        // Asking again only after learning something, as they may never come
+        let learned = found || !seen.is_empty();
+        for name in seen {
+            let cached = discovered.get_mut(&name).expect("instance just seen");
+            if cached.is_resolved() {
+                events.push(BrowseEvent::Updated(cached.service.clone()));
+            } else if cached.service.target.is_some() && cached.service.port.is_some() {
+                cached.resolution = Resolution::Resolved;
+                events.push(BrowseEvent::Added(cached.service.clone()));
+            }
+        }
+        if learned {
+            self.follow_up = discovered
+                .values()
+                .any(|cached| !cached.is_resolved() || cached.service.addresses.is_empty());
+        }
+        drop(discovered);
+        self.emit(events);
+    }
+
+    fn emit(&mut self, events: Vec<BrowseEvent>) {
+        for event in events {
+            // Nobody listening only means nobody cares
+            if let Err(TrySendError::Full(event)) = self.events.try_send(event) {
+                debug!("browse events not read, dropping {:?}", event);
+            }
+        }
+    }
+
+    /// Forgets the instances whose TTL has passed, and asks again for those
+    /// getting close to it
+    fn expire(&mut self) {
+        let now = self.clock.now();
+        let mut removed = Vec::new();
+        let mut refresh = false;
+        {
+            let mut discovered = self.discovered.lock().unwrap();
+            let unconfirmed = &mut self.unconfirmed;
+            discovered.retain(|name, cached| {
+                if now >= cached.expires() {
+                    if cached.is_resolved() {
+                        removed.push(BrowseEvent::Removed(cached.service.clone()));
+                    }
+                    return false;
+                }
+                if let Some(ref mut reconfirm) = cached.reconfirm {
+                    if !reconfirm.repeated && now >= reconfirm.since + RECONFIRM_REPEAT {
+                        reconfirm.repeated = true;
+                        if !unconfirmed.contains(name) {
+                            unconfirmed.push(name.clone());
+                        }
+                    }
+                }
+                while cached.refreshes < REFRESH_AT.len() && now >= cached.deadline() {
+                    cached.refreshes += 1;
+                    refresh = true;
+                }
+                true
+            });
+        }
+        if refresh {
+            trace!("refreshing instances of {}", self.service_type);
+            self.sent = false;
+        }
+        self.emit(removed);
+    }
+
+    /// Arranges for the task to be woken when the next instance is due
+    fn poll_wakeup(&mut self, cx: &mut Context) {
+        let next = {
+            let discovered = self.discovered.lock().unwrap();
+            discovered.values().map(Cached::deadline).min()
+        };
+        let next = match next {
+            Some(at) => at,
+            None => {
+                self.wakeup = None;
+                return;
+            }
+        };
+
+        if self.wakeup.as_ref().map(|&(at, _)| at) != Some(next) {
+            self.wakeup = Some((next, self.clock.sleep_until(next)));
+        }
+
+        if let Some((_, ref mut sleep)) = self.wakeup {
+            if sleep.as_mut().poll(cx).is_ready() {
+                self.wakeup = None;
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+}
+
+impl<AF: Unpin + AddressFamily> Future for Query<AF> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let pinned = Pin::get_mut(self);
+        if Pin::new(&mut pinned.stop).poll(cx).is_ready() {
+            return Poll::Ready(());
+        }
+        pinned.recv_reconfirms(cx);
+        pinned.recv_packets(cx);
+        pinned.expire();
+        if !pinned.sent {
+            pinned.send_query(cx);
+        }
+        if pinned.follow_up {
+            pinned.send_follow_up(cx);
+        }
+        if !pinned.unconfirmed.is_empty() {
+            pinned.send_reconfirmation(cx);
+        }
+        pinned.poll_wakeup(cx);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bind, group, BrowseEvent, BrowserConfig, DiscoveredService, Query, QueryPort};
+    use crate::address_family::Inet;
+    use crate::clock::MockClock;
+    use crate::dns_parser::{self, Name, Packet, QueryClass, QueryType, RRData};
+    use crate::socket::MockSocket;
+    use futures_util::task::noop_waker_ref;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::Context;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+
+    #[test]
+    fn queries_from_mdns_port() {
+        assert_eq!(QueryPort::Mdns.port(), 5353);
+    }
+
+    #[test]
+    fn queries_from_ephemeral_port() {
+        assert_eq!(QueryPort::Ephemeral.port(), 0);
+        let config = BrowserConfig {
+            query_port: QueryPort::Ephemeral,
+            ..BrowserConfig::default()
+        };
+        let port = bind::<Inet>(&config).unwrap().local_addr().unwrap().port();
+        assert_ne!(port, 5353);
+    }
+
+    #[test]
+    fn queries_on_configured_group() {
+        let socket = MockSocket::new();
+        let config = BrowserConfig {
+            multicast_group_v4: Some(Ipv4Addr::new(239, 255, 0, 251)),
+            ..BrowserConfig::default()
+        };
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, _) = mpsc::channel(16);
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let mut query = Query {
+            group: group::<Inet>(&config),
+            ..Query::<Inet>::with_socket(
+                Box::new(socket.clone()),
+                Name::from_str("_http._tcp.local").unwrap(),
+                &discovered,
+                &events,
+                stopped,
+                reconfirms,
+                Arc::new(MockClock::new()),
+            )
+        };
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        let sent = socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].1,
+            "239.255.0.251:5353".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn refuses_groups_that_arent_multicast() {
+        let config = BrowserConfig {
+            query_port: QueryPort::Ephemeral,
+            multicast_group_v4: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            ..BrowserConfig::default()
+        };
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let query = Query::<Inet>::new(
+            Name::from_str("_http._tcp.local").unwrap(),
+            &config,
+            &Arc::new(Mutex::new(HashMap::new())),
+            &mpsc::channel(16).0,
+            stopped,
+            reconfirms,
+        );
+        let err = query.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn collects_answers_to_query() {
+        let socket = MockSocket::new();
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, _) = mpsc::channel(16);
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let service_type = Name::from_str("_http._tcp.local").unwrap();
+        let mut query = Query::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            service_type.clone(),
+            &discovered,
+            &events,
+            stopped,
+            reconfirms,
+            Arc::new(MockClock::new()),
+        );
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        let sent = socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, "224.0.0.251:5353".parse::<SocketAddr>().unwrap());
+        let packet = Packet::parse(&sent[0].0).unwrap();
+        assert_eq!(packet.questions[0].qname, service_type);
+
+        let instance = Name::from_str("Test._http._tcp.local").unwrap();
+        let host = Name::from_str("host.local").unwrap();
+        let srv = RRData::SRV {
+            priority: 0,
+            weight: 0,
+            port: 80,
+            target: host,
+        };
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder = builder.add_answer(&instance, QueryClass::IN, 60, &srv);
+        builder = builder.add_answer(&service_type, QueryClass::IN, 60, &RRData::PTR(instance));
+        let response = builder.build().unwrap();
+        let from = "10.0.0.2:5353".parse().unwrap();
+        socket.state().incoming.push_back(Ok((response, from)));
+
+        assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        let discovered = discovered.lock().unwrap();
+        assert_eq!(
+            discovered
+                .values()
+                .map(|cached| cached.service.clone())
+                .collect::<Vec<_>>(),
+            vec![DiscoveredService {
+                name: "Test._http._tcp.local".to_owned(),
+                target: Some("host.local".to_owned()),
+                port: Some(80),
+                txt: None,
+                addresses: Vec::new(),
+            }]
+        );
+    }
+
+    /// An announcement of Test._http._tcp.local on host.local, with a PTR
+    /// record of `ptr_ttl`
+    fn announcement(ptr_ttl: u32, txt: &[u8]) -> Vec<u8> {
+        let service_type = Name::from_str("_http._tcp.local").unwrap();
+        let instance = Name::from_str("Test._http._tcp.local").unwrap();
+        let host = Name::from_str("host.local").unwrap();
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder = builder.add_answer(
+            &service_type,
+            QueryClass::IN,
+            ptr_ttl,
+            &RRData::PTR(instance.clone()),
+        );
+        builder = builder.add_answer(&instance, QueryClass::IN, ptr_ttl, &srv(host.clone()));
+        builder = builder.add_answer(&instance, QueryClass::IN, ptr_ttl, &RRData::TXT(txt));
+        builder = builder.add_answer(&host, QueryClass::IN, 120, &RRData::A(host_ip()));
+        builder.build().unwrap()
+    }
+
+    fn srv(target: Name) -> RRData {
+        RRData::SRV {
+            priority: 0,
+            weight: 0,
+            port: 80,
+            target,
+        }
+    }
+
+    fn host_ip() -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, 2)
+    }
+
+    #[test]
+    fn reports_changes_as_events() {
+        let socket = MockSocket::new();
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, mut changes) = mpsc::channel(16);
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let mut query = Query::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            Name::from_str("_http._tcp.local").unwrap(),
+            &discovered,
+            &events,
+            stopped,
+            reconfirms,
+            Arc::new(MockClock::new()),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let from: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        let mut receive = |packet| {
+            socket.state().incoming.push_back(Ok((packet, from)));
+            assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        };
+        let service = |txt: &[u8]| DiscoveredService {
+            name: "Test._http._tcp.local".to_owned(),
+            target: Some("host.local".to_owned()),
+            port: Some(80),
+            txt: Some(txt.to_vec()),
+            addresses: vec![host_ip().into()],
+        };
+
+        receive(announcement(60, b"\x03a=1"));
+        // Repeated announcements change nothing
+        receive(announcement(60, b"\x03a=1"));
+        receive(announcement(60, b"\x03a=2"));
+        receive(announcement(0, b"\x03a=2"));
+
+        assert_eq!(
+            changes.try_recv(),
+            Ok(BrowseEvent::Added(service(b"\x03a=1")))
+        );
+        assert_eq!(
+            changes.try_recv(),
+            Ok(BrowseEvent::Updated(service(b"\x03a=2")))
+        );
+        assert_eq!(
+            changes.try_recv(),
+            Ok(BrowseEvent::Removed(service(b"\x03a=2")))
+        );
+        assert!(changes.try_recv().is_err());
+        assert!(discovered.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_events_while_they_arent_read() {
+        let socket = MockSocket::new();
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, mut changes) = mpsc::channel(1);
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let mut query = Query::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            Name::from_str("_http._tcp.local").unwrap(),
+            &discovered,
+            &events,
+            stopped,
+            reconfirms,
+            Arc::new(MockClock::new()),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let from: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        let mut receive = |packet| {
+            socket.state().incoming.push_back(Ok((packet, from)));
+            assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        };
+
+        receive(announcement(60, b"\x03a=1"));
+        receive(announcement(60, b"\x03a=2"));
+        assert!(matches!(changes.try_recv(), Ok(BrowseEvent::Added(_))));
+        assert!(changes.try_recv().is_err());
+        // What is listed is still current
+        let discovered = discovered.lock().unwrap();
+        let cached = discovered.get("Test._http._tcp.local").unwrap();
+        assert_eq!(cached.service.txt, Some(b"\x03a=2".to_vec()));
+    }
+
+    #[test]
+    fn refreshes_then_expires_instances() {
+        let socket = MockSocket::new();
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, mut changes) = mpsc::channel(16);
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let clock = Arc::new(MockClock::new());
+        let mut query = Query::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            Name::from_str("_http._tcp.local").unwrap(),
+            &discovered,
+            &events,
+            stopped,
+            reconfirms,
+            clock.clone(),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let from: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        socket
+            .state()
+            .incoming
+            .push_back(Ok((announcement(10, b"\x03a=1"), from)));
+        assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        assert!(matches!(changes.try_recv(), Ok(BrowseEvent::Added(_))));
+        assert_eq!(socket.state().sent.len(), 1);
+
+        let mut poll_at = |secs: f32| {
+            clock.advance(Duration::from_secs_f32(secs));
+            assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+            socket.state().sent.len()
+        };
+        assert_eq!(poll_at(7.9), 1);
+        // At 80% and 85% of the TTL
+        assert_eq!(poll_at(0.1), 2);
+        assert_eq!(poll_at(0.5), 3);
+        assert!(changes.try_recv().is_err());
+
+        // Gone once nobody answered for all of it
+        assert_eq!(poll_at(1.5), 3);
+        assert!(matches!(changes.try_recv(), Ok(BrowseEvent::Removed(_))));
+        assert!(discovered.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_instances_once_resolved() {
+        let socket = MockSocket::new();
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, mut changes) = mpsc::channel(16);
+        let (_stop, stopped) = oneshot::channel();
+        let (_reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let service_type = Name::from_str("_http._tcp.local").unwrap();
+        let mut query = Query::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            service_type.clone(),
+            &discovered,
+            &events,
+            stopped,
+            reconfirms,
+            Arc::new(MockClock::new()),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let from: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        let mut receive = |rrs: Vec<(Name<'static>, RRData<'static>)>| {
+            let mut builder =
+                dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+            for (name, data) in &rrs {
+                builder = builder.add_answer(name, QueryClass::IN, 120, data);
+            }
+            let packet = builder.build().unwrap();
+            socket.state().incoming.push_back(Ok((packet, from)));
+            assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+            // What it asked for next
+            let sent = socket.state().sent.pop().unwrap();
+            let packet = Packet::parse(&sent.0).unwrap();
+            packet
+                .questions
+                .iter()
+                .map(|q| (q.qname.to_string(), q.qtype))
+                .collect::<Vec<_>>()
+        };
+        let instance = Name::from_str("Test._http._tcp.local").unwrap();
+        let host = Name::from_str("host.local").unwrap();
+
+        let asked = receive(vec![(service_type, RRData::PTR(instance.clone()))]);
+        assert_eq!(
+            asked,
+            vec![
+                ("Test._http._tcp.local".to_owned(), QueryType::SRV),
+                ("Test._http._tcp.local".to_owned(), QueryType::TXT),
+            ]
+        );
+        assert!(changes.try_recv().is_err());
+        assert!(!discovered.lock().unwrap()["Test._http._tcp.local"].is_resolved());
+
+        let asked = receive(vec![(instance, srv(host.clone()))]);
+        assert_eq!(asked, vec![("host.local".to_owned(), QueryType::A)]);
+        let mut service = DiscoveredService {
+            name: "Test._http._tcp.local".to_owned(),
+            target: Some("host.local".to_owned()),
+            port: Some(80),
+            txt: None,
+            addresses: Vec::new(),
+        };
+        assert_eq!(changes.try_recv(), Ok(BrowseEvent::Added(service.clone())));
+
+        socket.state().incoming.push_back(Ok((
+            {
+                let builder = dns_parser::Builder::new_response(0, false, true)
+                    .move_to::<dns_parser::Answers>();
+                builder
+                    .add_answer(&host, QueryClass::IN, 120, &RRData::A(host_ip()))
+                    .build()
+                    .unwrap()
+            },
+            from,
+        )));
+        assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        service.addresses = vec![host_ip().into()];
+        assert_eq!(changes.try_recv(), Ok(BrowseEvent::Updated(service)));
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn forgets_instances_failing_reconfirmation() {
+        let socket = MockSocket::new();
+        let discovered = Arc::new(Mutex::new(HashMap::new()));
+        let (events, mut changes) = mpsc::channel(16);
+        let (_stop, stopped) = oneshot::channel();
+        let (reconfirm, reconfirms) = mpsc::unbounded_channel();
+        let clock = Arc::new(MockClock::new());
+        let mut query = Query::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            Name::from_str("_http._tcp.local").unwrap(),
+            &discovered,
+            &events,
+            stopped,
+            reconfirms,
+            clock.clone(),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let from: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        socket
+            .state()
+            .incoming
+            .push_back(Ok((announcement(4500, b"\x03a=1"), from)));
+        assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+        assert!(matches!(changes.try_recv(), Ok(BrowseEvent::Added(_))));
+        socket.state().sent.clear();
+
+        reconfirm.send("Test._http._tcp.local".to_owned()).unwrap();
+        let mut poll_after = |secs: u64| {
+            clock.advance(Duration::from_secs(secs));
+            assert!(Pin::new(&mut query).poll(&mut cx).is_pending());
+            let sent = std::mem::take(&mut socket.state().sent);
+            sent.iter()
+                .map(|(packet, _)| {
+                    let packet = Packet::parse(packet).unwrap();
+                    let question = &packet.questions[0];
+                    (question.qname.to_string(), question.qtype)
+                })
+                .collect::<Vec<_>>()
+        };
+        let asked = vec![("Test._http._tcp.local".to_owned(), QueryType::SRV)];
+        assert_eq!(poll_after(0), asked);
+        assert_eq!(poll_after(1), asked);
+        assert!(poll_after(8).is_empty());
+        assert!(changes.try_recv().is_err());
+
+        // Nobody answered within the window, despite the TTL left
+        poll_after(1);
+        assert!(matches!(changes.try_recv(), Ok(BrowseEvent::Removed(_))));
+        assert!(discovered.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Instant, SystemTime};
+
+/// A future that completes once a deadline has passed
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time for timers in `fsm`
+///
+/// The responder uses the system clock, tests substitute a clock that
+/// only moves when told to.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    /// The time of day, for schedules
+    fn wall(&self) -> SystemTime;
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::delay_until(deadline.into()))
+    }
+}
+
+#[cfg(test)]
+pub use self::mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use super::{Clock, Sleep};
+    use futures_util::future;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// A clock which only advances when `advance` is called
+    ///
+    /// Its sleeps never complete on their own, so whoever advances it is
+    /// expected to poll the timer owner afterwards. Its time of day starts
+    /// at midnight UTC.
+    pub struct MockClock {
+        now: Mutex<(Instant, SystemTime)>,
+    }
+
+    impl MockClock {
+        pub fn new() -> Self {
+            MockClock {
+                now: Mutex::new((Instant::now(), UNIX_EPOCH)),
+            }
+        }
+
+        pub fn advance(&self, by: Duration) {
+            let mut now = self.now.lock().unwrap();
+            now.0 += by;
+            now.1 += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.now.lock().unwrap().0
+        }
+
+        fn wall(&self) -> SystemTime {
+            self.now.lock().unwrap().1
+        }
+
+        fn sleep_until(&self, _deadline: Instant) -> Sleep {
+            Box::pin(future::pending())
+        }
+    }
+}
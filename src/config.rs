@@ -0,0 +1,832 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tunables for a `Responder`
+///
+/// Start from `Config::default()` and override the fields you care about.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Names of the interfaces to join the multicast group on and advertise
+    /// addresses of. `None` uses every non-loopback interface.
+    pub interfaces: Option<Vec<String>>,
+    /// Names of interfaces to leave out, using every other non-loopback
+    /// one, e.g. a VPN's. Can't be combined with `interfaces`.
+    pub excluded_interfaces: Option<Vec<String>>,
+    /// Use macOS's special interfaces too, which are otherwise left out
+    /// unless named in `interfaces`: `awdl0` for AirDrop and `llw0` for its
+    /// low latency WLAN. Clients given their link-local addresses fail to
+    /// connect. Has no effect on other platforms.
+    pub include_special_interfaces: bool,
+    /// Weights of interfaces whose addresses are listed first, highest
+    /// first, e.g. to have clients pick Ethernet over Wi-Fi. The rest count
+    /// as 0, and ties keep the order the OS lists interfaces in.
+    pub interface_preference: HashMap<String, u32>,
+    /// Should listing the interfaces fail, e.g. in the middle of a change,
+    /// answer with the addresses listed last if that was at most this long
+    /// ago, rather than with none. Their TTL drops to 10 seconds, so peers
+    /// check back soon. Off by default.
+    pub stale_address_window: Option<Duration>,
+    /// How often to look for interfaces that appeared since the last check,
+    /// so the multicast group can be joined on them too.
+    pub interface_refresh_interval: Duration,
+    /// Sets `IPV6_V6ONLY` on the IPv6 socket, so it never sees IPv4-mapped
+    /// traffic whatever the platform default is. IPv4 is served by its own
+    /// socket alongside; turning this off is only useful for unusual setups.
+    pub v6_only: bool,
+    /// Sets `SO_RCVBUF` on the sockets, for bursts of queries that would
+    /// overflow the platform default. The OS may clamp it: Linux doubles it
+    /// for bookkeeping and caps it at `net.core.rmem_max`.
+    pub recv_buffer_size: Option<usize>,
+    /// Sets `SO_SNDBUF` on the sockets, clamped like `recv_buffer_size`
+    /// (at `net.core.wmem_max` on Linux)
+    pub send_buffer_size: Option<usize>,
+    /// Advertise IPv4 and IPv6 addresses in every response, rather than only
+    /// those of the family the query arrived over. Clients often connect over
+    /// whichever family works, so this helps on dual-stack networks.
+    pub include_both_families: bool,
+    /// Send unicast responses from our address on the querier's subnet,
+    /// the one it knows us by, rather than whichever the routing table
+    /// picks. Only supported on Linux, elsewhere the OS keeps picking.
+    pub unicast_source_from_subnet: bool,
+    /// Send unicast responses out of the interface their query arrived on,
+    /// as told by `IP_PKTINFO`, rather than whichever the routing table
+    /// picks. Only supported on Linux, elsewhere the OS keeps picking.
+    pub unicast_via_arrival_interface: bool,
+    /// Give every interface a socket of its own, bound to it, so that what
+    /// arrives on an interface is answered out of that same one. The shared
+    /// socket then only receives multicast through the groups it joined
+    /// itself, should binding to an interface fail. Only supported on Linux,
+    /// elsewhere the one socket serves them all.
+    pub socket_per_interface: bool,
+    /// Multicast groups to join and announce to instead of mDNS's own,
+    /// e.g. to keep interoperability tests off the real network. Must be
+    /// multicast addresses.
+    pub multicast_group_v4: Option<Ipv4Addr>,
+    pub multicast_group_v6: Option<Ipv6Addr>,
+    /// Re-announce every service after this fraction of its TTL has passed,
+    /// keeping it fresh in peer caches on lossy networks. Must be between 0
+    /// and 1, e.g. `Some(0.8)`. Off by default.
+    pub refresh_fraction: Option<f32>,
+    /// Limits how often any single querier is answered. Off by default.
+    pub rate_limit: Option<RateLimit>,
+    /// Most multicast datagrams sent per second, across all services, to
+    /// spare shared networks. Bursts of up to as many go out at once, the
+    /// rest wait in the send queue while unicast responses go ahead. Off by
+    /// default.
+    pub max_multicast_rate: Option<u32>,
+    /// Skip queueing a response identical to one still waiting to be sent
+    /// to the same address, e.g. the responses to several hosts asking the
+    /// same at once. Announcements and goodbyes are always queued, so each
+    /// one counts in the stats. On by default.
+    pub dedup_outgoing: bool,
+    /// Called with every question received, whether or not it is answered
+    pub query_observer: Option<QueryObserver>,
+    /// Inspects, rewrites or vetoes every datagram about to be sent
+    pub outgoing_filter: Option<OutgoingFilter>,
+    /// Gives some queriers another hostname for the SRV records and
+    /// addresses of their responses, e.g. in split-horizon setups. Those
+    /// queriers are always answered by unicast, so nobody else learns the
+    /// name.
+    pub hostname_rewrite: Option<HostnameRewrite>,
+    /// If the socket can't be bound when the responder starts, e.g. at boot
+    /// before any network is up, keep trying at this interval rather than
+    /// failing. Services registered meanwhile are announced once it binds.
+    pub bind_retry_interval: Option<Duration>,
+    /// If the multicast group can't be joined at all once bound, e.g. on
+    /// networks gaining multicast routing late, keep trying at this interval
+    /// and announce every service once it is. Meanwhile only unicast
+    /// queries are answered.
+    pub join_retry_interval: Option<Duration>,
+    /// Leave the multicast group and join it again at this interval, so
+    /// that switches which forget memberships they haven't seen reported
+    /// lately keep forwarding queries to an otherwise quiet host. Off by
+    /// default, as the OS answers the router's membership queries anyway.
+    pub rejoin_interval: Option<Duration>,
+    /// Upper bound for the TTLs in responses to legacy unicast queriers,
+    /// i.e. those not sending from port 5353. RFC 6762 asks for at most 10
+    /// seconds, as such resolvers don't see goodbye announcements.
+    pub legacy_unicast_ttl: u32,
+    /// TTLs of our records by type, in seconds
+    pub ttls: Ttls,
+    /// How long a querier stays in `Responder::recent_queriers` after its
+    /// last query
+    pub querier_window: Duration,
+    /// Multicast the full record set of every service at this interval,
+    /// whether or not anyone asked. This speeds up discovery by peers that
+    /// only listen, at the cost of considerably more traffic on the network,
+    /// so it's off by default.
+    pub advertise_interval: Option<Duration>,
+    /// Called when another host answers with records that clash with ours,
+    /// so the application can rename the affected service or shut down
+    pub conflict_observer: Option<ConflictObserver>,
+    /// How many instances to answer a PTR query with. Capped at 100 by
+    /// default, keeping responses from being used for amplification.
+    pub ptr_instances: PtrInstances,
+    /// Check at this interval that the sockets still receive, by sending a
+    /// query to the group and expecting it back; see
+    /// `Responder::is_healthy`. Off by default.
+    pub health_check_interval: Option<Duration>,
+    /// Say goodbye for every service before stopping when the `Responder`
+    /// goes away without shutting down, as `Responder::shutdown` does,
+    /// rather than stopping silently. On by default.
+    pub goodbye_on_disconnect: bool,
+    /// Adds an OPT record (RFC 6891) to responses, advertising this as the
+    /// largest UDP payload we receive so queriers can size what they send
+    /// next. From 512 up to 4095 bytes; off by default.
+    pub advertised_payload_size: Option<u16>,
+    /// Gets a copy of every datagram received, before it is parsed, e.g.
+    /// for capturing traffic while debugging
+    pub packet_tap: Option<PacketTap>,
+    /// What to do about queries for a service while no interface has an
+    /// address to point its SRV record at, e.g. while the network is down
+    pub when_no_addresses: NoAddresses,
+    /// Answer questions asking for a unicast response by multicast instead
+    /// if the records asked for haven't been multicast within a quarter of
+    /// their TTL, so other listeners get them too (RFC 6762 section 5.4).
+    /// Off by default, honouring what the querier asked for.
+    pub upgrade_stale_qu: bool,
+    /// Most questions to answer from a single packet. Any past it are
+    /// ignored, bounding the work a crafted packet can cause.
+    pub max_questions: usize,
+    /// Drop queries with header flags mDNS has no use for (RFC 6762 section
+    /// 18): AA, RD or RA set, an opcode other than a standard query, or a
+    /// non-zero RCODE. Off by default, as some clients set RD and their
+    /// questions are worth answering anyway.
+    pub strict_query_flags: bool,
+    /// Delay the first announcement of a newly registered service by a
+    /// random time up to this long, so that many devices powering up at
+    /// once don't flood the network together
+    pub announce_jitter: Duration,
+    /// Announce services as `Responder` registers them. With this off they
+    /// are only answered for until announced by `Service::announce` or
+    /// `ServiceGroup::announce`, e.g. to stage services ahead of time.
+    pub announce_on_register: bool,
+    /// Probe for the names of services before announcing them, asking
+    /// three times 250 ms apart whether another host has records of them
+    /// (RFC 6762 section 8.1). Meanwhile queries for them go unanswered,
+    /// and a host answering with different records makes us pick another
+    /// name. Off by default, announcing straight away.
+    pub probe_before_announcing: bool,
+    /// Only ever answer queries, never multicasting anything unasked for:
+    /// no announcements, on registration or otherwise, and no goodbyes.
+    /// For networks where unsolicited multicast is against policy.
+    pub passive_only: bool,
+    /// Times to send each goodbye, spaced `goodbye_interval` apart, so one
+    /// lost packet doesn't leave peers with stale records (RFC 6762 section
+    /// 10.1). Once by default, and at least once: `Responder` rejects 0.
+    pub goodbye_count: u8,
+    /// Time between the copies of a goodbye, when `goodbye_count` sends
+    /// more than one. A quarter of a second by default.
+    pub goodbye_interval: Duration,
+    /// Keep the responses to queries, and answer the same queries again
+    /// with copies of them rather than building them anew, for static
+    /// services under heavy query load. Only queries without known answers
+    /// are answered this way, and only while no service is restricted to
+    /// some queriers, generates its TXT record or shares its name with
+    /// others. Any change to the services discards the copies, as does a
+    /// change to our addresses once `interface_refresh_interval` finds it.
+    /// Off by default.
+    pub prebuilt_responses: bool,
+    /// What to send along with the PTR records answering a browse
+    pub ptr_response: PtrResponse,
+    /// Which section the records sent along with an answer go in
+    pub placement: Placement,
+}
+
+/// Per-querier thresholds, counted in query packets per `window`
+///
+/// Past `soft_limit` a querier only gets the records it asked for (e.g. the
+/// PTR records for a PTR query, without the SRV, TXT and addresses). Past
+/// `hard_limit` it isn't answered at all until the window is over.
+/// `soft_limit` can't be above `hard_limit`. Queriers are told apart by
+/// address, and once there are too many to track in a window the rest are
+/// counted as one.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    pub window: Duration,
+    pub soft_limit: u32,
+    pub hard_limit: u32,
+}
+
+/// How to answer for a service without any addresses, see
+/// `Config::when_no_addresses`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NoAddresses {
+    /// Answer with the SRV and TXT records anyway, although clients can't
+    /// resolve the host they point at
+    Answer,
+    /// Don't answer, so clients retry later
+    Suppress,
+    /// Advertise these addresses instead
+    Static(Vec<IpAddr>),
+}
+
+/// How to answer PTR queries, see `Config::ptr_response`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PtrResponse {
+    /// With the SRV, TXT and address records of each instance, sparing
+    /// clients a round-trip to resolve it (RFC 6763 section 12.1)
+    Bundled,
+    /// With the PTR records alone, keeping responses small. Clients query
+    /// for the rest of the instances they pick.
+    Minimal,
+}
+
+/// How many instances of a type answer a PTR query, see
+/// `Config::ptr_instances`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PtrInstances {
+    /// Every instance registered
+    All,
+    /// At most this many. With more instances registered, each response
+    /// carries the next slice of them.
+    Capped(usize),
+    /// One instance per response, taking turns, for privacy or to keep
+    /// responses small
+    Single,
+}
+
+/// Sections of a response for each type of record sent along with those
+/// answering a question, e.g. the SRV records of the instances in a PTR
+/// answer
+///
+/// Records asked for always go in the answer section. By default everything
+/// else goes in the additional section (RFC 6763 section 12), but some
+/// clients only ever look at the answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub srv: Section,
+    pub txt: Section,
+    /// A and AAAA records
+    pub addresses: Section,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Answer,
+    Additional,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement {
+            srv: Section::Additional,
+            txt: Section::Additional,
+            addresses: Section::Additional,
+        }
+    }
+}
+
+/// TTLs of each type of record we send, in seconds
+///
+/// They only get lowered, e.g. to 0 for goodbyes or for legacy queriers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ttls {
+    pub ptr: u32,
+    pub srv: u32,
+    pub txt: u32,
+    pub a: u32,
+    pub aaaa: u32,
+}
+
+impl Default for Ttls {
+    /// As recommended by RFC 6762 section 10: 120 seconds for records
+    /// naming a host or tied to one, 75 minutes for the others. TXT records
+    /// get the shorter one too, so that changed entries reach caches sooner.
+    fn default() -> Self {
+        Ttls {
+            ptr: 4500,
+            srv: 120,
+            txt: 120,
+            a: 120,
+            aaaa: 120,
+        }
+    }
+}
+
+/// Hours of each day a service is advertised in, see
+/// `Service::set_schedule`
+///
+/// Both are times of day since midnight UTC, to the second. An `end` before
+/// `start` makes the hours span midnight, and one equal to it the whole day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+impl Schedule {
+    fn time_of_day(at: SystemTime) -> u64 {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.as_secs() % DAY_SECS
+    }
+
+    fn bounds(&self) -> (u64, u64) {
+        (
+            self.start.as_secs() % DAY_SECS,
+            self.end.as_secs() % DAY_SECS,
+        )
+    }
+
+    pub fn contains(&self, at: SystemTime) -> bool {
+        let now = Self::time_of_day(at);
+        let (start, end) = self.bounds();
+        if start == end {
+            true
+        } else if start < end {
+            start <= now && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+
+    /// How long until the hours next start or end after `at`
+    pub fn until_change(&self, at: SystemTime) -> Duration {
+        let now = Self::time_of_day(at);
+        let (start, end) = self.bounds();
+        let until = |bound: u64| match (bound + DAY_SECS - now) % DAY_SECS {
+            0 => DAY_SECS,
+            secs => secs,
+        };
+        Duration::from_secs(until(start).min(until(end)))
+    }
+}
+
+/// A block of addresses in CIDR notation, e.g. "10.0.0.0/24"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// Why a string couldn't be parsed as a `Subnet`
+#[derive(Debug)]
+pub enum SubnetError {
+    /// The string given, which isn't an address and prefix length
+    Invalid(String),
+}
+
+impl fmt::Display for SubnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SubnetError::Invalid(ref subnet) => write!(f, "Invalid subnet {:?}", subnet),
+        }
+    }
+}
+
+impl error::Error for SubnetError {}
+
+impl Subnet {
+    /// `None` if `prefix_len` is longer than the address
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Subnet> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return None;
+        }
+        Some(Subnet { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = SubnetError;
+
+    fn from_str(s: &str) -> Result<Subnet, SubnetError> {
+        let invalid = || SubnetError::Invalid(s.to_owned());
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = prefix_len.parse().map_err(|_| invalid())?;
+        Subnet::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+/// A question as seen by a `QueryObserver`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObservedQuestion {
+    pub name: String,
+    /// Numeric QTYPE, e.g. 12 for PTR
+    pub qtype: u16,
+    /// Whether the querier asked for a unicast response (the QU bit)
+    pub unicast_response: bool,
+}
+
+/// A callback for monitoring the questions a responder receives
+///
+/// It only ever sees a copy of each question, so it can't influence how the
+/// responder answers. It runs on the responder's task and should be quick.
+#[derive(Clone)]
+pub struct QueryObserver(Arc<ObserverFn>);
+
+type ObserverFn = dyn Fn(SocketAddr, &ObservedQuestion) + Send + Sync;
+
+impl QueryObserver {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(SocketAddr, &ObservedQuestion) + Send + Sync + 'static,
+    {
+        QueryObserver(Arc::new(f))
+    }
+
+    pub(crate) fn observe(&self, from: SocketAddr, question: &ObservedQuestion) {
+        (self.0)(from, question)
+    }
+}
+
+impl fmt::Debug for QueryObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("QueryObserver")
+    }
+}
+
+/// A callback seeing every datagram before it is queued for sending, along
+/// with where to, see `Config::outgoing_filter`
+///
+/// It returns the datagram to send, changed or not, or `None` to drop it.
+/// It runs on the responder's task and should be quick.
+#[derive(Clone)]
+pub struct OutgoingFilter(Arc<FilterFn>);
+
+type FilterFn = dyn Fn(Vec<u8>, SocketAddr) -> Option<Vec<u8>> + Send + Sync;
+
+impl OutgoingFilter {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(Vec<u8>, SocketAddr) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        OutgoingFilter(Arc::new(f))
+    }
+
+    pub(crate) fn filter(&self, packet: Vec<u8>, to: SocketAddr) -> Option<Vec<u8>> {
+        (self.0)(packet, to)
+    }
+}
+
+impl fmt::Debug for OutgoingFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OutgoingFilter")
+    }
+}
+
+/// Picks the hostname to answer a querier with, see
+/// `Config::hostname_rewrite`
+///
+/// It gets the querier's address and returns the full hostname to give it,
+/// e.g. `"nas-internal.local"`, or `None` for ours, which is also used in
+/// place of names that can't be advertised, such as ones with a label
+/// longer than 62 bytes. It is called for every question answered and
+/// should be quick. Address queries are answered under either name, so
+/// queriers can resolve whichever they were given.
+///
+/// Rewritten names are neither probed for nor defended against conflicts,
+/// so they should be names no other host on the link uses.
+#[derive(Clone)]
+pub struct HostnameRewrite(Arc<RewriteFn>);
+
+type RewriteFn = dyn Fn(&SocketAddr) -> Option<String> + Send + Sync;
+
+impl HostnameRewrite {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> Option<String> + Send + Sync + 'static,
+    {
+        HostnameRewrite(Arc::new(f))
+    }
+
+    pub(crate) fn rewrite(&self, querier: &SocketAddr) -> Option<String> {
+        (self.0)(querier)
+    }
+}
+
+impl fmt::Debug for HostnameRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("HostnameRewrite")
+    }
+}
+
+/// A record of ours that another host claims with different data
+/// (RFC 6762 section 9)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    /// Name of the conflicting record, a service instance or our hostname
+    pub name: String,
+    /// Numeric TYPE of the record, e.g. 33 for SRV
+    pub rtype: u16,
+    /// The host that sent the conflicting record
+    pub from: SocketAddr,
+    pub resolution: ConflictResolution,
+}
+
+/// What the responder did about a `Conflict`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Our record won the tiebreak and was announced again
+    Defended,
+    /// The service lost the tiebreak and now goes by this name
+    Renamed(String),
+    /// Our hostname lost the tiebreak, which is left to the application
+    Unresolved,
+}
+
+/// A callback for conflicts with records announced by other hosts
+///
+/// It runs on the responder's task and should be quick.
+#[derive(Clone)]
+pub struct ConflictObserver(Arc<ConflictFn>);
+
+type ConflictFn = dyn Fn(&Conflict) + Send + Sync;
+
+impl ConflictObserver {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Conflict) + Send + Sync + 'static,
+    {
+        ConflictObserver(Arc::new(f))
+    }
+
+    pub(crate) fn observe(&self, conflict: &Conflict) {
+        (self.0)(conflict)
+    }
+}
+
+impl fmt::Debug for ConflictObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ConflictObserver")
+    }
+}
+
+/// A datagram as received, and where it came from
+pub type TappedPacket = (Vec<u8>, SocketAddr);
+
+/// Hands out copies of received datagrams, see `Config::packet_tap`
+///
+/// Datagrams are dropped rather than queued while the receiver is behind,
+/// so a slow consumer never holds up the responder.
+#[derive(Clone, Debug)]
+pub struct PacketTap(SyncSender<TappedPacket>);
+
+impl PacketTap {
+    /// Creates a tap which holds up to `capacity` datagrams that have yet
+    /// to be received
+    pub fn new(capacity: usize) -> (PacketTap, Receiver<TappedPacket>) {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        (PacketTap(tx), rx)
+    }
+
+    pub(crate) fn deliver(&self, packet: &[u8], from: SocketAddr) {
+        // Full or nobody listening, either way the copy isn't wanted
+        let _ = self.0.try_send((packet.to_vec(), from));
+    }
+}
+
+/// Interfaces macOS lists alongside the real ones, which peers can't reach
+/// us through
+const SPECIAL_INTERFACES: &[&str] = &["awdl0", "llw0"];
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interfaces: None,
+            excluded_interfaces: None,
+            include_special_interfaces: false,
+            interface_preference: HashMap::new(),
+            stale_address_window: None,
+            interface_refresh_interval: Duration::from_secs(30),
+            v6_only: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            include_both_families: false,
+            unicast_source_from_subnet: false,
+            unicast_via_arrival_interface: false,
+            socket_per_interface: false,
+            multicast_group_v4: None,
+            multicast_group_v6: None,
+            refresh_fraction: None,
+            rate_limit: None,
+            max_multicast_rate: None,
+            dedup_outgoing: true,
+            query_observer: None,
+            outgoing_filter: None,
+            hostname_rewrite: None,
+            bind_retry_interval: None,
+            join_retry_interval: None,
+            rejoin_interval: None,
+            legacy_unicast_ttl: 10,
+            ttls: Ttls::default(),
+            querier_window: Duration::from_secs(60),
+            advertise_interval: None,
+            conflict_observer: None,
+            ptr_instances: PtrInstances::Capped(100),
+            health_check_interval: None,
+            goodbye_on_disconnect: true,
+            advertised_payload_size: None,
+            packet_tap: None,
+            when_no_addresses: NoAddresses::Answer,
+            upgrade_stale_qu: false,
+            max_questions: 100,
+            strict_query_flags: false,
+            announce_jitter: Duration::from_millis(250),
+            announce_on_register: true,
+            passive_only: false,
+            goodbye_count: 1,
+            goodbye_interval: Duration::from_millis(250),
+            probe_before_announcing: false,
+            prebuilt_responses: false,
+            ptr_response: PtrResponse::Bundled,
+            placement: Placement::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Rejects settings which contradict each other
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.interfaces.is_some() && self.excluded_interfaces.is_some() {
+            return Err(Error::ConflictingInterfaceFilters);
+        }
+        let groups = [
+            self.multicast_group_v4.map(IpAddr::V4),
+            self.multicast_group_v6.map(IpAddr::V6),
+        ];
+        if let Some(group) = groups.iter().flatten().find(|ip| !ip.is_multicast()) {
+            return Err(Error::NotMulticast(*group));
+        }
+        if self.goodbye_count == 0 {
+            return Err(Error::NoGoodbyes);
+        }
+        if let Some(ref limit) = self.rate_limit {
+            if limit.soft_limit > limit.hard_limit {
+                return Err(Error::SoftLimitAboveHard(
+                    limit.soft_limit,
+                    limit.hard_limit,
+                ));
+            }
+        }
+        match self.advertised_payload_size {
+            Some(size) if !(512..4096).contains(&size) => Err(Error::PayloadSizeOutOfRange(size)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn allows_interface(&self, name: &str) -> bool {
+        if let Some(ref names) = self.interfaces {
+            return names.iter().any(|n| n == name);
+        }
+        if cfg!(target_os = "macos")
+            && !self.include_special_interfaces
+            && SPECIAL_INTERFACES.contains(&name)
+        {
+            return false;
+        }
+        match self.excluded_interfaces {
+            Some(ref names) => !names.iter().any(|n| n == name),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, RateLimit, Schedule, Subnet};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn rejects_both_interface_filters() {
+        let mut config = Config {
+            interfaces: Some(vec!["eth0".to_owned()]),
+            ..Config::default()
+        };
+        assert!(config.check().is_ok());
+        config.excluded_interfaces = Some(vec!["tun0".to_owned()]);
+        assert!(config.check().is_err());
+        config.interfaces = None;
+        assert!(config.check().is_ok());
+        assert!(!config.allows_interface("tun0"));
+        assert!(config.allows_interface("eth0"));
+    }
+
+    #[test]
+    fn rejects_unicast_groups() {
+        let mut config = Config {
+            multicast_group_v4: Some("239.255.0.251".parse().unwrap()),
+            multicast_group_v6: Some("ff05::fb".parse().unwrap()),
+            ..Config::default()
+        };
+        assert!(config.check().is_ok());
+        config.multicast_group_v6 = Some("fe80::1".parse().unwrap());
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn rejects_payload_sizes_we_cant_receive() {
+        let mut config = Config {
+            advertised_payload_size: Some(1440),
+            ..Config::default()
+        };
+        assert!(config.check().is_ok());
+        config.advertised_payload_size = Some(9000);
+        assert!(config.check().is_err());
+        config.advertised_payload_size = Some(100);
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn rejects_soft_limit_above_hard_one() {
+        let mut config = Config {
+            rate_limit: Some(RateLimit {
+                window: Duration::from_secs(1),
+                soft_limit: 2,
+                hard_limit: 2,
+            }),
+            ..Config::default()
+        };
+        assert!(config.check().is_ok());
+        config.rate_limit.as_mut().unwrap().soft_limit = 3;
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn rejects_never_saying_goodbye() {
+        let mut config = Config {
+            goodbye_count: 1,
+            ..Config::default()
+        };
+        assert!(config.check().is_ok());
+        config.goodbye_count = 0;
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn schedule_spans_midnight_when_ending_first() {
+        let hours = |h: u64| Duration::from_secs(h * 60 * 60);
+        let at = |h| UNIX_EPOCH + hours(h);
+        let night = Schedule {
+            start: hours(22),
+            end: hours(6),
+        };
+        assert!(night.contains(at(23)));
+        assert!(night.contains(at(24 + 5)));
+        assert!(!night.contains(at(6)));
+        assert_eq!(night.until_change(at(6)), hours(16));
+        assert_eq!(night.until_change(at(23)), hours(7));
+    }
+
+    #[test]
+    fn schedule_ending_as_it_starts_spans_the_day() {
+        let hours = |h: u64| Duration::from_secs(h * 60 * 60);
+        let day = Schedule {
+            start: hours(8),
+            end: hours(24 + 8),
+        };
+        for h in 0..24 {
+            assert!(day.contains(UNIX_EPOCH + hours(h)), "{}", h);
+        }
+    }
+
+    #[test]
+    fn subnet_contains_addresses() {
+        let subnet: Subnet = "10.0.0.0/24".parse().unwrap();
+        assert!(subnet.contains("10.0.0.200".parse().unwrap()));
+        assert!(!subnet.contains("10.0.1.1".parse().unwrap()));
+        assert!(!subnet.contains("::1".parse().unwrap()));
+
+        let subnet: Subnet = "fe80::/10".parse().unwrap();
+        assert!(subnet.contains("fe80::1".parse().unwrap()));
+        assert!(!subnet.contains("2001:db8::1".parse().unwrap()));
+
+        let any: Subnet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+        assert!("10.0.0.0".parse::<Subnet>().is_err());
+        assert_eq!(
+            "10.0.0.0".parse::<Subnet>().unwrap_err().to_string(),
+            "Invalid subnet \"10.0.0.0\""
+        );
+    }
+}
@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::marker::PhantomData;
 
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
-use super::{Header, Name, Opcode, QueryClass, QueryType, RRData, ResponseCode};
+use super::{Header, Name, Opcode, Packet, QueryClass, QueryType, RRData, ResponseCode, Type};
 
 pub enum Questions {}
 pub enum Answers {}
@@ -29,6 +30,11 @@ impl MoveTo<Additional> for Nameservers {}
 pub struct Builder<S> {
     buf: Vec<u8>,
     max_size: Option<usize>,
+    /// Offsets of the names written so far, by their suffixes, if names are
+    /// being compressed
+    names: Option<HashMap<String, u16>>,
+    /// Whether to set the cache-flush bit on unique records
+    cache_flush: bool,
     _state: PhantomData<S>,
 }
 
@@ -41,7 +47,7 @@ impl Builder<Questions> {
     pub fn new_query(id: u16, recursion: bool) -> Builder<Questions> {
         let mut buf = Vec::with_capacity(512);
         let head = Header {
-            id: id,
+            id,
             query: true,
             opcode: Opcode::StandardQuery,
             authoritative: false,
@@ -57,8 +63,10 @@ impl Builder<Questions> {
         buf.extend([0u8; 12].iter());
         head.write(&mut buf[..12]);
         Builder {
-            buf: buf,
+            buf,
             max_size: Some(512),
+            names: None,
+            cache_flush: false,
             _state: PhantomData,
         }
     }
@@ -66,10 +74,10 @@ impl Builder<Questions> {
     pub fn new_response(id: u16, recursion: bool, authoritative: bool) -> Builder<Questions> {
         let mut buf = Vec::with_capacity(512);
         let head = Header {
-            id: id,
+            id,
             query: false,
             opcode: Opcode::StandardQuery,
-            authoritative: authoritative,
+            authoritative,
             truncated: false,
             recursion_desired: recursion,
             recursion_available: false,
@@ -82,8 +90,10 @@ impl Builder<Questions> {
         buf.extend([0u8; 12].iter());
         head.write(&mut buf[..12]);
         Builder {
-            buf: buf,
+            buf,
             max_size: Some(512),
+            names: None,
+            cache_flush: false,
             _state: PhantomData,
         }
     }
@@ -91,16 +101,21 @@ impl Builder<Questions> {
 
 impl<T> Builder<T> {
     fn write_rr(&mut self, name: &Name, cls: QueryClass, ttl: u32, data: &RRData) {
-        name.write_to(&mut self.buf).unwrap();
+        self.write_name(name);
         self.buf.write_u16::<BigEndian>(data.typ() as u16).unwrap();
-        self.buf.write_u16::<BigEndian>(cls as u16).unwrap();
+        let mut cls = cls as u16;
+        // PTR records are shared, everything else we send is unique
+        if self.cache_flush && data.typ() != Type::PTR {
+            cls |= 0x8000;
+        }
+        self.buf.write_u16::<BigEndian>(cls).unwrap();
         self.buf.write_u32::<BigEndian>(ttl).unwrap();
 
         let size_offset = self.buf.len();
         self.buf.write_u16::<BigEndian>(0).unwrap();
 
         let data_offset = self.buf.len();
-        data.write_to(&mut self.buf).unwrap();
+        self.write_rdata(data);
         let data_size = self.buf.len() - data_offset;
 
         BigEndian::write_u16(
@@ -109,6 +124,75 @@ impl<T> Builder<T> {
         );
     }
 
+    fn write_rdata(&mut self, data: &RRData) {
+        if self.names.is_none() {
+            data.write_to(&mut self.buf).unwrap();
+            return;
+        }
+        match *data {
+            RRData::CNAME(ref name) | RRData::NS(ref name) | RRData::PTR(ref name) => {
+                self.write_name(name)
+            }
+            RRData::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => {
+                self.buf.write_u16::<BigEndian>(priority).unwrap();
+                self.buf.write_u16::<BigEndian>(weight).unwrap();
+                self.buf.write_u16::<BigEndian>(port).unwrap();
+                self.write_name(target);
+            }
+            RRData::MX {
+                preference,
+                ref exchange,
+            } => {
+                self.buf.write_u16::<BigEndian>(preference).unwrap();
+                self.write_name(exchange);
+            }
+            _ => data.write_to(&mut self.buf).unwrap(),
+        }
+    }
+
+    /// Writes a name, pointing back to an earlier occurrence of its longest
+    /// possible suffix if compression is enabled
+    fn write_name(&mut self, name: &Name) {
+        let names = match self.names {
+            Some(ref mut names) => names,
+            None => {
+                name.write_to(&mut self.buf).unwrap();
+                return;
+            }
+        };
+
+        let name = name.to_string();
+        let name = name.strip_suffix('.').unwrap_or(&name);
+        let labels: Vec<&str> = if name.is_empty() {
+            Vec::new()
+        } else {
+            name.split('.').collect()
+        };
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".");
+            if let Some(&offset) = names.get(&suffix) {
+                self.buf
+                    .write_u16::<BigEndian>(0b1100_0000_0000_0000 | offset)
+                    .unwrap();
+                return;
+            }
+            // Pointers have 14 bits for the offset
+            if self.buf.len() < 0x4000 {
+                names.insert(suffix, self.buf.len() as u16);
+            }
+            let label = labels[i];
+            assert!(label.len() < 63);
+            self.buf.write_u8(label.len() as u8).unwrap();
+            self.buf.extend_from_slice(label.as_bytes());
+        }
+        self.buf.write_u8(0).unwrap();
+    }
+
     /// Returns the final packet
     ///
     /// When packet is not truncated method returns `Ok(packet)`. If
@@ -141,6 +225,8 @@ impl<T> Builder<T> {
         Builder {
             buf: self.buf,
             max_size: self.max_size,
+            names: self.names,
+            cache_flush: self.cache_flush,
             _state: PhantomData,
         }
     }
@@ -149,12 +235,33 @@ impl<T> Builder<T> {
         self.max_size = max_size;
     }
 
+    /// Compresses names written from now on by pointing back to earlier
+    /// occurrences of their suffixes (RFC 1035 section 4.1.4)
+    pub fn set_compression(&mut self, enabled: bool) {
+        if !enabled {
+            self.names = None;
+        } else if self.names.is_none() {
+            self.names = Some(HashMap::new());
+        }
+    }
+
+    /// Marks records written from now on as the complete set for their name
+    /// and type, so peers flush whatever else they cached for it (RFC 6762
+    /// section 10.2). PTR records are left alone, as they are shared.
+    pub fn set_cache_flush(&mut self, enabled: bool) {
+        self.cache_flush = enabled;
+    }
+
     pub fn is_empty(&self) -> bool {
         Header::question_count(&self.buf) == 0
             && Header::answer_count(&self.buf) == 0
             && Header::nameserver_count(&self.buf) == 0
             && Header::additional_count(&self.buf) == 0
     }
+
+    pub fn has_answers(&self) -> bool {
+        Header::answer_count(&self.buf) > 0
+    }
 }
 
 impl<T: MoveTo<Questions>> Builder<T> {
@@ -172,8 +279,8 @@ impl<T: MoveTo<Questions>> Builder<T> {
     ) -> Builder<Questions> {
         let mut builder = self.move_to::<Questions>();
 
-        qname.write_to(&mut builder.buf).unwrap();
-        builder.buf.write_u16::<BigEndian>(qtype as u16).unwrap();
+        builder.write_name(qname);
+        builder.buf.write_u16::<BigEndian>(qtype.into()).unwrap();
         builder.buf.write_u16::<BigEndian>(qclass as u16).unwrap();
         Header::inc_questions(&mut builder.buf).expect("Too many questions");
         builder
@@ -195,10 +302,39 @@ impl<T: MoveTo<Answers>> Builder<T> {
 
         builder
     }
+
+    /// Adds a record of any type and class, with `rdata` as is
+    ///
+    /// # Panics
+    ///
+    /// * `rdata` is longer than 65535 bytes.
+    pub fn add_raw_answer(
+        self,
+        name: &Name,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+        rdata: &[u8],
+    ) -> Builder<Answers> {
+        assert!(rdata.len() <= u16::MAX as usize, "rdata too long");
+        let mut builder = self.move_to::<Answers>();
+
+        builder.write_name(name);
+        builder.buf.write_u16::<BigEndian>(rtype).unwrap();
+        builder.buf.write_u16::<BigEndian>(class).unwrap();
+        builder.buf.write_u32::<BigEndian>(ttl).unwrap();
+        builder
+            .buf
+            .write_u16::<BigEndian>(rdata.len() as u16)
+            .unwrap();
+        builder.buf.extend_from_slice(rdata);
+        Header::inc_answers(&mut builder.buf).expect("Too many answers");
+
+        builder
+    }
 }
 
 impl<T: MoveTo<Nameservers>> Builder<T> {
-    #[allow(dead_code)]
     pub fn add_nameserver(
         self,
         name: &Name,
@@ -215,6 +351,42 @@ impl<T: MoveTo<Nameservers>> Builder<T> {
     }
 }
 
+impl<T: MoveTo<Additional>> Builder<T> {
+    /// Adds the answers of `records` as additional records, e.g. so that
+    /// both sections can be filled at the same time
+    ///
+    /// Names are compressed like any others added to this builder. Should
+    /// `records` hold one that can't be parsed, they are copied as they
+    /// are, so they must have been written without compression.
+    pub fn add_additionals_from(self, records: &Builder<Answers>) -> Builder<Additional> {
+        let mut builder = self.move_to::<Additional>();
+        let count = Header::answer_count(&records.buf);
+        if count == 0 {
+            return builder;
+        }
+
+        let packet = Packet::parse(&records.buf)
+            .ok()
+            .filter(|packet| packet.answers.len() == count as usize);
+        match packet {
+            Some(packet) => {
+                for rr in &packet.answers {
+                    let cls = QueryClass::parse(rr.cls as u16).unwrap_or(QueryClass::IN);
+                    builder.write_rr(&rr.name, cls, rr.ttl, &rr.data);
+                    Header::inc_additional(&mut builder.buf).expect("Too many additional answers");
+                }
+            }
+            None => {
+                builder.buf.extend_from_slice(&records.buf[12..]);
+                for _ in 0..count {
+                    Header::inc_additional(&mut builder.buf).expect("Too many additional answers");
+                }
+            }
+        }
+        builder
+    }
+}
+
 impl Builder<Additional> {
     #[allow(dead_code)]
     pub fn add_additional(
@@ -227,7 +399,27 @@ impl Builder<Additional> {
         let mut builder = self.move_to::<Additional>();
 
         builder.write_rr(name, cls, ttl, data);
-        Header::inc_nameservers(&mut builder.buf).expect("Too many additional answers");
+        Header::inc_additional(&mut builder.buf).expect("Too many additional answers");
+
+        builder
+    }
+
+    /// Adds an OPT pseudo-record advertising `payload_size` as the largest
+    /// UDP payload we accept (RFC 6891 section 6.1.2), which has to come
+    /// last
+    pub fn add_opt(self, payload_size: u16) -> Builder<Additional> {
+        let mut builder = self.move_to::<Additional>();
+
+        // The root name, no extended RCODE or flags, and no options
+        builder.buf.push(0);
+        builder
+            .buf
+            .write_u16::<BigEndian>(Type::OPT as u16)
+            .unwrap();
+        builder.buf.write_u16::<BigEndian>(payload_size).unwrap();
+        builder.buf.write_u32::<BigEndian>(0).unwrap();
+        builder.buf.write_u16::<BigEndian>(0).unwrap();
+        Header::inc_additional(&mut builder.buf).expect("Too many additional answers");
 
         builder
     }
@@ -237,8 +429,11 @@ impl Builder<Additional> {
 mod test {
     use super::Builder;
     use super::Name;
+    use super::QueryClass;
     use super::QueryClass as QC;
     use super::QueryType as QT;
+    use super::RRData;
+    use crate::dns_parser::{Answers, Packet};
 
     #[test]
     fn build_query() {
@@ -259,4 +454,73 @@ mod test {
             \x0c_xmpp-server\x04_tcp\x05gmail\x03com\x00\x00!\x00\x01";
         assert_eq!(&bld.build().unwrap()[..], &result[..]);
     }
+
+    #[test]
+    fn build_query_with_trailing_dot() {
+        let mut bld = Builder::new_query(1573, true);
+        let name = Name::from_str("example.com.").unwrap();
+        bld = bld.add_question(&name, QT::A, QC::IN);
+        let result = b"\x06%\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+                      \x07example\x03com\x00\x00\x01\x00\x01";
+        assert_eq!(&bld.build().unwrap()[..], &result[..]);
+        assert_eq!(name, Name::from_str("example.com").unwrap());
+    }
+
+    #[test]
+    fn sets_cache_flush_on_unique_records() {
+        let mut bld = Builder::new_response(0, false, true).move_to::<Answers>();
+        bld.set_cache_flush(true);
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        let name = Name::from_str("Test._http._tcp.local").unwrap();
+        bld = bld.add_answer(&typ, QueryClass::IN, 60, &RRData::PTR(name.clone()));
+        bld = bld.add_answer(&name, QueryClass::IN, 60, &RRData::TXT(b"\x00"));
+        let packet = bld.build().unwrap();
+
+        // Class of the PTR record, right after its name and type
+        let ptr_class = 12 + 18 + 2;
+        assert_eq!(&packet[ptr_class..ptr_class + 2], b"\x00\x01");
+        // Class of the TXT record, after the PTR's TTL and rdata
+        let txt_class = ptr_class + 2 + 4 + 2 + 23 + 23 + 2;
+        assert_eq!(&packet[txt_class..txt_class + 2], b"\x80\x01");
+        // Parsed regardless
+        assert_eq!(Packet::parse(&packet).unwrap().answers.len(), 2);
+    }
+
+    fn response(compress: bool) -> Vec<u8> {
+        let mut bld = Builder::new_response(0, false, true).move_to::<Answers>();
+        bld.set_compression(compress);
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        let host = Name::from_str("host.local").unwrap();
+        for instance in &["One._http._tcp.local", "Two._http._tcp.local"] {
+            let name = Name::from_str(*instance).unwrap();
+            bld = bld.add_answer(&typ, QueryClass::IN, 60, &RRData::PTR(name.clone()));
+            let srv = RRData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 80,
+                target: host.clone(),
+            };
+            bld = bld.add_answer(&name, QueryClass::IN, 60, &srv);
+        }
+        bld.build().unwrap()
+    }
+
+    #[test]
+    fn compresses_repeated_names() {
+        let plain = response(false);
+        let compressed = response(true);
+        assert!(compressed.len() < plain.len());
+
+        let plain = Packet::parse(&plain).unwrap();
+        let compressed = Packet::parse(&compressed).unwrap();
+        assert_eq!(compressed.answers.len(), 4);
+        for (a, b) in plain.answers.iter().zip(&compressed.answers) {
+            assert_eq!(a.name.to_string(), b.name.to_string());
+            assert_eq!(format!("{:?}", a.data.typ()), format!("{:?}", b.data.typ()));
+        }
+        match compressed.answers[3].data {
+            RRData::SRV { ref target, .. } => assert_eq!(target.to_string(), "host.local"),
+            ref data => panic!("unexpected {:?}", data),
+        }
+    }
 }
@@ -41,6 +41,8 @@ pub enum Type {
     SRV = 33,
     /// EDNS0 options (RFC 6891)
     OPT = 41,
+    /// the types a name has records of (RFC 4034)
+    NSEC = 47,
 }
 
 /// The QTYPE value according to RFC 1035
@@ -49,47 +51,49 @@ pub enum Type {
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum QueryType {
     /// a host addresss
-    A = 1,
+    A,
     /// an authoritative name server
-    NS = 2,
+    NS,
     /// a mail forwarder (Obsolete - use MX)
-    MF = 4,
+    MF,
     /// the canonical name for an alias
-    CNAME = 5,
+    CNAME,
     /// marks the start of a zone of authority
-    SOA = 6,
+    SOA,
     /// a mailbox domain name (EXPERIMENTAL)
-    MB = 7,
+    MB,
     /// a mail group member (EXPERIMENTAL)
-    MG = 8,
+    MG,
     /// a mail rename domain name (EXPERIMENTAL)
-    MR = 9,
+    MR,
     /// a null RR (EXPERIMENTAL)
-    NULL = 10,
+    NULL,
     /// a well known service description
-    WKS = 11,
+    WKS,
     /// a domain name pointer
-    PTR = 12,
+    PTR,
     /// host information
-    HINFO = 13,
+    HINFO,
     /// mailbox or mail list information
-    MINFO = 14,
+    MINFO,
     /// mail exchange
-    MX = 15,
+    MX,
     /// text strings
-    TXT = 16,
+    TXT,
     /// IPv6 host address (RFC 2782)
-    AAAA = 28,
+    AAAA,
     /// service record (RFC 2782)
-    SRV = 33,
+    SRV,
     /// A request for a transfer of an entire zone
-    AXFR = 252,
+    AXFR,
     /// A request for mailbox-related records (MB, MG or MR)
-    MAILB = 253,
+    MAILB,
     /// A request for mail agent RRs (Obsolete - see MX)
-    MAILA = 254,
+    MAILA,
     /// A request for all records
-    All = 255,
+    All,
+    /// Any other value, so unknown types don't fail the whole packet
+    Unknown(u16),
 }
 
 /// The CLASS value according to RFC 1035
@@ -154,10 +158,10 @@ impl From<u16> for Opcode {
         }
     }
 }
-impl Into<u16> for Opcode {
-    fn into(self) -> u16 {
+impl From<Opcode> for u16 {
+    fn from(val: Opcode) -> Self {
         use self::Opcode::*;
-        match self {
+        match val {
             StandardQuery => 0,
             InverseQuery => 1,
             ServerStatusRequest => 2,
@@ -181,10 +185,10 @@ impl From<u8> for ResponseCode {
         }
     }
 }
-impl Into<u8> for ResponseCode {
-    fn into(self) -> u8 {
+impl From<ResponseCode> for u8 {
+    fn from(val: ResponseCode) -> Self {
         use self::ResponseCode::*;
-        match self {
+        match val {
             NoError => 0,
             FormatError => 1,
             ServerFailure => 2,
@@ -197,31 +201,61 @@ impl Into<u8> for ResponseCode {
 }
 
 impl QueryType {
-    pub fn parse(code: u16) -> Result<QueryType, Error> {
+    pub fn parse(code: u16) -> QueryType {
         use self::QueryType::*;
         match code {
-            1 => Ok(A),
-            2 => Ok(NS),
-            4 => Ok(MF),
-            5 => Ok(CNAME),
-            6 => Ok(SOA),
-            7 => Ok(MB),
-            8 => Ok(MG),
-            9 => Ok(MR),
-            10 => Ok(NULL),
-            11 => Ok(WKS),
-            12 => Ok(PTR),
-            13 => Ok(HINFO),
-            14 => Ok(MINFO),
-            15 => Ok(MX),
-            16 => Ok(TXT),
-            28 => Ok(AAAA),
-            33 => Ok(SRV),
-            252 => Ok(AXFR),
-            253 => Ok(MAILB),
-            254 => Ok(MAILA),
-            255 => Ok(All),
-            x => Err(Error::InvalidQueryType(x)),
+            1 => A,
+            2 => NS,
+            4 => MF,
+            5 => CNAME,
+            6 => SOA,
+            7 => MB,
+            8 => MG,
+            9 => MR,
+            10 => NULL,
+            11 => WKS,
+            12 => PTR,
+            13 => HINFO,
+            14 => MINFO,
+            15 => MX,
+            16 => TXT,
+            28 => AAAA,
+            33 => SRV,
+            252 => AXFR,
+            253 => MAILB,
+            254 => MAILA,
+            255 => All,
+            x => Unknown(x),
+        }
+    }
+}
+
+impl From<QueryType> for u16 {
+    fn from(qtype: QueryType) -> u16 {
+        use self::QueryType::*;
+        match qtype {
+            A => 1,
+            NS => 2,
+            MF => 4,
+            CNAME => 5,
+            SOA => 6,
+            MB => 7,
+            MG => 8,
+            MR => 9,
+            NULL => 10,
+            WKS => 11,
+            PTR => 12,
+            HINFO => 13,
+            MINFO => 14,
+            MX => 15,
+            TXT => 16,
+            AAAA => 28,
+            SRV => 33,
+            AXFR => 252,
+            MAILB => 253,
+            MAILA => 254,
+            All => 255,
+            Unknown(x) => x,
         }
     }
 }
@@ -262,6 +296,7 @@ impl Type {
             28 => Ok(AAAA),
             33 => Ok(SRV),
             41 => Ok(OPT),
+            47 => Ok(NSEC),
             x => Err(Error::InvalidType(x)),
         }
     }
@@ -12,4 +12,6 @@ pub use self::header::Header;
 mod rrdata;
 pub use self::rrdata::RRData;
 mod builder;
-pub use self::builder::{Answers, Builder, Questions};
+#[cfg(test)]
+pub use self::builder::Additional;
+pub use self::builder::{Answers, Builder, Nameservers};
@@ -37,7 +37,7 @@ impl<'a> Name<'a> {
                 return Ok((
                     Name::FromPacket {
                         labels: &data[..pos + 1],
-                        original: original,
+                        original,
                     },
                     pos + 1,
                 ));
@@ -55,7 +55,7 @@ impl<'a> Name<'a> {
                 return Ok((
                     Name::FromPacket {
                         labels: &data[..pos + 2],
-                        original: original,
+                        original,
                     },
                     pos + 2,
                 ));
@@ -97,7 +97,7 @@ impl<'a> Name<'a> {
                             .write_to(writer);
                     } else if byte & 0b1100_0000 == 0 {
                         let end = pos + byte as usize + 1;
-                        writer.write(&labels[pos..end])?;
+                        writer.write_all(&labels[pos..end])?;
                         pos = end;
                         continue;
                     } else {
@@ -107,11 +107,16 @@ impl<'a> Name<'a> {
             }
 
             Name::FromStr(ref name) => {
-                for part in name.split('.') {
-                    assert!(part.len() < 63);
-                    let ln = part.len() as u8;
-                    writer.write_u8(ln)?;
-                    writer.write(part.as_bytes())?;
+                // "example.com." and "example.com" are the same name, the
+                // trailing dot only spells out the root label
+                let name = name.strip_suffix('.').unwrap_or(name);
+                if !name.is_empty() {
+                    for part in name.split('.') {
+                        assert!(part.len() < 63);
+                        let ln = part.len() as u8;
+                        writer.write_u8(ln)?;
+                        writer.write_all(part.as_bytes())?;
+                    }
                 }
                 writer.write_u8(0)?;
 
@@ -154,7 +159,8 @@ impl<'a> fmt::Display for Name<'a> {
                 }
             }
 
-            Name::FromStr(ref name) => fmt.write_str(&name),
+            // Displayed like the name parsed from a packet would be
+            Name::FromStr(ref name) => fmt.write_str(name.strip_suffix('.').unwrap_or(name)),
         }
     }
 }
@@ -1,12 +1,10 @@
-use std::i32;
-
 use byteorder::{BigEndian, ByteOrder};
 
 use super::{Class, RRData, ResourceRecord, Type};
 use super::{Error, Header, Name, Packet, QueryClass, QueryType, Question};
 
 impl<'a> Packet<'a> {
-    pub fn parse(data: &[u8]) -> Result<Packet, Error> {
+    pub fn parse(data: &[u8]) -> Result<Packet<'_>, Error> {
         let header = Header::parse(data)?;
         let mut offset = Header::size();
         let mut questions = Vec::with_capacity(header.questions as usize);
@@ -16,7 +14,7 @@ impl<'a> Packet<'a> {
             if offset + 4 > data.len() {
                 return Err(Error::UnexpectedEOF);
             }
-            let qtype = QueryType::parse(BigEndian::read_u16(&data[offset..offset + 2]))?;
+            let qtype = QueryType::parse(BigEndian::read_u16(&data[offset..offset + 2]));
             offset += 2;
             let qclass_qu = BigEndian::read_u16(&data[offset..offset + 2]);
             let qclass = QueryClass::parse(qclass_qu & 0x7fff)?;
@@ -25,9 +23,9 @@ impl<'a> Packet<'a> {
             offset += 2;
             questions.push(Question {
                 qname: name,
-                qtype: qtype,
-                qclass: qclass,
-                qu: qu,
+                qtype,
+                qclass,
+                qu,
             });
         }
         let mut answers = Vec::with_capacity(header.answers as usize);
@@ -38,12 +36,23 @@ impl<'a> Packet<'a> {
         for _ in 0..header.nameservers {
             nameservers.push(parse_record(data, &mut offset)?);
         }
+        // Unlike the sections before it, the additional section often has
+        // records there's no support for, such as OPT with the payload size
+        // in its class field, so it ends at the first of them rather than
+        // failing the whole packet
+        let mut additional = Vec::with_capacity(header.additional as usize);
+        for _ in 0..header.additional {
+            match parse_record(data, &mut offset) {
+                Ok(record) => additional.push(record),
+                Err(_) => break,
+            }
+        }
         Ok(Packet {
-            header: header,
-            questions: questions,
-            answers: answers,
-            nameservers: nameservers,
-            additional: Vec::new(), // TODO(tailhook)
+            header,
+            questions,
+            answers,
+            nameservers,
+            additional,
         })
     }
 }
@@ -72,14 +81,15 @@ fn parse_record<'a>(data: &'a [u8], offset: &mut usize) -> Result<ResourceRecord
     let data = RRData::parse(typ, &data[*offset..*offset + rdlen], data)?;
     *offset += rdlen;
     Ok(ResourceRecord {
-        name: name,
-        cls: cls,
-        ttl: ttl,
-        data: data,
+        name,
+        cls,
+        ttl,
+        data,
     })
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_range_loop)]
 mod test {
 
     use super::super::Opcode::*;
@@ -120,6 +130,23 @@ mod test {
         assert_eq!(packet.answers.len(), 0);
     }
 
+    #[test]
+    fn parse_additionals_up_to_unsupported_record() {
+        // An A record, then OPT with a payload size of 1232 as its class
+        let query = b"\x06%\x01\x00\x00\x01\x00\x00\x00\x00\x00\x02\
+                      \x07example\x03com\x00\x00\x01\x00\x01\
+                      \xc0\x0c\x00\x01\x00\x01\x00\x00\x04\xf8\x00\x04]\xb8\xd8\"\
+                      \x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x00";
+        let packet = Packet::parse(query).unwrap();
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.additional.len(), 1);
+        assert_eq!(packet.additional[0].ttl, 1272);
+        match packet.additional[0].data {
+            RRData::A(addr) => assert_eq!(addr, Ipv4Addr::new(93, 184, 216, 34)),
+            ref x => panic!("Wrong rdata {:?}", x),
+        }
+    }
+
     #[test]
     fn parse_example_response() {
         let response = b"\x06%\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
@@ -250,7 +277,7 @@ mod test {
         assert_eq!(packet.questions[0].qclass, QC::IN);
         assert_eq!(&packet.questions[0].qname.to_string()[..], "google.com");
         assert_eq!(packet.answers.len(), 6);
-        let ips = vec![
+        let ips = [
             Ipv4Addr::new(64, 233, 164, 100),
             Ipv4Addr::new(64, 233, 164, 139),
             Ipv4Addr::new(64, 233, 164, 113),
@@ -343,7 +370,7 @@ mod test {
             "_xmpp-server._tcp.gmail.com"
         );
         assert_eq!(packet.answers.len(), 5);
-        let items = vec![
+        let items = [
             (5, 0, 5269, "xmpp-server.l.google.com"),
             (20, 0, 5269, "alt3.xmpp-server.l.google.com"),
             (20, 0, 5269, "alt1.xmpp-server.l.google.com"),
@@ -357,7 +384,7 @@ mod test {
             );
             assert_eq!(packet.answers[i].cls, C::IN);
             assert_eq!(packet.answers[i].ttl, 900);
-            match *&packet.answers[i].data {
+            match packet.answers[i].data {
                 RRData::SRV {
                     priority,
                     weight,
@@ -407,7 +434,7 @@ mod test {
         assert_eq!(packet.questions[0].qclass, QC::IN);
         assert_eq!(&packet.questions[0].qname.to_string()[..], "gmail.com");
         assert_eq!(packet.answers.len(), 5);
-        let items = vec![
+        let items = [
             (5, "gmail-smtp-in.l.google.com"),
             (10, "alt1.gmail-smtp-in.l.google.com"),
             (40, "alt4.gmail-smtp-in.l.google.com"),
@@ -418,7 +445,7 @@ mod test {
             assert_eq!(&packet.answers[i].name.to_string()[..], "gmail.com");
             assert_eq!(packet.answers[i].cls, C::IN);
             assert_eq!(packet.answers[i].ttl, 1148);
-            match *&packet.answers[i].data {
+            match packet.answers[i].data {
                 RRData::MX {
                     preference,
                     ref exchange,
@@ -526,7 +553,7 @@ mod test {
             ref x => panic!("Wrong rdata {:?}", x),
         }
 
-        let ips = vec![
+        let ips = [
             Ipv4Addr::new(104, 16, 103, 204),
             Ipv4Addr::new(104, 16, 107, 204),
             Ipv4Addr::new(104, 16, 104, 204),
@@ -24,6 +24,13 @@ pub enum RRData<'a> {
         exchange: Name<'a>,
     },
     TXT(&'a [u8]),
+    /// The types of the records held for the owner name, by their numeric
+    /// values. mDNS uses it to tell which records don't exist (RFC 6762
+    /// section 6.1), with `next` set to the owner name itself.
+    NSEC {
+        next: Name<'a>,
+        types: Vec<u16>,
+    },
     // Anything that can't be parsed yet
     Unknown {
         typ: Type,
@@ -42,6 +49,7 @@ impl<'a> RRData<'a> {
             RRData::SRV { .. } => Type::SRV,
             RRData::MX { .. } => Type::MX,
             RRData::TXT(..) => Type::TXT,
+            RRData::NSEC { .. } => Type::NSEC,
             RRData::Unknown { typ, .. } => typ,
         }
     }
@@ -79,6 +87,13 @@ impl<'a> RRData<'a> {
                 exchange.write_to(writer)
             }
             RRData::TXT(data) => writer.write_all(data),
+            RRData::NSEC {
+                ref next,
+                ref types,
+            } => {
+                next.write_to(writer)?;
+                writer.write_all(&type_bitmaps(types))
+            }
             RRData::Unknown { data, .. } => writer.write_all(data),
         }
     }
@@ -130,10 +145,80 @@ impl<'a> RRData<'a> {
                 })
             }
             Type::TXT => Ok(RRData::TXT(rdata)),
-            typ => Ok(RRData::Unknown {
-                typ: typ,
-                data: rdata,
-            }),
+            Type::NSEC => {
+                let (next, len) = Name::scan(rdata, original)?;
+                let types = parse_type_bitmaps(&rdata[len..])?;
+                Ok(RRData::NSEC { next, types })
+            }
+            typ => Ok(RRData::Unknown { typ, data: rdata }),
+        }
+    }
+}
+
+/// Encodes `types` as NSEC type bitmaps (RFC 4034 section 4.1.2)
+fn type_bitmaps(types: &[u16]) -> Vec<u8> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    let mut encoded = Vec::new();
+    let mut window: Option<(u8, Vec<u8>)> = None;
+    for typ in types {
+        let (number, bit) = ((typ >> 8) as u8, (typ & 0xff) as usize);
+        if window.as_ref().map(|(n, _)| *n) != Some(number) {
+            if let Some((n, bitmap)) = window.take() {
+                encoded.push(n);
+                encoded.push(bitmap.len() as u8);
+                encoded.extend(bitmap);
+            }
+            window = Some((number, Vec::new()));
+        }
+        let bitmap = &mut window.as_mut().unwrap().1;
+        if bitmap.len() <= bit / 8 {
+            bitmap.resize(bit / 8 + 1, 0);
+        }
+        bitmap[bit / 8] |= 0x80 >> (bit % 8);
+    }
+    if let Some((n, bitmap)) = window {
+        encoded.push(n);
+        encoded.push(bitmap.len() as u8);
+        encoded.extend(bitmap);
+    }
+    encoded
+}
+
+fn parse_type_bitmaps(mut data: &[u8]) -> Result<Vec<u16>, Error> {
+    let mut types = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 2 {
+            return Err(Error::WrongRdataLength);
+        }
+        let (window, len) = (u16::from(data[0]), usize::from(data[1]));
+        if len == 0 || len > 32 || data.len() < 2 + len {
+            return Err(Error::WrongRdataLength);
+        }
+        for (i, byte) in data[2..2 + len].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(window << 8 | (i * 8 + bit) as u16);
+                }
+            }
         }
+        data = &data[2 + len..];
+    }
+    Ok(types)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_type_bitmaps, type_bitmaps};
+
+    #[test]
+    fn encodes_type_bitmaps_by_window() {
+        // TXT and SRV, then CAA from the next window (RFC 4034 section 4.3)
+        let encoded = type_bitmaps(&[33, 16, 257]);
+        assert_eq!(encoded, vec![0, 5, 0, 0, 0x80, 0, 0x40, 1, 1, 0x40],);
+        assert_eq!(parse_type_bitmaps(&encoded).unwrap(), vec![16, 33, 257]);
+        assert!(parse_type_bitmaps(&[0, 5, 0]).is_err());
     }
 }
@@ -0,0 +1,188 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// What went wrong in the responder
+///
+/// Functions returning `io::Result` wrap it in their `io::Error`, where
+/// `get_ref` and `downcast` get it back.
+#[derive(Debug)]
+pub enum Error {
+    /// The mDNS socket couldn't be bound, with what the OS said about it
+    Bind(io::Error),
+    /// The hostname can't be used in DNS names
+    HostnameNotUnicode,
+    /// A socket passed in isn't one answering mDNS queries can be done on
+    NotMdnsSocket(SocketAddr),
+    /// A received packet didn't fit the receive buffer, of the given size
+    BufferTooSmall(usize, usize),
+    /// `Config::interfaces` and `Config::excluded_interfaces` are both set
+    ConflictingInterfaceFilters,
+    /// A configured multicast group isn't a multicast address
+    NotMulticast(IpAddr),
+    /// `Config::advertised_payload_size` is less than 512 bytes, or more
+    /// than a packet we can receive
+    PayloadSizeOutOfRange(u16),
+    /// `Config::goodbye_count` is 0
+    NoGoodbyes,
+    /// A `RawRecord` of the given type has rdata of the given length, over
+    /// the 1024 bytes allowed
+    RdataTooLong(u16, usize),
+    /// The soft limit of `Config::rate_limit`, then its hard limit, the
+    /// soft one being higher
+    SoftLimitAboveHard(u32, u32),
+    /// A service name or type, or the hostname, has an empty label or one
+    /// longer than 62 bytes, or is longer than 253 bytes in all
+    InvalidName(String),
+    /// A TXT entry is longer than 255 bytes, by its length
+    TxtEntryTooLong(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Bind(ref err) => write!(
+                f,
+                "Couldn't bind to port 5353, check no other responder holds it exclusively: {}",
+                err
+            ),
+            Error::HostnameNotUnicode => write!(f, "Hostname is not valid unicode"),
+            Error::NotMdnsSocket(addr) => write!(
+                f,
+                "Socket bound to {}, but needs to be bound to port 5353",
+                addr
+            ),
+            Error::BufferTooSmall(bytes, size) => write!(
+                f,
+                "Incoming packet of {} bytes doesn't fit a buffer of {}",
+                bytes, size
+            ),
+            Error::ConflictingInterfaceFilters => write!(
+                f,
+                "Config sets both interfaces and excluded_interfaces, set at most one"
+            ),
+            Error::NotMulticast(ip) => write!(f, "{} is not a multicast address", ip),
+            Error::PayloadSizeOutOfRange(size) => write!(
+                f,
+                "Advertised payload size of {} bytes is outside 512 to 4095",
+                size
+            ),
+            Error::NoGoodbyes => write!(f, "Goodbye count is 0, goodbyes are sent at least once"),
+            Error::RdataTooLong(rtype, len) => write!(
+                f,
+                "Rdata of record type {} is {} bytes long, at most 1024 are allowed",
+                rtype, len
+            ),
+            Error::SoftLimitAboveHard(soft, hard) => write!(
+                f,
+                "Soft rate limit of {} is above the hard limit of {}",
+                soft, hard
+            ),
+            Error::InvalidName(ref name) => write!(
+                f,
+                "{:?} can't be used as a DNS name, it needs labels of 1 to 62 bytes \
+                 and at most 253 bytes in all",
+                name
+            ),
+            Error::TxtEntryTooLong(len) => write!(
+                f,
+                "TXT entry of {} bytes is longer than the 255 allowed",
+                len
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Bind(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::Bind(ref err) => err.kind(),
+            Error::HostnameNotUnicode => io::ErrorKind::InvalidData,
+            Error::NotMdnsSocket(_) => io::ErrorKind::InvalidInput,
+            Error::BufferTooSmall(..) => io::ErrorKind::Other,
+            Error::ConflictingInterfaceFilters
+            | Error::NotMulticast(_)
+            | Error::PayloadSizeOutOfRange(_)
+            | Error::NoGoodbyes
+            | Error::SoftLimitAboveHard(..)
+            | Error::RdataTooLong(..)
+            | Error::InvalidName(_)
+            | Error::TxtEntryTooLong(_) => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use std::error::Error as _;
+    use std::io;
+
+    #[test]
+    fn bind_errors_chain_the_os_error() {
+        let os = io::Error::from(io::ErrorKind::AddrInUse);
+        let message = os.to_string();
+        let err = Error::Bind(os);
+        assert!(err.to_string().ends_with(&message));
+        let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        match err.get_ref().unwrap().downcast_ref::<Error>() {
+            Some(Error::Bind(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_errors_have_no_source() {
+        let errors = vec![
+            (Error::HostnameNotUnicode, io::ErrorKind::InvalidData),
+            (
+                Error::NotMdnsSocket("0.0.0.0:1234".parse().unwrap()),
+                io::ErrorKind::InvalidInput,
+            ),
+            (Error::BufferTooSmall(4096, 4096), io::ErrorKind::Other),
+            (
+                Error::ConflictingInterfaceFilters,
+                io::ErrorKind::InvalidInput,
+            ),
+            (
+                Error::PayloadSizeOutOfRange(9000),
+                io::ErrorKind::InvalidInput,
+            ),
+            (Error::NoGoodbyes, io::ErrorKind::InvalidInput),
+            (Error::SoftLimitAboveHard(3, 2), io::ErrorKind::InvalidInput),
+            (Error::RdataTooLong(256, 2000), io::ErrorKind::InvalidInput),
+            (
+                Error::InvalidName("a..local".into()),
+                io::ErrorKind::InvalidInput,
+            ),
+            (Error::TxtEntryTooLong(300), io::ErrorKind::InvalidInput),
+        ];
+        for (err, kind) in errors {
+            assert!(err.source().is_none());
+            let message = err.to_string();
+            let err = io::Error::from(err);
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.to_string(), message);
+        }
+        let err = Error::NotMdnsSocket("0.0.0.0:1234".parse().unwrap());
+        assert_eq!(
+            err.to_string(),
+            "Socket bound to 0.0.0.0:1234, but needs to be bound to port 5353"
+        );
+    }
+}
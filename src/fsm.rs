@@ -1,281 +1,6169 @@
-use crate::dns_parser::{self, Name, QueryClass, QueryType, RRData};
-use get_if_addrs::get_if_addrs;
+use crate::dns_parser::{self, Name, QueryClass, QueryType, RRData, Type};
+use get_if_addrs::{IfAddr, Interface};
 use log::{debug, error, trace, warn};
-use quick_error::quick_error;
-use std::collections::VecDeque;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::ErrorKind::WouldBlock;
 use std::marker::PhantomData;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{
     future::Future,
     pin::Pin,
+    slice,
     task::{Context, Poll},
 };
 
-use tokio::{net::UdpSocket, stream::Stream, sync::mpsc};
+use tokio::{
+    net::UdpSocket,
+    stream::Stream,
+    sync::{mpsc, oneshot},
+};
 
-use super::{DEFAULT_TTL, MDNS_PORT};
+use super::{MDNS_PORT, OTHER_TTL};
 use crate::address_family::AddressFamily;
-use crate::services::{ServiceData, Services};
+use crate::clock::{Clock, Sleep, SystemClock};
+use crate::config::{
+    Config, Conflict, ConflictResolution, NoAddresses, ObservedQuestion, PtrInstances, PtrResponse,
+    Schedule, Section, Subnet,
+};
+use crate::error::Error;
+use crate::interfaces::{Interfaces, SystemInterfaces};
+use crate::services::{self, ServiceData, ServiceRecord, Services};
+#[cfg(feature = "test-util")]
+use crate::socket::DetachedSocket;
+use crate::socket::Socket;
+use crate::stats::{SendOutcome, SharedStats};
 
 pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
 
-#[derive(Clone, Debug)]
+/// Largest announcement services get coalesced into, leaving room for the IP
+/// and UDP headers within a typical Ethernet MTU
+const MAX_ANNOUNCEMENT_SIZE: usize = 1440;
+
+/// Probes sent for a name before announcing it, and the time between them
+/// (RFC 6762 section 8.1)
+const PROBES: u8 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a host losing a simultaneous probe tiebreak waits before
+/// probing again (RFC 6762 section 8.2)
+const PROBE_DEFERRAL: Duration = Duration::from_secs(1);
+
+/// Most responses kept for `Config::prebuilt_responses`
+const MAX_PREBUILT_RESPONSES: usize = 256;
+
+/// Most queriers counted separately against `Config::rate_limit` within a
+/// window, any more sharing one count
+const MAX_QUERIERS: usize = 1024;
+
+/// TTL of addresses answered with while interfaces can't be listed, see
+/// `Config::stale_address_window`
+const STALE_ADDRESS_TTL: u32 = 10;
+
+/// Binds the socket, once at startup and again on each retry of a deferred
+/// bind
+pub type Binder = Box<dyn Fn() -> io::Result<Box<dyn Socket>> + Send>;
+
+/// Binds the socket of one interface, see `Config::socket_per_interface`
+pub type InterfaceBinder = Box<dyn Fn(&Interface) -> io::Result<Box<dyn Socket>> + Send>;
+
+#[derive(Debug)]
 pub enum Command {
+    /// Announces a service, or says goodbye for it with a `ttl` of
+    /// `Some(0)`
     SendUnsolicited {
         svc: ServiceData,
-        ttl: u32,
+        /// Caps the configured TTLs, which `None` leaves be
+        ttl: Option<u32>,
+        include_ip: bool,
+    },
+    /// Announces several services together, in as few packets as they fit
+    SendUnsolicitedBatch {
+        svcs: Vec<ServiceData>,
+        ttl: Option<u32>,
         include_ip: bool,
     },
+    /// Announces a service that isn't registered, once, and says goodbye
+    /// for it after `goodbye_after`
+    AnnounceOnce {
+        svc: ServiceData,
+        goodbye_after: Option<Duration>,
+    },
+    /// Renames the host from `previous` to `name`, which every `FSM` is told
+    /// so that each can say goodbye for its own addresses
+    SetHostname {
+        name: Name<'static>,
+        previous: Name<'static>,
+    },
+    /// Says goodbye for the PTR record of a service no longer discoverable
+    Hide(ServiceData),
+    /// The schedule of the service named `name` changed from `previous`
+    Reschedule {
+        name: Name<'static>,
+        previous: Option<Schedule>,
+    },
+    /// Stops or resumes sending, see `Responder::set_observe_only`
+    SetObserveOnly(bool),
     Shutdown,
+    /// Says goodbye for every registered service, then shuts down once the
+    /// send queue is empty and signals that it has
+    Drain(oneshot::Sender<()>),
 }
 
-quick_error! {
-#[derive(Debug)]
-pub enum Error {
-    BufferTooSmall(bytes: usize, size: usize) {
-        description("buffer isn't big enough")
-        display("Incoming packet {}>{}", bytes, size)
+/// A response waiting in the send queue
+#[derive(Clone)]
+pub struct Datagram {
+    pub packet: Vec<u8>,
+    pub addr: SocketAddr,
+    /// Services this is an unsolicited announcement of
+    pub announces: Vec<Name<'static>>,
+    /// Whether this answers a query, the only datagrams
+    /// `Config::dedup_outgoing` applies to
+    pub response: bool,
+    /// Our address to send it from, see `Config::unicast_source_from_subnet`
+    pub source: Option<IpAddr>,
+    /// The interface whose own socket to send it through, see
+    /// `Config::socket_per_interface`
+    pub via: Option<String>,
+    /// Index of the interface to send it out of: the one its query arrived
+    /// on, see `Config::unicast_via_arrival_interface`, or else that of
+    /// `source`
+    pub out_of: Option<u32>,
+}
+
+/// Per-packet facts that shape how its questions are answered
+#[derive(Clone, Copy)]
+struct AnswerContext {
+    /// Only add the records that were asked for, leaving out the SRV, TXT
+    /// and address records normally sent along with them
+    essential_only: bool,
+    /// Upper bound for the TTL of every record in the response, `None`
+    /// leaving them as configured
+    max_ttl: Option<u32>,
+    /// Where the query came from
+    from: SocketAddr,
+    audience: Audience,
+}
+
+/// A response being built, with the records sent along with the answers
+/// kept apart until the additional section can be written
+struct Response {
+    answers: AnswerBuilder,
+    /// Records for the additional section, see `Config::placement`
+    additional: AnswerBuilder,
+}
+
+impl Response {
+    fn new(answers: AnswerBuilder) -> Response {
+        let additional =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        Response {
+            answers,
+            additional,
+        }
+    }
+
+    /// Adds records answering the question
+    fn answer<F: FnOnce(AnswerBuilder) -> AnswerBuilder>(mut self, add: F) -> Response {
+        self.answers = add(self.answers);
+        self
+    }
+
+    /// Adds records sent along with the answers to `section`
+    fn add<F>(mut self, section: Section, add: F) -> Response
+    where
+        F: FnOnce(AnswerBuilder) -> AnswerBuilder,
+    {
+        match section {
+            Section::Answer => self.answers = add(self.answers),
+            Section::Additional => self.additional = add(self.additional),
+        }
+        self
+    }
+
+    fn has_answers(&self) -> bool {
+        self.answers.has_answers()
+    }
+
+    /// Writes the response, ending it with an OPT record advertising
+    /// `payload_size` if there is one
+    fn build(self, payload_size: Option<u16>) -> Vec<u8> {
+        let mut builder = self.answers.add_additionals_from(&self.additional);
+        if let Some(size) = payload_size {
+            builder = builder.add_opt(size);
+        }
+        builder.set_max_size(None);
+        builder.build().unwrap_or_else(|x| x)
+    }
+}
+
+/// Whether `qname` is `hostname`, in full or as its bare first label as
+/// unicast resolvers may ask, e.g. `myhost` for `myhost.local`
+fn names_host(qname: &Name, hostname: &Name) -> bool {
+    if qname == hostname {
+        return true;
+    }
+    let (qname, hostname) = (qname.to_string(), hostname.to_string());
+    !qname.is_empty()
+        && !qname.contains('.')
+        && hostname.strip_suffix(".local") == Some(qname.as_str())
+}
+
+/// Whether `name` is the one all service types are listed under
+fn is_type_enumeration(name: &Name) -> bool {
+    name.to_string()
+        .eq_ignore_ascii_case("_services._dns-sd._udp.local")
+}
+
+/// Records a querier listed as already known, in the answer or additional
+/// section of its query, which are left out of the response unless their
+/// TTL has less than half left (RFC 6762 section 7.1)
+#[derive(Default)]
+struct KnownAnswers(Vec<(Name<'static>, Type, Vec<u8>, u32)>);
+
+impl KnownAnswers {
+    fn from_packet(packet: &dns_parser::Packet) -> KnownAnswers {
+        let records = packet.answers.iter().chain(&packet.additional);
+        KnownAnswers(
+            records
+                .map(|rr| {
+                    let name = Name::from_str(rr.name.to_string()).unwrap();
+                    (name, rr.data.typ(), rdata_bytes(&rr.data), rr.ttl)
+                })
+                .collect(),
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn contains(&self, name: &Name, data: &RRData, ttl: u32) -> bool {
+        let typ = data.typ();
+        self.0
+            .iter()
+            .any(|(known_name, known_typ, rdata, known_ttl)| {
+                *known_typ == typ
+                    && *known_ttl >= ttl / 2
+                    && known_name == name
+                    && *rdata == rdata_bytes(data)
+            })
+    }
+}
+
+/// The responses to a query, either of which may be left out for lack of
+/// answers
+#[derive(Clone)]
+struct Responses {
+    multicast: Option<Vec<u8>>,
+    unicast: Option<Vec<u8>>,
+    /// Names the multicast response answers for, see `note_multicast`
+    multicast_names: Vec<Name<'static>>,
+}
+
+/// Whether a query is a legacy one, and its questions in the order asked
+type QueryKey = (bool, Vec<(String, QueryType, QueryClass, bool)>);
+
+/// Responses kept for `Config::prebuilt_responses`, along with what they
+/// were built from
+#[derive(Default)]
+struct Prebuilt {
+    /// `ServicesInner::generation` of the services
+    generation: u64,
+    addresses: Vec<IpAddr>,
+    responses: HashMap<QueryKey, Responses>,
+}
+
+/// Who gets to see a response, which decides the services it may contain
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Audience {
+    /// Multicast to everyone, so only services without subnet restrictions
+    Everyone,
+    /// Unicast to the querier, with every service it is permitted to see
+    Querier,
+    /// Unicast to the querier, with only the restricted services it is
+    /// permitted to see, which were left out of a multicast response
+    Restricted,
+}
+
+/// Multicast datagrams that may be sent right away, refilled at
+/// `Config::max_multicast_rate`
+struct TokenBucket {
+    rate: u32,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: f64::from(rate),
+            refilled: now,
+        }
+    }
+
+    /// Whether a token is available at `now`, or how long until one is
+    fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let rate = f64::from(self.rate);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// Uses up a token, after `check` found one
+    fn take(&mut self) {
+        self.tokens -= 1.0;
     }
 }
+
+/// Queries seen from one source address within the current rate limit
+/// window
+struct QuerierState {
+    window_start: Instant,
+    count: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Throttle {
+    None,
+    Trim,
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Timer {
+    /// Retry binding the socket
+    Bind,
+    RefreshInterfaces,
+    /// Re-announce the service with this name, if it is still registered
+    RefreshService(Name<'static>),
+    /// Announce every service, see `Config::advertise_interval`
+    Advertise,
+    /// Check the previous self-query came back and send another one
+    HealthCheck,
+    /// First announcement of the services with these names, deferred by
+    /// `Config::announce_jitter`
+    Announce(Vec<Name<'static>>),
+    /// Probe for these names again, having sent this many probes so far,
+    /// or announce them once all went out
+    Probe(Vec<Name<'static>>, u8),
+    /// Retry joining the multicast group, see `Config::join_retry_interval`
+    JoinMulticast,
+    /// Refresh the memberships of the multicast group, see
+    /// `Config::rejoin_interval`
+    Rejoin,
+    /// Send what `Config::max_multicast_rate` held back
+    SendQueue,
+    /// The hours of the service with this name start or end now
+    Schedule(Name<'static>),
+    /// Say goodbye for the service announced once under this key of
+    /// `FSM::goodbyes`
+    Goodbye(u64),
+    /// Send this goodbye for these names again, then this many more times,
+    /// see `Config::goodbye_count`
+    RepeatGoodbye(Vec<Name<'static>>, Vec<u8>, u8),
 }
 
 pub struct FSM<AF: AddressFamily> {
-    socket: UdpSocket,
+    /// `None` until bound, if binding was deferred
+    socket: Option<Box<dyn Socket>>,
+    binder: Binder,
     services: Services,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    interfaces: Box<dyn Interfaces>,
     commands: mpsc::UnboundedReceiver<Command>,
-    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
+    stats: SharedStats,
+    outgoing: VecDeque<Datagram>,
+    /// Queries counted against `Config::rate_limit` by source address, or
+    /// under `None` for those past `MAX_QUERIERS`
+    queriers: HashMap<Option<IpAddr>, QuerierState>,
+    /// Names of the interfaces the multicast group has been joined on
+    joined: HashSet<String>,
+    /// Binds the sockets of `Config::socket_per_interface`, if supported
+    iface_binder: Option<InterfaceBinder>,
+    /// The socket of each joined interface, with `iface_binder`
+    iface_sockets: Vec<(String, Box<dyn Socket>)>,
+    /// Name of the interface multicast datagrams are sent out of
+    egress: Option<String>,
+    timers: Vec<(Instant, Timer)>,
+    wakeup: Option<(Instant, Sleep)>,
+    /// Set once asked to drain, signalled when the queue has been sent
+    draining: Option<oneshot::Sender<()>>,
+    /// Drops the send queue rather than sending it, see
+    /// `Responder::set_observe_only`
+    observe_only: bool,
+    /// Where the next PTR answer capped by `Config::ptr_instances` starts
+    ptr_rotation: Cell<usize>,
+    /// The addresses listed last and when, see
+    /// `Config::stale_address_window`
+    last_addresses: RefCell<Option<(Instant, Vec<IpAddr>)>>,
+    /// ID of the self-query sent by the last health check, until it is
+    /// received back
+    health_probe: Option<u16>,
+    /// When records of each of our names were last multicast
+    multicast_at: HashMap<Name<'static>, Instant>,
+    multicast_budget: Option<TokenBucket>,
+    prebuilt: Prebuilt,
+    /// Our addresses as of the last interface refresh, and whether they are
+    /// stale, which `Config::prebuilt_responses` are checked against
+    refreshed_addresses: Option<(Vec<IpAddr>, bool)>,
+    /// Services announced by `Command::AnnounceOnce` which are due a
+    /// goodbye, under the key of their `Timer::Goodbye`
+    goodbyes: HashMap<u64, ServiceData>,
+    next_goodbye: u64,
+    /// Names of the services being probed for, see
+    /// `Config::probe_before_announcing`
+    probing: HashSet<Name<'static>>,
+    /// Picks the delays of first announcements, and the order of SRV records
+    /// of instances sharing a name
+    rng: RefCell<StdRng>,
     _af: PhantomData<AF>,
 }
 
 impl<AF: AddressFamily> FSM<AF> {
     // Will panic if called from outside the context of a runtime
-    pub fn new(services: &Services) -> io::Result<(FSM<AF>, mpsc::UnboundedSender<Command>)> {
-        let std_socket = AF::bind()?;
-        let socket = UdpSocket::from_std(std_socket)?;
+    pub fn new(
+        services: &Services,
+        config: &Arc<Config>,
+        stats: &SharedStats,
+    ) -> io::Result<(FSM<AF>, mpsc::UnboundedSender<Command>)> {
+        let bind_config = config.clone();
+        let binder: Binder = Box::new(move || {
+            let std_socket = AF::bind(&bind_config).map_err(Error::Bind)?;
+            let socket = UdpSocket::from_std(std_socket)?;
+            Ok(Box::new(socket))
+        });
+
+        let iface_binder = if config.socket_per_interface && cfg!(target_os = "linux") {
+            let bind_config = config.clone();
+            let binder: InterfaceBinder = Box::new(move |iface: &Interface| {
+                let std_socket = AF::bind_interface(&bind_config, &iface.name)?;
+                let socket = UdpSocket::from_std(std_socket)?;
+                Ok(Box::new(socket))
+            });
+            Some(binder)
+        } else {
+            None
+        };
+
+        Self::with_binder(
+            binder,
+            iface_binder,
+            services,
+            config,
+            stats,
+            Arc::new(SystemClock),
+            Box::new(SystemInterfaces),
+        )
+    }
+
+    /// Answers over `socket`, which the application bound to port 5353 and
+    /// joined to the multicast group itself, e.g. when it got the socket
+    /// through systemd's socket activation
+    ///
+    /// The application stays in charge of the socket's group memberships.
+    // Will panic if called from outside the context of a runtime
+    pub fn from_socket(
+        socket: std::net::UdpSocket,
+        services: &Services,
+        config: &Arc<Config>,
+        stats: &SharedStats,
+    ) -> io::Result<(FSM<AF>, mpsc::UnboundedSender<Command>)> {
+        let addr = socket.local_addr()?;
+        if addr.port() != MDNS_PORT || addr.is_ipv6() != AF::v6() {
+            return Err(Error::NotMdnsSocket(addr).into());
+        }
+        socket.set_nonblocking(true)?;
+        #[cfg(target_os = "linux")]
+        {
+            if config.unicast_via_arrival_interface {
+                crate::socket::enable_pktinfo(&socket, AF::v6())?;
+            }
+        }
+        let socket = UdpSocket::from_std(socket)?;
+
+        Ok(Self::with_socket(
+            Box::new(socket),
+            services,
+            config,
+            stats,
+            Arc::new(SystemClock),
+            Box::new(SystemInterfaces),
+        ))
+    }
+
+    /// Builds the state machine around a socket that is ready for use
+    pub fn with_socket(
+        socket: Box<dyn Socket>,
+        services: &Services,
+        config: &Arc<Config>,
+        stats: &SharedStats,
+        clock: Arc<dyn Clock>,
+        interfaces: Box<dyn Interfaces>,
+    ) -> (FSM<AF>, mpsc::UnboundedSender<Command>) {
+        let binder: Binder = Box::new(|| {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "provided socket can't be bound again",
+            ))
+        });
+        let (mut fsm, tx) = Self::unbound(binder, services, config, stats, clock, interfaces);
+        fsm.socket = Some(socket);
+        fsm.start_timers();
+        (fsm, tx)
+    }
+
+    /// Builds the state machine, binding its socket through `binder`, and
+    /// those of `Config::socket_per_interface` through `iface_binder`
+    pub fn with_binder(
+        binder: Binder,
+        iface_binder: Option<InterfaceBinder>,
+        services: &Services,
+        config: &Arc<Config>,
+        stats: &SharedStats,
+        clock: Arc<dyn Clock>,
+        interfaces: Box<dyn Interfaces>,
+    ) -> io::Result<(FSM<AF>, mpsc::UnboundedSender<Command>)> {
+        let (mut fsm, tx) = Self::unbound(binder, services, config, stats, clock, interfaces);
+        fsm.iface_binder = iface_binder;
+
+        match fsm.bind() {
+            Ok(()) => (),
+            Err(err) => match fsm.config.bind_retry_interval {
+                Some(interval) => {
+                    warn!("could not bind socket, will retry: {}", err);
+                    fsm.schedule(interval, Timer::Bind);
+                }
+                None => return Err(err),
+            },
+        }
+
+        Ok((fsm, tx))
+    }
+
+    /// Builds a state machine without a socket, which only answers the
+    /// packets passed to `inject_packet`
+    #[cfg(feature = "test-util")]
+    pub fn detached(services: &Services, config: &Arc<Config>, stats: &SharedStats) -> FSM<AF> {
+        let (fsm, _) = Self::with_socket(
+            Box::new(DetachedSocket),
+            services,
+            config,
+            stats,
+            Arc::new(SystemClock),
+            Box::new(SystemInterfaces),
+        );
+        fsm
+    }
+
+    /// Handles `packet` as if it came from `from`, returning the datagrams
+    /// sent in response along with their destinations
+    #[cfg(feature = "test-util")]
+    pub fn inject_packet(&mut self, packet: &[u8], from: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.handle_packet(packet, from, None, None);
+        self.outgoing
+            .drain(..)
+            .map(|d| (d.packet, d.addr))
+            .collect()
+    }
 
+    fn unbound(
+        binder: Binder,
+        services: &Services,
+        config: &Arc<Config>,
+        stats: &SharedStats,
+        clock: Arc<dyn Clock>,
+        interfaces: Box<dyn Interfaces>,
+    ) -> (FSM<AF>, mpsc::UnboundedSender<Command>) {
         let (tx, rx) = mpsc::unbounded_channel();
 
+        let multicast_budget = config
+            .max_multicast_rate
+            .filter(|&rate| rate > 0)
+            .map(|rate| TokenBucket::new(rate, clock.now()));
         let fsm = FSM {
-            socket: socket,
+            socket: None,
+            binder,
             services: services.clone(),
+            config: config.clone(),
+            clock,
+            interfaces,
             commands: rx,
+            stats: stats.clone(),
             outgoing: VecDeque::new(),
+            queriers: HashMap::new(),
+            joined: HashSet::new(),
+            iface_binder: None,
+            iface_sockets: Vec::new(),
+            egress: None,
+            timers: Vec::new(),
+            wakeup: None,
+            draining: None,
+            observe_only: false,
+            ptr_rotation: Cell::new(0),
+            last_addresses: RefCell::new(None),
+            health_probe: None,
+            multicast_at: HashMap::new(),
+            multicast_budget,
+            prebuilt: Prebuilt::default(),
+            refreshed_addresses: None,
+            goodbyes: HashMap::new(),
+            next_goodbye: 0,
+            probing: HashSet::new(),
+            rng: RefCell::new(StdRng::from_entropy()),
             _af: PhantomData,
         };
-
-        Ok((fsm, tx))
+        (fsm, tx)
     }
 
-    fn recv_packets(&mut self, cx: &mut Context) -> io::Result<()> {
-        let mut buf = [0u8; 4096];
-        loop {
-            let (bytes, addr) = match self.socket.poll_recv_from(cx, &mut buf) {
-                Poll::Ready(Ok((bytes, addr))) => (bytes, addr),
-                Poll::Ready(Err(err)) => return Err(err),
-                Poll::Pending => break,
-            };
-            // Is moot for certain platforms (Windows will throw a <10040> error from poll_recv)
-            if bytes >= buf.len() {
-                warn!("buffer too small for packet from {:?}", addr);
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    Error::BufferTooSmall(bytes, buf.len()),
-                ));
+    /// Binds the socket and joins the multicast group on it
+    fn bind(&mut self) -> io::Result<()> {
+        self.socket = Some((self.binder)()?);
+
+        if let Err(err) = self.join() {
+            // Unicast queries still get answered, e.g. on hosts without
+            // multicast routing for IPv6. Interfaces are tried again on the
+            // next refresh.
+            warn!(
+                "could not join multicast group, only answering unicast: {}",
+                err
+            );
+            self.stats.set_healthy(AF::v6(), false);
+            if let Some(interval) = self.config.join_retry_interval {
+                self.schedule(interval, Timer::JoinMulticast);
             }
-            self.handle_packet(&buf[..bytes], addr);
         }
 
+        self.refresh_addresses();
+        self.schedule(
+            self.config.interface_refresh_interval,
+            Timer::RefreshInterfaces,
+        );
+        if let Some(interval) = self.config.rejoin_interval {
+            self.schedule(interval, Timer::Rejoin);
+        }
+        self.start_timers();
         Ok(())
     }
 
-    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr) {
-        trace!("received packet from {:?}", addr);
+    /// Lists our addresses for `Config::prebuilt_responses` to be checked
+    /// against, rather than listing the interfaces on every query
+    fn refresh_addresses(&mut self) {
+        if self.config.prebuilt_responses {
+            self.refreshed_addresses = Some(self.listed_addresses());
+        }
+    }
 
-        let packet = match dns_parser::Packet::parse(buffer) {
-            Ok(packet) => packet,
-            Err(error) => {
-                warn!("couldn't parse packet from {:?}: {}", addr, error);
-                return;
+    /// Joins the multicast group on the eligible interfaces, or failing
+    /// that through the default one
+    fn join(&mut self) -> io::Result<()> {
+        match self.join_multicast() {
+            Ok(()) if !self.joined.is_empty() => Ok(()),
+            Ok(()) if self.config.interfaces.is_some() => {
+                warn!("none of the configured interfaces are usable yet");
+                Ok(())
+            }
+            Ok(()) => AF::join_multicast(self.socket(), &self.config),
+            Err(err) => {
+                error!("could not get list of interfaces: {}", err);
+                AF::join_multicast(self.socket(), &self.config)
             }
-        };
-
-        if !packet.header.query {
-            trace!("received packet from {:?} with no query", addr);
-            return;
         }
+    }
 
-        if packet.header.truncated {
-            warn!("dropping truncated packet from {:?}", addr);
-            return;
+    /// Schedules the periodic work that needs a socket
+    fn start_timers(&mut self) {
+        if let Some(interval) = self.config.advertise_interval {
+            self.schedule(interval, Timer::Advertise);
         }
+        if let Some(interval) = self.config.health_check_interval {
+            self.schedule(interval, Timer::HealthCheck);
+        }
+    }
 
-        let mut unicast_builder = dns_parser::Builder::new_response(packet.header.id, false, true)
-            .move_to::<dns_parser::Answers>();
-        let mut multicast_builder =
-            dns_parser::Builder::new_response(packet.header.id, false, true)
-                .move_to::<dns_parser::Answers>();
-        unicast_builder.set_max_size(None);
-        multicast_builder.set_max_size(None);
+    /// Where multicast goes, the group of this FSM's address family
+    fn group(&self) -> SocketAddr {
+        SocketAddr::new(AF::group(&self.config), MDNS_PORT)
+    }
 
-        for question in packet.questions {
-            debug!(
-                "received question: {:?} {}",
-                question.qclass, question.qname
-            );
+    fn socket(&self) -> &dyn Socket {
+        &**self.socket.as_ref().expect("socket not bound")
+    }
 
-            if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
-                if question.qu {
-                    unicast_builder = self.handle_question(&question, unicast_builder);
-                } else {
-                    multicast_builder = self.handle_question(&question, multicast_builder);
+    /// Non-loopback interfaces permitted by the config
+    fn eligible_interfaces(&self) -> io::Result<Vec<Interface>> {
+        let interfaces = self.interfaces.list()?;
+        Ok(interfaces
+            .into_iter()
+            .filter(|iface| !iface.is_loopback() && self.config.allows_interface(&iface.name))
+            .collect())
+    }
+
+    /// Joins the multicast group on every eligible interface that hasn't
+    /// been joined yet
+    ///
+    /// Interfaces which disappeared are forgotten, so the group is joined
+    /// again should they come back.
+    fn join_multicast(&mut self) -> io::Result<()> {
+        let interfaces = self.eligible_interfaces()?;
+        let interfaces: Vec<_> = interfaces.iter().filter(|i| AF::has_addr(i)).collect();
+
+        self.joined
+            .retain(|name| interfaces.iter().any(|iface| iface.name == *name));
+        self.iface_sockets
+            .retain(|(name, _)| interfaces.iter().any(|iface| iface.name == *name));
+
+        for iface in interfaces.iter().copied() {
+            if self.joined.contains(&iface.name) {
+                continue;
+            }
+            let joined = if self.iface_binder.is_some() {
+                self.bind_interface(iface)
+            } else {
+                AF::join_multicast_on(self.socket(), iface, &*self.interfaces, &self.config)
+            };
+            match joined {
+                Ok(()) => debug!("joined multicast group on {}", iface.name),
+                // Already a member, e.g. through the default interface
+                Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => (),
+                Err(err) => {
+                    warn!("failed to join multicast group on {}: {}", iface.name, err);
+                    continue;
                 }
             }
+            self.joined.insert(iface.name.clone());
         }
 
-        if !multicast_builder.is_empty() {
-            let response = multicast_builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+        if self.config.interfaces.is_some() && self.iface_binder.is_none() {
+            self.select_egress(&interfaces);
         }
 
-        if !unicast_builder.is_empty() {
-            let response = unicast_builder.build().unwrap_or_else(|x| x);
-            self.outgoing.push_back((response, addr));
-        }
+        Ok(())
     }
 
-    fn handle_question(
-        &self,
-        question: &dns_parser::Question,
-        mut builder: AnswerBuilder,
-    ) -> AnswerBuilder {
-        let services = self.services.read().unwrap();
+    /// Leaves the multicast group and joins it again wherever it was
+    /// joined, which makes the OS send fresh membership reports
+    fn rejoin(&mut self) {
+        let interfaces = match self.eligible_interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(err) => {
+                error!("could not get list of interfaces: {}", err);
+                return;
+            }
+        };
+        let joined: Vec<_> = interfaces
+            .iter()
+            .filter(|iface| AF::has_addr(iface) && self.joined.contains(&iface.name))
+            .collect();
 
-        match question.qtype {
-            QueryType::A | QueryType::AAAA | QueryType::All
-                if question.qname == *services.get_hostname() =>
-            {
-                builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
+        // Joined through the default interface instead, if at all
+        if joined.is_empty() && self.config.interfaces.is_none() {
+            let socket = self.socket();
+            let rejoined = AF::leave_multicast(socket, &self.config)
+                .and_then(|()| AF::join_multicast(socket, &self.config));
+            if let Err(err) = rejoined {
+                warn!("failed to rejoin multicast group: {}", err);
             }
-            QueryType::PTR => {
-                for svc in services.find_by_type(&question.qname) {
-                    builder = svc.add_ptr_rr(builder, DEFAULT_TTL);
-                    builder = svc.add_srv_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                }
+            return;
+        }
+
+        for iface in joined {
+            let socket = self
+                .iface_sockets
+                .iter()
+                .find(|(name, _)| *name == iface.name)
+                .map_or_else(|| self.socket(), |(_, socket)| &**socket);
+            let rejoined = AF::leave_multicast_on(socket, iface, &*self.interfaces, &self.config)
+                .and_then(|()| {
+                    AF::join_multicast_on(socket, iface, &*self.interfaces, &self.config)
+                });
+            match rejoined {
+                Ok(()) => trace!("rejoined multicast group on {}", iface.name),
+                Err(err) => warn!(
+                    "failed to rejoin multicast group on {}: {}",
+                    iface.name, err
+                ),
             }
-            QueryType::SRV => {
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_srv_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                }
+        }
+    }
+
+    /// Binds a socket of its own for `iface`, which joins the group there and
+    /// multicasts out of it alone
+    fn bind_interface(&mut self, iface: &Interface) -> io::Result<()> {
+        let binder = self.iface_binder.as_ref().expect("no interface binder");
+        let socket = binder(iface)?;
+        match AF::join_multicast_on(&*socket, iface, &*self.interfaces, &self.config) {
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => (),
+            joined => joined?,
+        }
+        AF::set_multicast_if(&*socket, iface, &*self.interfaces)?;
+        self.iface_sockets.push((iface.name.clone(), socket));
+        Ok(())
+    }
+
+    /// With an interface filter configured, pins multicast sends to the first
+    /// allowed interface rather than leaving the choice to the routing table
+    fn select_egress(&mut self, interfaces: &[&Interface]) {
+        if let Some(ref name) = self.egress {
+            if self.joined.contains(name) {
+                return;
             }
-            QueryType::TXT => {
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
+        }
+
+        self.egress = None;
+        for iface in interfaces.iter().filter(|i| self.joined.contains(&i.name)) {
+            match AF::set_multicast_if(self.socket(), iface, &*self.interfaces) {
+                Ok(()) => {
+                    debug!("sending multicast through {}", iface.name);
+                    self.egress = Some(iface.name.clone());
+                    return;
                 }
+                Err(err) => warn!("failed to send multicast through {}: {}", iface.name, err),
             }
-            _ => (),
         }
-
-        builder
     }
 
-    fn add_ip_rr(&self, hostname: &Name, mut builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        let interfaces = match get_if_addrs() {
-            Ok(interfaces) => interfaces,
-            Err(err) => {
-                error!("could not get list of interfaces: {}", err);
-                return builder;
-            }
-        };
+    fn schedule(&mut self, after: Duration, timer: Timer) {
+        let at = self.clock.now() + after;
+        self.timers.push((at, timer));
+    }
 
-        for iface in interfaces {
-            if iface.is_loopback() {
-                continue;
+    fn fire_timers(&mut self) {
+        let now = self.clock.now();
+        let mut due = Vec::new();
+        self.timers.retain(|(at, timer)| {
+            if *at <= now {
+                due.push(timer.clone());
+                false
+            } else {
+                true
             }
+        });
 
-            trace!("found interface {:?}", iface);
-            match iface.ip() {
-                IpAddr::V4(ip) if !AF::v6() => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::A(ip))
+        for timer in due {
+            match timer {
+                Timer::Bind => match self.bind() {
+                    Ok(()) => {
+                        debug!("bound socket after retrying");
+                        self.advertise();
+                    }
+                    Err(err) => {
+                        debug!("could not bind socket yet: {}", err);
+                        let interval = self
+                            .config
+                            .bind_retry_interval
+                            .expect("bind retried without an interval");
+                        self.schedule(interval, Timer::Bind);
+                    }
+                },
+                Timer::JoinMulticast => match self.join() {
+                    Ok(()) => {
+                        debug!("joined multicast group after retrying");
+                        self.stats.set_healthy(AF::v6(), true);
+                        self.advertise();
+                    }
+                    Err(err) => {
+                        debug!("could not join multicast group yet: {}", err);
+                        let interval = self
+                            .config
+                            .join_retry_interval
+                            .expect("join retried without an interval");
+                        self.schedule(interval, Timer::JoinMulticast);
+                    }
+                },
+                Timer::Rejoin => {
+                    self.rejoin();
+                    let interval = self
+                        .config
+                        .rejoin_interval
+                        .expect("rejoining without an interval");
+                    self.schedule(interval, Timer::Rejoin);
+                }
+                Timer::RefreshInterfaces => {
+                    if let Err(err) = self.join_multicast() {
+                        error!("could not get list of interfaces: {}", err);
+                    }
+                    self.refresh_addresses();
+                    self.schedule(
+                        self.config.interface_refresh_interval,
+                        Timer::RefreshInterfaces,
+                    );
+                }
+                Timer::Advertise => {
+                    self.advertise();
+                    let interval = self
+                        .config
+                        .advertise_interval
+                        .expect("advertising without an interval");
+                    self.schedule(interval, Timer::Advertise);
+                }
+                Timer::HealthCheck => {
+                    self.check_health();
+                    let interval = self
+                        .config
+                        .health_check_interval
+                        .expect("health check without an interval");
+                    self.schedule(interval, Timer::HealthCheck);
                 }
-                IpAddr::V6(ip) if AF::v6() => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::AAAA(ip))
+                Timer::Announce(names) => {
+                    if !self.probe(names.clone()) {
+                        self.announce_names(&names);
+                    }
+                }
+                Timer::Probe(names, sent) => {
+                    // Those renamed or unregistered meanwhile are left out
+                    let names: Vec<_> = names
+                        .into_iter()
+                        .filter(|name| self.probing.contains(name))
+                        .collect();
+                    if names.is_empty() {
+                        continue;
+                    }
+                    if sent < PROBES {
+                        self.send_probe(&names);
+                        self.schedule(PROBE_INTERVAL, Timer::Probe(names, sent + 1));
+                    } else {
+                        for name in &names {
+                            self.probing.remove(name);
+                        }
+                        self.announce_names(&names);
+                    }
+                }
+                Timer::Schedule(name) => {
+                    // Gone, or no longer scheduled, if not found
+                    let svc = match services::read(&self.services).find_by_name(&name).cloned() {
+                        Some(svc) => svc,
+                        None => continue,
+                    };
+                    let schedule = match svc.schedule {
+                        Some(schedule) => schedule,
+                        None => continue,
+                    };
+                    let now = self.clock.wall();
+                    if schedule.contains(now) {
+                        self.send_unsolicited(&svc, None, true);
+                        self.schedule_refresh(&name);
+                    } else {
+                        self.send_unsolicited(&svc, Some(0), false);
+                    }
+                    self.schedule(schedule.until_change(now), Timer::Schedule(name));
+                }
+                Timer::Goodbye(key) => {
+                    if let Some(svc) = self.goodbyes.remove(&key) {
+                        self.send_unsolicited(&svc, Some(0), false);
+                    }
+                }
+                Timer::RepeatGoodbye(names, packet, left) => {
+                    self.enqueue_goodbye(names, packet, left)
+                }
+                // Only wakes the poll loop, which sends after firing timers
+                Timer::SendQueue => (),
+                Timer::RefreshService(name) => {
+                    let svc = services::read(&self.services).find_by_name(&name).cloned();
+                    if let Some(svc) = svc {
+                        self.send_unsolicited(&svc, None, true);
+                        self.schedule_refresh(&svc.name);
+                    }
                 }
-                _ => (),
             }
         }
+    }
 
-        builder
+    /// Judges the socket by whether the previous self-query was received
+    /// back through multicast loopback, then sends the next one
+    ///
+    /// The query is for a NULL record of our hostname, which nobody answers.
+    fn check_health(&mut self) {
+        if self.health_probe.is_some() {
+            warn!("self-query was not received, the socket may be unusable");
+            self.stats.set_healthy(AF::v6(), false);
+        }
+
+        let id = loop {
+            let id = self.rng.borrow_mut().gen::<u16>();
+            if id != 0 {
+                break id;
+            }
+        };
+        let packet = {
+            let services = services::read(&self.services);
+            dns_parser::Builder::new_query(id, false)
+                .add_question(services.get_hostname(), QueryType::NULL, QueryClass::IN)
+                .build()
+                .unwrap_or_else(|x| x)
+        };
+        self.health_probe = Some(id);
+        let addr = self.group();
+        self.enqueue(packet, addr, Vec::new());
     }
 
-    fn send_unsolicited(&mut self, svc: &ServiceData, ttl: u32, include_ip: bool) {
-        let mut builder =
-            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
-        builder.set_max_size(None);
+    /// Whether a packet is the self-query of the pending health check
+    fn is_health_probe(&self, packet: &dns_parser::Packet) -> bool {
+        self.health_probe == Some(packet.header.id)
+            && packet.questions.len() == 1
+            && packet.questions[0].qtype == QueryType::NULL
+    }
 
-        let services = self.services.read().unwrap();
+    /// Announces every registered service
+    fn advertise(&mut self) {
+        let svcs: Vec<_> = services::read(&self.services).all().cloned().collect();
+        self.send_unsolicited_batch(&svcs, None, true);
+        for svc in &svcs {
+            self.schedule_refresh(&svc.name);
+        }
+    }
 
-        builder = svc.add_ptr_rr(builder, ttl);
-        builder = svc.add_srv_rr(services.get_hostname(), builder, ttl);
-        builder = svc.add_txt_rr(builder, ttl);
-        if include_ip {
-            builder = self.add_ip_rr(services.get_hostname(), builder, ttl);
+    /// Announces the services named `names`, as they are now
+    fn announce_names(&mut self, names: &[Name<'static>]) {
+        let svcs: Vec<_> = {
+            let services = services::read(&self.services);
+            names
+                .iter()
+                .filter_map(|name| services.find_by_name(name).cloned())
+                .collect()
+        };
+        self.send_unsolicited_batch(&svcs, None, true);
+        for svc in &svcs {
+            self.schedule_refresh(&svc.name);
         }
+    }
 
-        if !builder.is_empty() {
-            let response = builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+    /// Probes for `names` before they are announced, if
+    /// `Config::probe_before_announcing` says to, returning whether it does
+    ///
+    /// The services aren't answered for until then.
+    fn probe(&mut self, names: Vec<Name<'static>>) -> bool {
+        if !self.config.probe_before_announcing {
+            return false;
         }
+        self.probing.extend(names.iter().cloned());
+        self.send_probe(&names);
+        self.schedule(PROBE_INTERVAL, Timer::Probe(names, 1));
+        true
     }
-}
 
-impl<AF: Unpin + AddressFamily> Future for FSM<AF> {
-    type Output = ();
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
-        let pinned = Pin::get_mut(self);
-        while let Poll::Ready(cmd) = Pin::new(&mut pinned.commands).poll_next(cx) {
-            match cmd {
-                Some(Command::Shutdown) => return Poll::Ready(()),
-                Some(Command::SendUnsolicited {
-                    svc,
-                    ttl,
-                    include_ip,
-                }) => {
-                    pinned.send_unsolicited(&svc, ttl, include_ip);
+    /// Asks whether anyone else has records of `names`, with the ones we
+    /// mean to announce in the authority section (RFC 6762 section 8.1)
+    fn send_probe(&mut self, names: &[Name<'static>]) {
+        let packet = {
+            let services = services::read(&self.services);
+            let hostname = services.get_hostname();
+            let mut builder = dns_parser::Builder::new_query(0, false);
+            for name in names {
+                builder = builder.add_question(name, QueryType::All, QueryClass::IN);
+            }
+            let mut builder = builder.move_to::<dns_parser::Nameservers>();
+            for svc in names
+                .iter()
+                .flat_map(|name| services.find_all_by_name(name))
+            {
+                if svc.has_srv() {
+                    let ttl = self.ttl_of(Type::SRV, None);
+                    let srv = svc.srv_rdata(hostname);
+                    builder = builder.add_nameserver(&svc.name, QueryClass::IN, ttl, &srv);
                 }
-                None => {
-                    warn!("responder disconnected without shutdown");
-                    return Poll::Ready(());
+                let ttl = self.ttl_of(Type::TXT, None);
+                let txt = svc.txt_rdata(false);
+                builder =
+                    builder.add_nameserver(&svc.name, QueryClass::IN, ttl, &RRData::TXT(&txt));
+            }
+            builder.build().unwrap_or_else(|x| x)
+        };
+        self.enqueue_unsolicited(packet, Vec::new());
+    }
+
+    /// Settles another host probing for one of the names we're probing for
+    /// at the same time (RFC 6762 section 8.2)
+    ///
+    /// Whoever has the lexicographically later records in the authority
+    /// section wins. Losing, we wait a second and probe for the name again,
+    /// by when the winner should have announced it and gets us to rename.
+    fn break_probe_ties(&mut self, packet: &dns_parser::Packet, from: SocketAddr) {
+        let lost: Vec<_> = {
+            let services = services::read(&self.services);
+            let hostname = services.get_hostname();
+            self.probing
+                .iter()
+                .filter(|name| packet.questions.iter().any(|q| q.qname == **name))
+                .filter(|name| {
+                    let mut theirs: Vec<_> = packet
+                        .nameservers
+                        .iter()
+                        .filter(|rr| rr.name == **name)
+                        .map(|rr| (rr.data.typ() as u16, rdata_bytes(&rr.data)))
+                        .collect();
+                    let mut ours = Vec::new();
+                    for svc in services.find_all_by_name(name) {
+                        if svc.has_srv() {
+                            ours.push((Type::SRV as u16, rdata_bytes(&svc.srv_rdata(hostname))));
+                        }
+                        let txt = svc.txt_rdata(false);
+                        ours.push((Type::TXT as u16, rdata_bytes(&RRData::TXT(&txt))));
+                    }
+                    theirs.sort();
+                    ours.sort();
+                    // Equal for our own probes, looped back to us
+                    ours < theirs
+                })
+                .cloned()
+                .collect()
+        };
+
+        for name in lost {
+            debug!("{:?} probes for {} too, and wins the tiebreak", from, name);
+            for (_, timer) in &mut self.timers {
+                if let Timer::Probe(ref mut names, _) = timer {
+                    names.retain(|probed| *probed != name);
                 }
             }
+            self.schedule(PROBE_DEFERRAL, Timer::Probe(vec![name], 0));
         }
+    }
 
-        match pinned.recv_packets(cx) {
-            Ok(_) => (),
-            Err(e) => error!("ResponderRecvPacket Error: {:?}", e),
+    /// Schedules the first announcement of newly registered services after
+    /// a random delay of up to `Config::announce_jitter`, so that hosts
+    /// powered up together don't all announce at once. `false` if there's
+    /// no jitter, and the services should be announced right away.
+    fn defer_announcement(&mut self, names: Vec<Name<'static>>) -> bool {
+        let max = self.config.announce_jitter.as_millis() as u64;
+        if max == 0 {
+            return false;
         }
+        let delay = Duration::from_millis(self.rng.borrow_mut().gen_range(0, max + 1));
+        self.schedule(delay, Timer::Announce(names));
+        true
+    }
 
-        println!("BEFORE: {}", pinned.outgoing.len());
-        while let Some(&(ref response, ref addr)) = pinned.outgoing.front() {
-            trace!("sending packet to {:?}", addr);
-
-            match pinned.socket.poll_send_to(cx, response, addr) {
-                Poll::Ready(Ok(v)) if v == response.len() => break,
-                Poll::Ready(Ok(_)) => warn!("failed to send entire datagram"),
-                Poll::Ready(Err(ref ioerr)) if ioerr.kind() == WouldBlock => break,
-                Poll::Ready(Err(err)) => warn!("error sending packet {:?}", err),
-                Poll::Pending => (break),
+    /// Announces or says goodbye for the service named `name` if its
+    /// schedule changing from `previous` changed whether it is advertised
+    fn reschedule(&mut self, name: &Name<'static>, previous: Option<Schedule>) {
+        let timer = Timer::Schedule(name.clone());
+        self.timers.retain(|(_, t)| *t != timer);
+        let svc = match services::read(&self.services).find_by_name(name).cloned() {
+            Some(svc) => svc,
+            None => return,
+        };
+        let now = self.clock.wall();
+        let was = previous.map_or(true, |schedule| schedule.contains(now));
+        match (was, svc.is_scheduled(now)) {
+            // Nothing to take back from peers that never heard of it
+            (true, false) if svc.enabled && self.advertised(name) => {
+                self.send_unsolicited(&svc, Some(0), false)
+            }
+            (false, true) => {
+                self.send_unsolicited(&svc, None, true);
+                self.schedule_refresh(name);
             }
+            _ => (),
+        }
+        if let Some(schedule) = svc.schedule {
+            self.schedule(schedule.until_change(now), timer);
         }
-        pinned.outgoing.pop_front();
-        println!("AFTER: {}", pinned.outgoing.len());
+    }
 
-        Poll::Pending
+    /// Whether records of `name` may be in peer caches: it was announced, is
+    /// about to be, or was multicast in a response
+    fn advertised(&self, name: &Name<'static>) -> bool {
+        self.stats.announcement_status(name).announcements > 0
+            || self.multicast_at.contains_key(name)
+            || self
+                .outgoing
+                .iter()
+                .any(|datagram| datagram.announces.contains(name))
+    }
+
+    /// Schedules a re-announcement of a service after the configured
+    /// fraction of its TTL, unless one is already pending
+    fn schedule_refresh(&mut self, name: &Name<'static>) {
+        let fraction = match self.config.refresh_fraction {
+            Some(fraction) if fraction > 0.0 && fraction < 1.0 => fraction,
+            Some(fraction) => {
+                warn!("ignoring refresh fraction {} outside of (0, 1)", fraction);
+                return;
+            }
+            None => return,
+        };
+
+        let timer = Timer::RefreshService(name.clone());
+        if self.timers.iter().any(|(_, t)| *t == timer) {
+            return;
+        }
+        // Before the first of its records expires
+        let ttl = self.instance_ttl().min(self.ttl_of(Type::PTR, None));
+        let after = Duration::from_secs_f32(ttl as f32 * fraction);
+        self.schedule(after, timer);
+    }
+
+    /// Arranges for the task to be woken when the next timer is due
+    fn poll_wakeup(&mut self, cx: &mut Context) {
+        let next = match self.timers.iter().map(|&(at, _)| at).min() {
+            Some(at) => at,
+            None => {
+                self.wakeup = None;
+                return;
+            }
+        };
+
+        if self.wakeup.as_ref().map(|&(at, _)| at) != Some(next) {
+            self.wakeup = Some((next, self.clock.sleep_until(next)));
+        }
+
+        if let Some((_, ref mut sleep)) = self.wakeup {
+            if sleep.as_mut().poll(cx).is_ready() {
+                self.wakeup = None;
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+
+    fn recv_packets(&mut self, cx: &mut Context) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        // The shared socket first, then those of `Config::socket_per_interface`
+        for index in 0..=self.iface_sockets.len() {
+            loop {
+                let socket = match index.checked_sub(1) {
+                    None => self.socket.as_mut(),
+                    Some(i) => self.iface_sockets.get_mut(i).map(|(_, socket)| socket),
+                };
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => break,
+                };
+                let received = if self.config.unicast_via_arrival_interface {
+                    socket.poll_recv_from_iface(cx, &mut buf)
+                } else {
+                    socket
+                        .poll_recv_from(cx, &mut buf)
+                        .map_ok(|(bytes, addr)| (bytes, addr, None))
+                };
+                let (bytes, addr, arrival) = match received {
+                    Poll::Ready(Ok(received)) => received,
+                    Poll::Ready(Err(ref err)) if is_transient(err) => {
+                        debug!("transient error receiving, carrying on: {}", err);
+                        continue;
+                    }
+                    Poll::Ready(Err(err)) => {
+                        self.stats.set_healthy(AF::v6(), false);
+                        return Err(err);
+                    }
+                    Poll::Pending => break,
+                };
+                // Is moot for certain platforms (Windows will throw a <10040> error from poll_recv)
+                if bytes >= buf.len() {
+                    warn!("buffer too small for packet from {:?}", addr);
+                    return Err(Error::BufferTooSmall(bytes, buf.len()).into());
+                }
+                if let Some(ref tap) = self.config.packet_tap {
+                    tap.deliver(&buf[..bytes], addr);
+                }
+                let via = index
+                    .checked_sub(1)
+                    .map(|i| self.iface_sockets[i].0.clone());
+                self.handle_packet(&buf[..bytes], addr, via.as_deref(), arrival);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a packet received through the socket of interface `via`, if
+    /// any, which is where the responses go out of then
+    ///
+    /// `arrival` is the index of the interface it arrived on, if known,
+    /// which unicast responses leave through.
+    fn handle_packet(
+        &mut self,
+        buffer: &[u8],
+        addr: SocketAddr,
+        via: Option<&str>,
+        arrival: Option<u32>,
+    ) {
+        trace!("received packet from {:?}", addr);
+
+        let packet = match dns_parser::Packet::parse(buffer) {
+            Ok(packet) => packet,
+            Err(error) => {
+                warn!("couldn't parse packet from {:?}: {}", addr, error);
+                return;
+            }
+        };
+
+        if !packet.header.query {
+            if self.is_own_goodbye(&packet, addr) {
+                trace!("received own goodbye back from {:?}", addr);
+                return;
+            }
+            trace!("received packet from {:?} with no query", addr);
+            self.check_conflicts(&packet, addr);
+            return;
+        }
+
+        if self.is_health_probe(&packet) {
+            trace!("received own self-query, socket is healthy");
+            self.health_probe = None;
+            self.stats.set_healthy(AF::v6(), true);
+            return;
+        }
+
+        if !packet.nameservers.is_empty() {
+            self.break_probe_ties(&packet, addr);
+        }
+
+        if packet.header.truncated {
+            warn!("dropping truncated packet from {:?}", addr);
+            return;
+        }
+
+        if self.config.strict_query_flags && has_disallowed_flags(&packet.header) {
+            debug!("dropping query from {:?} with disallowed flags", addr);
+            return;
+        }
+
+        // Nothing to answer, e.g. known answers continuing an earlier query
+        if packet.questions.is_empty() {
+            trace!("ignoring query without questions from {:?}", addr);
+            return;
+        }
+
+        self.stats.record_query(addr, self.clock.now());
+
+        let essential_only = match self.throttle(addr) {
+            Throttle::None => false,
+            Throttle::Trim => {
+                debug!("trimming responses to {:?}, over soft rate limit", addr);
+                true
+            }
+            Throttle::Drop => {
+                debug!("ignoring query from {:?}, over hard rate limit", addr);
+                return;
+            }
+        };
+
+        // A querier not using port 5353 isn't a full mDNS implementation and
+        // gets a conventional unicast DNS response (RFC 6762 section 6.7)
+        let legacy = addr.port() != MDNS_PORT;
+        let ctx = AnswerContext {
+            essential_only,
+            max_ttl: if legacy {
+                Some(self.config.legacy_unicast_ttl)
+            } else {
+                None
+            },
+            from: addr,
+            audience: Audience::Querier,
+        };
+        let known = KnownAnswers::from_packet(&packet);
+        let mut questions = packet.questions;
+        if questions.len() > self.config.max_questions {
+            let ignored = questions.len() - self.config.max_questions;
+            warn!(
+                "ignoring {} of {} questions from {:?}",
+                ignored,
+                questions.len(),
+                addr
+            );
+            self.stats.record_ignored_questions(ignored as u64);
+            questions.truncate(self.config.max_questions);
+        }
+
+        for question in &questions {
+            debug!(
+                "received question: {:?} {}",
+                question.qclass, question.qname
+            );
+
+            if let Some(ref observer) = self.config.query_observer {
+                let observed = ObservedQuestion {
+                    name: question.qname.to_string(),
+                    qtype: question.qtype.into(),
+                    unicast_response: question.qu,
+                };
+                observer.observe(addr, &observed);
+            }
+            self.notify_services(question, addr);
+        }
+
+        let key = self.prebuilt_key(&questions, legacy, essential_only, &known);
+        let reused = key
+            .as_ref()
+            .and_then(|key| self.prebuilt.responses.get(key))
+            .cloned();
+        let responses = match reused {
+            Some(mut responses) => {
+                trace!("answering {:?} with a prebuilt response", addr);
+                self.stats.record_reused_response();
+                let id = packet.header.id.to_be_bytes();
+                if let Some(ref mut unicast) = responses.unicast {
+                    if legacy {
+                        unicast[..2].copy_from_slice(&id);
+                    }
+                }
+                responses
+            }
+            None => {
+                let rotation = self.ptr_rotation.get();
+                let responses =
+                    self.build_responses(&questions, packet.header.id, legacy, ctx, &known);
+                // Answers rotating through the instances differ every time.
+                // Nor is there any point keeping what answers nothing, which
+                // also leaves queries for made up names taking no room.
+                let keep = self.ptr_rotation.get() == rotation
+                    && (responses.multicast.is_some() || responses.unicast.is_some())
+                    && self.prebuilt.responses.len() < MAX_PREBUILT_RESPONSES;
+                if let Some(key) = key.filter(|_| keep) {
+                    self.prebuilt.responses.insert(key, responses.clone());
+                }
+                responses
+            }
+        };
+
+        if let Some(response) = responses.multicast {
+            self.enqueue_datagram(Datagram {
+                packet: response,
+                addr: self.group(),
+                announces: Vec::new(),
+                response: true,
+                source: None,
+                via: via.map(str::to_owned),
+                out_of: None,
+            });
+            for name in responses.multicast_names {
+                self.note_multicast(name);
+            }
+        }
+
+        if let Some(response) = responses.unicast {
+            let (source, interface) = match self.source_for(addr.ip()) {
+                Some((source, interface)) => (Some(source), interface),
+                None => (None, None),
+            };
+            self.enqueue_datagram(Datagram {
+                packet: response,
+                addr,
+                announces: Vec::new(),
+                response: true,
+                source,
+                via: via.map(str::to_owned),
+                out_of: arrival.or(interface),
+            });
+        }
+    }
+
+    /// Builds the responses to `questions`, from a querier which is told
+    /// apart by `ctx`
+    fn build_responses(
+        &self,
+        questions: &[dns_parser::Question],
+        id: u16,
+        legacy: bool,
+        ctx: AnswerContext,
+        known: &KnownAnswers,
+    ) -> Responses {
+        let restricted_ctx = AnswerContext {
+            audience: Audience::Restricted,
+            ..ctx
+        };
+        let multicast_ctx = AnswerContext {
+            audience: Audience::Everyone,
+            ..ctx
+        };
+
+        // Only legacy responses echo the query's ID and questions, mDNS
+        // responses carry neither (RFC 6762 sections 6 and 18.1)
+        let unicast_id = if legacy { id } else { 0 };
+        let mut unicast_builder = dns_parser::Builder::new_response(unicast_id, false, true);
+        unicast_builder.set_compression(true);
+        if legacy {
+            for question in questions {
+                unicast_builder =
+                    unicast_builder.add_question(&question.qname, question.qtype, question.qclass);
+            }
+        }
+        let mut unicast_builder = unicast_builder.move_to::<dns_parser::Answers>();
+        let mut multicast_builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        unicast_builder.set_max_size(None);
+        multicast_builder.set_max_size(None);
+        multicast_builder.set_compression(true);
+        let mut unicast_response = Response::new(unicast_builder);
+        let mut multicast_response = Response::new(multicast_builder);
+
+        // A hostname meant for this querier alone mustn't reach the others
+        let rewritten = self.rewrite_hostname(&ctx.from).is_some();
+        let mut multicast_names = Vec::new();
+        for question in questions {
+            if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
+                // Everyone benefits from a multicast answer, if none went
+                // out lately (RFC 6762 section 5.4)
+                let unicast = legacy
+                    || rewritten
+                    || (question.qu
+                        && !(self.config.upgrade_stale_qu
+                            && self.multicast_is_stale(&question.qname)));
+                if unicast {
+                    unicast_response =
+                        self.handle_question(question, unicast_response, &ctx, known);
+                } else {
+                    multicast_response =
+                        self.handle_question(question, multicast_response, &multicast_ctx, known);
+                    unicast_response =
+                        self.handle_question(question, unicast_response, &restricted_ctx, known);
+                    multicast_names.push(Name::from_str(question.qname.to_string()).unwrap());
+                }
+            }
+        }
+
+        let payload_size = self.config.advertised_payload_size;
+        Responses {
+            multicast: Some(multicast_response)
+                .filter(Response::has_answers)
+                .map(|response| response.build(payload_size)),
+            // Legacy responses echo the questions, so check for answers
+            // instead
+            unicast: Some(unicast_response)
+                .filter(Response::has_answers)
+                .map(|response| response.build(payload_size)),
+            multicast_names,
+        }
+    }
+
+    /// What the responses to `questions` are kept under, if they may be
+    /// reused as `Config::prebuilt_responses` allows
+    ///
+    /// Discards the responses kept so far if the services or our addresses
+    /// changed since they were built.
+    fn prebuilt_key(
+        &mut self,
+        questions: &[dns_parser::Question],
+        legacy: bool,
+        essential_only: bool,
+        known: &KnownAnswers,
+    ) -> Option<QueryKey> {
+        let config = &self.config;
+        if !config.prebuilt_responses
+            || essential_only
+            || !known.is_empty()
+            || (config.upgrade_stale_qu && questions.iter().any(|q| q.qu))
+            || !self.probing.is_empty()
+            // The hostname differs by querier
+            || config.hostname_rewrite.is_some()
+        {
+            return None;
+        }
+        let generation = {
+            let services = services::read(&self.services);
+            // Their responses differ by querier or over time, or order
+            // instances sharing a name at random
+            let varying = services.all().any(|svc| {
+                svc.is_restricted()
+                    || svc.txt_fn.is_some()
+                    || svc.schedule.is_some()
+                    || services.find_all_by_name(&svc.name).nth(1).is_some()
+            });
+            if varying {
+                return None;
+            }
+            services.generation()
+        };
+        let (addresses, stale) = self.refreshed_addresses.clone()?;
+        if stale {
+            // Answered with a shorter TTL
+            return None;
+        }
+        if self.prebuilt.generation != generation || self.prebuilt.addresses != addresses {
+            self.prebuilt = Prebuilt {
+                generation,
+                addresses,
+                responses: HashMap::new(),
+            };
+        }
+        let questions = questions
+            .iter()
+            .map(|q| (q.qname.to_string(), q.qtype, q.qclass, q.qu))
+            .collect();
+        Some((legacy, questions))
+    }
+
+    /// Passes `question` on to the services it is about which asked for
+    /// their queries, i.e. those of its type or name
+    fn notify_services(&self, question: &dns_parser::Question, from: SocketAddr) {
+        let services = services::read(&self.services);
+        let by_type = services.find_by_type(&question.qname);
+        let by_name = services.find_all_by_name(&question.qname);
+        let mut observed = None;
+        for svc in by_type.chain(by_name) {
+            if let Some(ref queries) = svc.queries {
+                let observed = observed.get_or_insert_with(|| ObservedQuestion {
+                    name: question.qname.to_string(),
+                    qtype: question.qtype.into(),
+                    unicast_response: question.qu,
+                });
+                // Nobody listening only means nobody cares, and questions
+                // not read yet are plenty
+                let _ = queries.clone().try_send((from, observed.clone()));
+            }
+        }
+    }
+
+    /// Whether our records of `name` haven't been multicast for a quarter
+    /// of their TTL
+    fn multicast_is_stale(&self, name: &Name) -> bool {
+        let ttl = {
+            let services = services::read(&self.services);
+            if name == services.get_hostname() {
+                self.ttl_of(Type::A, None)
+                    .min(self.ttl_of(Type::AAAA, None))
+            } else if services.find_by_type(name).next().is_some() {
+                self.ttl_of(Type::PTR, None)
+            } else {
+                self.instance_ttl()
+            }
+        };
+        let fresh_for = Duration::from_secs(u64::from(ttl) / 4);
+        match self.multicast_at.get(name) {
+            Some(&at) => self.clock.now() >= at + fresh_for,
+            None => true,
+        }
+    }
+
+    /// Remembers that records of `name` were just multicast, if it is one of
+    /// our names
+    fn note_multicast(&mut self, name: Name<'static>) {
+        let ours = {
+            let services = services::read(&self.services);
+            name == *services.get_hostname()
+                || services.find_by_name(&name).is_some()
+                || services.find_by_type(&name).next().is_some()
+        };
+        if ours {
+            self.multicast_at.insert(name, self.clock.now());
+        }
+    }
+
+    /// Whether `packet` is a goodbye we sent, looped back to us, which
+    /// there's nothing to do about
+    fn is_own_goodbye(&self, packet: &dns_parser::Packet, from: SocketAddr) -> bool {
+        if from.port() != MDNS_PORT
+            || packet.answers.is_empty()
+            || packet.answers.iter().any(|rr| rr.ttl != 0)
+        {
+            return false;
+        }
+        match self.interfaces.list() {
+            Ok(interfaces) => interfaces.iter().any(|iface| iface.ip() == from.ip()),
+            Err(_) => false,
+        }
+    }
+
+    /// Looks for records in another host's response that claim one of our
+    /// unique names with different data (RFC 6762 section 9)
+    ///
+    /// PTR records are shared, so only SRV, TXT and address records can
+    /// conflict. As in the probing tiebreak of section 8.2, whoever has the
+    /// lexicographically later record data wins: we either defend ours by
+    /// announcing it again, or give the service a new name.
+    fn check_conflicts(&mut self, packet: &dns_parser::Packet, from: SocketAddr) {
+        let mut conflicts = Vec::new();
+        {
+            let services = services::read(&self.services);
+            let hostname = services.get_hostname();
+            for rr in &packet.answers {
+                // A goodbye gives the name up rather than claiming it
+                if rr.ttl == 0 {
+                    continue;
+                }
+                let (ours, svc) = match rr.data {
+                    RRData::SRV { .. } | RRData::TXT(_) => {
+                        // Any of the instances sharing the name
+                        let ours: Vec<_> = services
+                            .find_all_by_name(&rr.name)
+                            .map(|svc| match rr.data {
+                                RRData::SRV { .. } => rdata_bytes(&svc.srv_rdata(hostname)),
+                                _ => rdata_bytes(&RRData::TXT(&svc.txt_rdata(false))),
+                            })
+                            .collect();
+                        match services.find_by_name(&rr.name) {
+                            Some(svc) => (ours, Some(svc.name.clone())),
+                            None => continue,
+                        }
+                    }
+                    RRData::A(_) | RRData::AAAA(_) if rr.name == *hostname => {
+                        let addrs = match self.interfaces.list() {
+                            Ok(interfaces) => interfaces,
+                            // Better to miss a conflict than to report a bogus one
+                            Err(_) => continue,
+                        };
+                        let ours = addrs
+                            .iter()
+                            .filter_map(|iface| match (iface.ip(), &rr.data) {
+                                (IpAddr::V4(ip), RRData::A(_)) => Some(RRData::A(ip)),
+                                (IpAddr::V6(ip), RRData::AAAA(_)) => Some(RRData::AAAA(ip)),
+                                _ => None,
+                            })
+                            .map(|data| rdata_bytes(&data))
+                            .collect();
+                        (ours, None)
+                    }
+                    _ => continue,
+                };
+
+                let theirs = rdata_bytes(&rr.data);
+                // Includes our own announcements, looped back to us
+                if ours.contains(&theirs) {
+                    continue;
+                }
+                let we_win = ours.iter().max().map_or(false, |ours| *ours > theirs);
+                conflicts.push((rr.name.to_string(), rr.data.typ() as u16, svc, we_win));
+            }
+        }
+
+        for (name, rtype, svc, we_win) in conflicts {
+            warn!("{:?} claims {} with different data", from, name);
+            let resolution = match svc {
+                // A name still being probed for isn't ours to defend yet
+                Some(svc) if we_win && !self.probing.contains(&svc) => {
+                    let svc = services::read(&self.services).find_by_name(&svc).cloned();
+                    if let Some(svc) = svc {
+                        self.send_unsolicited(&svc, None, true);
+                    }
+                    ConflictResolution::Defended
+                }
+                Some(svc) => match self.rename(&svc) {
+                    Some(new_name) => ConflictResolution::Renamed(new_name),
+                    None => ConflictResolution::Unresolved,
+                },
+                None if we_win => {
+                    self.announce_addresses();
+                    ConflictResolution::Defended
+                }
+                // Renaming the host is up to the application
+                None => ConflictResolution::Unresolved,
+            };
+
+            if let Some(ref observer) = self.config.conflict_observer {
+                observer.observe(&Conflict {
+                    name,
+                    rtype,
+                    from,
+                    resolution,
+                });
+            }
+        }
+    }
+
+    /// Gives a service the first free name of the form "Name (2)",
+    /// "Name (3)" and so on, and announces it under that name
+    fn rename(&mut self, name: &Name<'static>) -> Option<String> {
+        let mut services = services::write(&self.services);
+        let svc = services.find_by_name(name)?.clone();
+        let instance = svc.instance()?;
+        let instance = &instance[..];
+        let (base, mut n) = match instance.rfind(" (") {
+            Some(pos) if instance.ends_with(')') => {
+                match instance[pos + 2..instance.len() - 1].parse::<u32>() {
+                    Ok(n) => (&instance[..pos], n + 1),
+                    Err(_) => (instance, 2),
+                }
+            }
+            _ => (instance, 2),
+        };
+        let mut base = base.to_owned();
+        let new_name = loop {
+            let mut candidate = format!("{} ({}).{}", base, n, svc.typ);
+            // Truncating the instance to make room for the number, as RFC
+            // 6763 section 4.1.1 allows, rather than exceed the label limit
+            while services::check_name(&candidate).is_err() {
+                base.pop()?;
+                base.truncate(base.trim_end().len());
+                candidate = format!("{} ({}).{}", base, n, svc.typ);
+            }
+            let candidate = Name::from_str(candidate).unwrap();
+            if services.find_by_name(&candidate).is_none() {
+                break candidate;
+            }
+            n += 1;
+        };
+        let renamed = services.rename(name, new_name)?;
+        drop(services);
+
+        debug!("renamed {} to {}", name, renamed.name);
+        self.stats.forget(name);
+        if self.probing.remove(name) {
+            // Probed for afresh under the new name
+            self.probe(vec![renamed.name.clone()]);
+        } else {
+            self.send_unsolicited(&renamed, None, true);
+            self.schedule_refresh(&renamed.name);
+        }
+        Some(renamed.name.to_string())
+    }
+
+    /// Multicasts our address records alone
+    fn announce_addresses(&mut self) {
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(None);
+        builder.set_cache_flush(true);
+        let hostname = services::read(&self.services).get_hostname().clone();
+        builder = self.add_ip_rr(&hostname, builder, None, &KnownAnswers::default());
+        if !builder.is_empty() {
+            let response = builder.build().unwrap_or_else(|x| x);
+            self.cancel_goodbyes(slice::from_ref(&hostname));
+            self.enqueue_unsolicited(response, Vec::new());
+        }
+    }
+
+    /// Says goodbye for the addresses of `previous`, then announces every
+    /// service again, now pointing at `name`
+    fn set_hostname(&mut self, name: Name<'static>, previous: &Name<'static>) {
+        services::write(&self.services).set_hostname(name);
+
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(None);
+        builder = self.add_ip_rr(previous, builder, Some(0), &KnownAnswers::default());
+        if !builder.is_empty() {
+            let goodbye = builder.build().unwrap_or_else(|x| x);
+            let repeats = self.config.goodbye_count.saturating_sub(1);
+            self.enqueue_goodbye(vec![previous.clone()], goodbye, repeats);
+        }
+
+        let svcs: Vec<_> = services::read(&self.services).all().cloned().collect();
+        if svcs.is_empty() {
+            self.announce_addresses();
+        } else {
+            self.send_unsolicited_batch(&svcs, None, true);
+        }
+    }
+
+    /// Counts a query against the rate limit of its source address, whatever
+    /// port it was sent from
+    fn throttle(&mut self, addr: SocketAddr) -> Throttle {
+        let limit = match self.config.rate_limit {
+            Some(ref limit) => limit,
+            None => return Throttle::None,
+        };
+        let now = self.clock.now();
+
+        // Forget sources whose window is over, and count new ones together
+        // once there are still too many, so the map can't grow unbounded
+        let mut key = Some(addr.ip());
+        if !self.queriers.contains_key(&key) && self.queriers.len() >= MAX_QUERIERS {
+            self.queriers
+                .retain(|_, state| now.duration_since(state.window_start) < limit.window);
+            if self.queriers.len() >= MAX_QUERIERS {
+                key = None;
+            }
+        }
+
+        let state = self.queriers.entry(key).or_insert(QuerierState {
+            window_start: now,
+            count: 0,
+        });
+        if now.duration_since(state.window_start) >= limit.window {
+            state.window_start = now;
+            state.count = 0;
+        }
+        state.count += 1;
+
+        if state.count > limit.hard_limit {
+            Throttle::Drop
+        } else if state.count > limit.soft_limit {
+            Throttle::Trim
+        } else {
+            Throttle::None
+        }
+    }
+
+    fn handle_question(
+        &self,
+        question: &dns_parser::Question,
+        mut response: Response,
+        ctx: &AnswerContext,
+        known: &KnownAnswers,
+    ) -> Response {
+        let services = services::read(&self.services);
+        let placement = self.config.placement;
+        let now = self.clock.wall();
+        let visible = |svc: &&ServiceData| {
+            svc.enabled
+                && svc.is_scheduled(now)
+                && svc.families.allows(AF::v6())
+                && !self.probing.contains(&svc.name)
+                && match ctx.audience {
+                    Audience::Everyone => !svc.is_restricted(),
+                    Audience::Querier => svc.permits(ctx.from.ip()),
+                    Audience::Restricted => svc.is_restricted() && svc.permits(ctx.from.ip()),
+                }
+        };
+
+        // Answered with the records of the service it stands for
+        let alias = services.find_alias(&question.qname);
+        let rewritten = match ctx.audience {
+            Audience::Everyone => None,
+            Audience::Querier | Audience::Restricted => self.rewrite_hostname(&ctx.from),
+        };
+        let hostname = rewritten
+            .as_ref()
+            .unwrap_or_else(|| services.get_hostname());
+
+        match question.qtype {
+            // Under whichever of the names the querier asks for, in case it
+            // learnt the one it wasn't rewritten to elsewhere
+            QueryType::A | QueryType::AAAA | QueryType::All
+                if (names_host(&question.qname, hostname)
+                    || names_host(&question.qname, services.get_hostname()))
+                    && ctx.audience != Audience::Restricted =>
+            {
+                let name = &question.qname;
+                response = response
+                    .answer(|b| self.add_ip_rr_of(question.qtype, name, b, ctx.max_ttl, known));
+            }
+            // Service type enumeration (RFC 6763 section 9), sorted so the
+            // answer doesn't change with the order types were registered in
+            QueryType::PTR if is_type_enumeration(&question.qname) => {
+                let mut types: Vec<_> = services
+                    .all()
+                    .filter(|svc| svc.discoverable)
+                    .filter(visible)
+                    .map(|svc| svc.typ.clone())
+                    .collect();
+                types.sort_by_key(|typ| typ.to_string().to_ascii_lowercase());
+                types.dedup();
+                let ttl = self.ttl_of(Type::PTR, ctx.max_ttl);
+                for typ in types {
+                    let data = RRData::PTR(typ);
+                    if !known.contains(&question.qname, &data, ttl) {
+                        response = response
+                            .answer(|b| b.add_answer(&question.qname, QueryClass::IN, ttl, &data));
+                    }
+                }
+            }
+            QueryType::PTR => {
+                let aliases = services.find_aliases_by_type(&question.qname);
+                let mut svcs: Vec<_> = services
+                    .find_by_type(&question.qname)
+                    .chain(&aliases)
+                    .filter(visible)
+                    .filter(|svc| svc.sends(ServiceRecord::Ptr))
+                    .collect();
+                if svcs.iter().any(|svc| svc.has_srv()) && self.suppress_services() {
+                    svcs.retain(|svc| !svc.has_srv());
+                }
+                let cap = match self.config.ptr_instances {
+                    PtrInstances::All => usize::MAX,
+                    PtrInstances::Capped(cap) => cap,
+                    PtrInstances::Single => 1,
+                };
+                let svcs: Vec<_> = if svcs.len() > cap {
+                    // Rotate through the instances, so every one of them is
+                    // answered eventually
+                    let start = self.ptr_rotation.get() % svcs.len();
+                    self.ptr_rotation.set(start + cap);
+                    svcs.iter().cycle().skip(start).take(cap).cloned().collect()
+                } else {
+                    svcs
+                };
+                let mut answered: Vec<&Name> = Vec::new();
+                for svc in svcs {
+                    // Instances sharing a name share the PTR and TXT records
+                    // too, and differ in their SRV records alone
+                    if answered.contains(&&svc.name) {
+                        if !ctx.essential_only && self.config.ptr_response == PtrResponse::Bundled {
+                            response = response.add(placement.srv, |b| {
+                                self.add_srv_rr(svc, hostname, b, ctx.max_ttl, known)
+                            });
+                        }
+                        continue;
+                    }
+                    answered.push(&svc.name);
+                    let ptr_ttl = self.ttl_of(Type::PTR, ctx.max_ttl);
+                    if known.contains(&svc.typ, &RRData::PTR(svc.name.clone()), ptr_ttl) {
+                        continue;
+                    }
+                    response = response.answer(|b| svc.add_ptr_rr(b, ptr_ttl));
+                    if ctx.essential_only || self.config.ptr_response == PtrResponse::Minimal {
+                        continue;
+                    }
+                    response = response
+                        .add(placement.srv, |b| {
+                            self.add_srv_rr(svc, hostname, b, ctx.max_ttl, known)
+                        })
+                        .add(placement.txt, |b| self.add_txt_rr(svc, b, ctx, known));
+                    if svc.has_srv() {
+                        let qtype = address_type(&[svc]);
+                        response = response.add(placement.addresses, |b| {
+                            self.add_ip_rr_of(qtype, hostname, b, ctx.max_ttl, known)
+                        });
+                    }
+                }
+            }
+            QueryType::SRV if !self.suppress_services() => {
+                // Nothing to add if the querier already has the answer
+                let srv_ttl = self.ttl_of(Type::SRV, ctx.max_ttl);
+                let svcs: Vec<_> = services
+                    .find_all_by_name(&question.qname)
+                    .chain(&alias)
+                    .filter(visible)
+                    .filter(|svc| svc.has_srv() && svc.sends(ServiceRecord::Srv))
+                    .filter(|svc| !known.contains(&svc.name, &svc.srv_rdata(hostname), srv_ttl))
+                    .collect();
+                let answered = !svcs.is_empty();
+                let qtype = address_type(&svcs);
+                for svc in srv_order(svcs, &mut *self.rng.borrow_mut()) {
+                    response = response.answer(|b| svc.add_srv_rr(hostname, b, srv_ttl));
+                }
+                if answered && !ctx.essential_only {
+                    response = response.add(placement.addresses, |b| {
+                        self.add_ip_rr_of(qtype, hostname, b, ctx.max_ttl, known)
+                    });
+                }
+            }
+            QueryType::TXT => {
+                let svc = services.find_by_name(&question.qname).or(alias.as_ref());
+                if let Some(svc) = svc.filter(visible) {
+                    response = response.answer(|b| self.add_txt_rr(svc, b, ctx, known));
+                }
+            }
+            QueryType::All => {
+                let svcs: Vec<_> = services
+                    .find_all_by_name(&question.qname)
+                    .chain(&alias)
+                    .filter(visible)
+                    .collect();
+                if let Some(&svc) = svcs.first() {
+                    let mut types = Vec::new();
+                    if svc.sends(ServiceRecord::Txt) {
+                        types.push(Type::TXT as u16);
+                    }
+                    if !self.suppress_services() {
+                        for svc in srv_order(svcs.clone(), &mut *self.rng.borrow_mut()) {
+                            if svc.has_srv() && svc.sends(ServiceRecord::Srv) {
+                                types.push(Type::SRV as u16);
+                                response = response.answer(|b| {
+                                    self.add_srv_rr(svc, hostname, b, ctx.max_ttl, known)
+                                });
+                            }
+                        }
+                    }
+                    response = response.answer(|b| self.add_txt_rr(svc, b, ctx, known));
+                    // Spares the querier asking for the types it has none
+                    // of (RFC 6762 section 6.1)
+                    types.extend(svc.records.iter().map(|record| record.rtype));
+                    let nsec = RRData::NSEC {
+                        next: svc.name.clone(),
+                        types,
+                    };
+                    response = response.add(Section::Additional, |b| {
+                        let ttl = self.ttl_of(Type::NSEC, ctx.max_ttl);
+                        b.add_answer(&svc.name, QueryClass::IN, ttl, &nsec)
+                    });
+                }
+            }
+            // Some resolvers probe for ".local" zone delegation first. There
+            // is no zone to be authoritative for, and staying quiet is what
+            // RFC 6762 section 3 tells them to expect.
+            QueryType::SOA | QueryType::NS => {
+                debug!("ignoring {:?} query for {}", question.qtype, question.qname);
+            }
+            _ => (),
+        }
+
+        let svc = services.find_by_name(&question.qname).or(alias.as_ref());
+        if let Some(svc) = svc.filter(visible) {
+            let rtype = match question.qtype {
+                QueryType::All => None,
+                qtype => Some(qtype.into()),
+            };
+            response =
+                response.answer(|b| svc.add_raw_rrs(b, rtype, ctx.max_ttl.unwrap_or(u32::MAX)));
+        }
+
+        response
+    }
+
+    /// The TTL of our records of type `typ` as configured, lowered to
+    /// `max_ttl` if given, e.g. for goodbyes and legacy queriers
+    fn ttl_of(&self, typ: Type, max_ttl: Option<u32>) -> u32 {
+        let ttls = &self.config.ttls;
+        let configured = match typ {
+            Type::PTR => ttls.ptr,
+            Type::SRV => ttls.srv,
+            Type::TXT => ttls.txt,
+            Type::A => ttls.a,
+            Type::AAAA => ttls.aaaa,
+            _ => OTHER_TTL,
+        };
+        configured.min(max_ttl.unwrap_or(u32::MAX))
+    }
+
+    /// The shortest configured TTL of the records named after an instance
+    fn instance_ttl(&self) -> u32 {
+        self.ttl_of(Type::SRV, None)
+            .min(self.ttl_of(Type::TXT, None))
+    }
+
+    /// Adds the SRV record of `svc`, if it has one the querier doesn't know
+    fn add_srv_rr(
+        &self,
+        svc: &ServiceData,
+        hostname: &Name,
+        builder: AnswerBuilder,
+        max_ttl: Option<u32>,
+        known: &KnownAnswers,
+    ) -> AnswerBuilder {
+        let ttl = self.ttl_of(Type::SRV, max_ttl);
+        if !svc.has_srv()
+            || !svc.sends(ServiceRecord::Srv)
+            || known.contains(&svc.name, &svc.srv_rdata(hostname), ttl)
+        {
+            return builder;
+        }
+        svc.add_srv_rr(hostname, builder, ttl)
+    }
+
+    /// Adds the TXT record of `svc`, unless the querier knows it
+    fn add_txt_rr(
+        &self,
+        svc: &ServiceData,
+        builder: AnswerBuilder,
+        ctx: &AnswerContext,
+        known: &KnownAnswers,
+    ) -> AnswerBuilder {
+        if !svc.sends(ServiceRecord::Txt) {
+            return builder;
+        }
+        // Generated once, as a `txt_fn` may give different data each time
+        let rdata = svc.txt_rdata(ctx.audience != Audience::Everyone);
+        let data = RRData::TXT(&rdata);
+        let ttl = self.ttl_of(Type::TXT, ctx.max_ttl);
+        if known.contains(&svc.name, &data, ttl) {
+            return builder;
+        }
+        builder.add_answer(&svc.name, QueryClass::IN, ttl, &data)
+    }
+
+    fn add_ip_rr(
+        &self,
+        hostname: &Name,
+        builder: AnswerBuilder,
+        max_ttl: Option<u32>,
+        known: &KnownAnswers,
+    ) -> AnswerBuilder {
+        self.add_ip_rr_of(QueryType::All, hostname, builder, max_ttl, known)
+    }
+
+    /// Adds our addresses of the family asked for by `qtype`, which matters
+    /// once both families are advertised
+    fn add_ip_rr_of(
+        &self,
+        qtype: QueryType,
+        hostname: &Name,
+        mut builder: AnswerBuilder,
+        max_ttl: Option<u32>,
+        known: &KnownAnswers,
+    ) -> AnswerBuilder {
+        let wanted = |ip: &IpAddr| match qtype {
+            QueryType::A => ip.is_ipv4(),
+            QueryType::AAAA => ip.is_ipv6(),
+            _ => true,
+        };
+        let (ips, stale) = self.listed_addresses();
+        for ip in ips.into_iter().filter(wanted) {
+            let (data, ttl) = match ip {
+                IpAddr::V4(ip) => (RRData::A(ip), self.ttl_of(Type::A, max_ttl)),
+                IpAddr::V6(ip) => (RRData::AAAA(ip), self.ttl_of(Type::AAAA, max_ttl)),
+            };
+            // Peers should check back soon on addresses that may be gone
+            let ttl = if stale {
+                ttl.min(STALE_ADDRESS_TTL)
+            } else {
+                ttl
+            };
+            if !known.contains(hostname, &data, ttl) {
+                builder = builder.add_answer(hostname, QueryClass::IN, ttl, &data);
+            }
+        }
+
+        builder
+    }
+
+    /// The hostname to give `querier` instead of ours, see
+    /// `Config::hostname_rewrite`, or `None` for ours if it can't be written
+    /// to packets
+    fn rewrite_hostname(&self, querier: &SocketAddr) -> Option<Name<'static>> {
+        let hostname = self.config.hostname_rewrite.as_ref()?.rewrite(querier)?;
+        if let Err(err) = services::check_name(&hostname) {
+            warn!("ignoring rewritten hostname for {}: {}", querier, err);
+            return None;
+        }
+        Some(Name::from_str(hostname).unwrap())
+    }
+
+    /// The addresses to advertise for our hostname, falling back to
+    /// `NoAddresses::Static` if no interface has any
+    fn addresses(&self) -> Vec<IpAddr> {
+        self.listed_addresses().0
+    }
+
+    /// Like `addresses`, along with whether they are those listed before,
+    /// as listing the interfaces failed
+    fn listed_addresses(&self) -> (Vec<IpAddr>, bool) {
+        let mut interfaces = match self.eligible_interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(err) => {
+                error!("could not get list of interfaces: {}", err);
+                if let Some(ips) = self.stale_addresses() {
+                    return (ips, true);
+                }
+                Vec::new()
+            }
+        };
+        let preference = &self.config.interface_preference;
+        interfaces.sort_by_key(|iface| Reverse(preference.get(&iface.name).copied().unwrap_or(0)));
+        let mut ips: Vec<_> = interfaces
+            .iter()
+            .inspect(|iface| trace!("found interface {:?}", iface))
+            .map(|iface| iface.ip())
+            .collect();
+        if ips.is_empty() {
+            if let NoAddresses::Static(ref static_ips) = self.config.when_no_addresses {
+                ips = static_ips.clone();
+            }
+        }
+
+        let both = self.config.include_both_families;
+        // Aliases can put the same address on several interfaces
+        let mut seen = HashSet::new();
+        ips.retain(|ip| (both || ip.is_ipv6() == AF::v6()) && seen.insert(*ip));
+        if self.config.stale_address_window.is_some() && !interfaces.is_empty() {
+            *self.last_addresses.borrow_mut() = Some((self.clock.now(), ips.clone()));
+        }
+        (ips, false)
+    }
+
+    /// The addresses listed last, unless that was too long ago to answer
+    /// with them still
+    fn stale_addresses(&self) -> Option<Vec<IpAddr>> {
+        let window = self.config.stale_address_window?;
+        let last = self.last_addresses.borrow();
+        let (at, ref ips) = *last.as_ref()?;
+        if self.clock.now().saturating_duration_since(at) > window {
+            return None;
+        }
+        Some(ips.clone())
+    }
+
+    /// Whether answers for services must be held back, as nothing would
+    /// tell clients where to find them
+    fn suppress_services(&self) -> bool {
+        self.config.when_no_addresses == NoAddresses::Suppress && self.addresses().is_empty()
+    }
+
+    fn send_unsolicited(&mut self, svc: &ServiceData, ttl: Option<u32>, include_ip: bool) {
+        self.send_announcement(&[svc], ttl, include_ip);
+    }
+
+    /// Announces services in as few packets as possible, but never more
+    /// than one service per packet once `MAX_ANNOUNCEMENT_SIZE` is reached
+    fn send_unsolicited_batch(&mut self, svcs: &[ServiceData], ttl: Option<u32>, include_ip: bool) {
+        let mut batch: Vec<&ServiceData> = Vec::new();
+        let mut size = 0;
+        for svc in svcs {
+            let svc_size = self.announcement(&[svc], ttl, include_ip).len();
+            if !batch.is_empty() && size + svc_size > MAX_ANNOUNCEMENT_SIZE {
+                self.send_announcement(&batch, ttl, include_ip);
+                batch.clear();
+                size = 0;
+            }
+            batch.push(svc);
+            size += svc_size;
+        }
+        if !batch.is_empty() {
+            self.send_announcement(&batch, ttl, include_ip);
+        }
+    }
+
+    fn send_announcement(&mut self, svcs: &[&ServiceData], ttl: Option<u32>, include_ip: bool) {
+        if self.config.passive_only {
+            return;
+        }
+        // Everyone hears announcements, so restricted services get none.
+        // Disabled ones and those outside their hours only get goodbyes.
+        let now = self.clock.wall();
+        let svcs: Vec<_> = svcs
+            .iter()
+            .copied()
+            .filter(|svc| !svc.is_restricted() && svc.families.allows(AF::v6()))
+            .filter(|svc| ttl == Some(0) || (svc.enabled && svc.is_scheduled(now)))
+            .collect();
+        let response = self.announcement(&svcs, ttl, include_ip);
+        if response.is_empty() {
+            return;
+        }
+        let names: Vec<_> = svcs.iter().map(|svc| svc.name.clone()).collect();
+        if ttl == Some(0) {
+            let repeats = self.config.goodbye_count.saturating_sub(1);
+            return self.enqueue_goodbye(names, response, repeats);
+        }
+        for svc in &svcs {
+            self.note_multicast(svc.typ.clone());
+            self.note_multicast(svc.name.clone());
+        }
+        self.cancel_goodbyes(&names);
+        if include_ip {
+            let hostname = services::read(&self.services).get_hostname().clone();
+            self.cancel_goodbyes(slice::from_ref(&hostname));
+            self.note_multicast(hostname);
+        }
+        self.enqueue_unsolicited(response, names);
+    }
+
+    /// Says goodbye for the PTR record of `svc` alone, leaving its other
+    /// records cached for those who know its name, if it was announced
+    fn send_ptr_goodbye(&mut self, svc: &ServiceData) {
+        let announced = svc.enabled
+            && svc.is_scheduled(self.clock.wall())
+            && !svc.is_restricted()
+            && svc.families.allows(AF::v6())
+            && svc.sends(ServiceRecord::Ptr);
+        if !announced {
+            return;
+        }
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(None);
+        builder = svc.add_ptr_rr(builder, 0);
+        let goodbye = builder.build().unwrap_or_else(|x| x);
+        let repeats = self.config.goodbye_count.saturating_sub(1);
+        self.enqueue_goodbye(vec![svc.name.clone()], goodbye, repeats);
+    }
+
+    /// Multicasts `goodbye` for `names`, and again `repeats` more times
+    fn enqueue_goodbye(&mut self, names: Vec<Name<'static>>, goodbye: Vec<u8>, repeats: u8) {
+        if repeats > 0 && !self.config.passive_only {
+            let interval = self.config.goodbye_interval;
+            let timer = Timer::RepeatGoodbye(names, goodbye.clone(), repeats - 1);
+            self.schedule(interval, timer);
+        }
+        self.enqueue_unsolicited(goodbye, Vec::new());
+    }
+
+    /// Drops the repeats of goodbyes for any of `names`, which are being
+    /// announced again and would be wiped from peers' caches by them
+    ///
+    /// A goodbye for several names loses its repeats for all of them, the
+    /// others having had theirs once at least.
+    fn cancel_goodbyes(&mut self, names: &[Name<'static>]) {
+        self.timers.retain(|(_, timer)| match timer {
+            Timer::RepeatGoodbye(said, ..) => !said.iter().any(|name| names.contains(name)),
+            _ => true,
+        });
+    }
+
+    /// Multicasts what nobody asked for, unless `Config::passive_only`
+    fn enqueue_unsolicited(&mut self, packet: Vec<u8>, announces: Vec<Name<'static>>) {
+        if self.config.passive_only {
+            trace!("not sending unsolicited packet, responding to queries only");
+            return;
+        }
+        let addr = self.group();
+        self.enqueue(packet, addr, announces);
+    }
+
+    /// Builds the records announcing `svcs`, empty if there are none
+    fn announcement(&self, svcs: &[&ServiceData], ttl: Option<u32>, include_ip: bool) -> Vec<u8> {
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(None);
+        builder.set_compression(true);
+        // What we announce replaces whatever peers cached for our names
+        builder.set_cache_flush(ttl != Some(0));
+
+        let services = services::read(&self.services);
+
+        for svc in svcs {
+            if svc.discoverable && svc.sends(ServiceRecord::Ptr) {
+                builder = svc.add_ptr_rr(builder, self.ttl_of(Type::PTR, ttl));
+            }
+            if svc.has_srv() && svc.sends(ServiceRecord::Srv) {
+                let srv_ttl = self.ttl_of(Type::SRV, ttl);
+                builder = svc.add_srv_rr(services.get_hostname(), builder, srv_ttl);
+            }
+            if svc.sends(ServiceRecord::Txt) {
+                builder = svc.add_txt_rr(builder, self.ttl_of(Type::TXT, ttl), false);
+            }
+        }
+        if include_ip && svcs.iter().any(|svc| svc.has_srv()) {
+            builder = self.add_ip_rr_of(
+                address_type(svcs),
+                services.get_hostname(),
+                builder,
+                ttl,
+                &KnownAnswers::default(),
+            );
+        }
+
+        if builder.is_empty() {
+            Vec::new()
+        } else {
+            builder.build().unwrap_or_else(|x| x)
+        }
+    }
+
+    fn enqueue(&mut self, packet: Vec<u8>, addr: SocketAddr, announces: Vec<Name<'static>>) {
+        self.enqueue_datagram(Datagram {
+            packet,
+            addr,
+            announces,
+            response: false,
+            source: None,
+            via: None,
+            out_of: None,
+        });
+    }
+
+    fn enqueue_datagram(&mut self, mut datagram: Datagram) {
+        let addr = datagram.addr;
+        if self.socket.is_none() {
+            // Everything gets announced once bound, so no need to hold on
+            trace!("dropping packet to {:?}, socket not bound yet", addr);
+            return;
+        }
+        // Multicast not meant for one interface goes out of each of them
+        if datagram.via.is_none() && addr.ip().is_multicast() && !self.iface_sockets.is_empty() {
+            let names: Vec<_> = self
+                .iface_sockets
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in names {
+                self.enqueue_datagram(Datagram {
+                    via: Some(name),
+                    ..datagram.clone()
+                });
+            }
+            return;
+        }
+        if let Some(ref filter) = self.config.outgoing_filter {
+            match filter.filter(datagram.packet, addr) {
+                Some(packet) => datagram.packet = packet,
+                None => {
+                    trace!("outgoing filter dropped packet to {:?}", addr);
+                    return;
+                }
+            }
+        }
+        // Queries arriving together, e.g. from several hosts starting to
+        // browse at once, share a response still waiting to be sent
+        if self.config.dedup_outgoing && datagram.response {
+            let queued = self.outgoing.iter().any(|queued| {
+                queued.response
+                    && queued.addr == addr
+                    && queued.via == datagram.via
+                    && queued.packet == datagram.packet
+            });
+            if queued {
+                trace!("identical datagram to {:?} already queued", addr);
+                return;
+            }
+        }
+        debug_assert!(
+            !addr.ip().is_multicast() || addr == self.group(),
+            "{} isn't the group of this address family",
+            addr
+        );
+        self.outgoing.push_back(datagram);
+    }
+
+    /// Where `Config::unicast_source_from_subnet` applies, our address on
+    /// the subnet of `querier`, with the index of its interface as a
+    /// link-local source can't be sent from without it
+    fn source_for(&self, querier: IpAddr) -> Option<(IpAddr, Option<u32>)> {
+        if !self.config.unicast_source_from_subnet {
+            return None;
+        }
+        let interfaces = self.eligible_interfaces().ok()?;
+        let iface = interfaces
+            .iter()
+            .filter(|i| AF::has_addr(i))
+            .find(|iface| {
+                let prefix_len = match iface.addr {
+                    IfAddr::V4(ref addr) => u32::from(addr.netmask).count_ones(),
+                    IfAddr::V6(ref addr) => u128::from(addr.netmask).count_ones(),
+                };
+                Subnet::new(iface.ip(), prefix_len as u8).map_or(false, |net| net.contains(querier))
+            })?;
+        Some((iface.ip(), self.interfaces.index(&iface.name).ok()))
+    }
+
+    /// Says goodbye for every registered service and starts shutting down,
+    /// signalling `done` once the send queue is empty
+    fn drain(&mut self, done: oneshot::Sender<()>) {
+        let mut svcs: Vec<_> = services::read(&self.services).all().cloned().collect();
+        // Those announced once are owed theirs early
+        svcs.extend(self.goodbyes.drain().map(|(_, svc)| svc));
+        self.send_unsolicited_batch(&svcs, Some(0), false);
+        self.draining = Some(done);
+    }
+
+    /// Sends queued datagrams until the queue is empty or the socket is busy
+    fn send_packets(&mut self, cx: &mut Context) {
+        if self.socket.is_none() {
+            return;
+        }
+        if self.observe_only {
+            if !self.outgoing.is_empty() {
+                debug!("observing only, dropping {} datagrams", self.outgoing.len());
+                let withheld = self.outgoing.drain(..).count();
+                self.stats.record_withheld_datagrams(withheld as u64);
+            }
+            return;
+        }
+        // Index of the next datagram to send, past any multicast held back
+        // by `Config::max_multicast_rate`, which unicast may overtake
+        let mut next = 0;
+        while let Some(datagram) = self.outgoing.get(next) {
+            let multicast = datagram.addr.ip().is_multicast();
+            if let (true, Some(budget)) = (multicast, &mut self.multicast_budget) {
+                if let Err(wait) = budget.check(self.clock.now()) {
+                    if !self.timers.iter().any(|(_, t)| *t == Timer::SendQueue) {
+                        trace!("holding back multicast for {:?}", wait);
+                        let at = self.clock.now() + wait;
+                        self.timers.push((at, Timer::SendQueue));
+                    }
+                    next += 1;
+                    continue;
+                }
+            }
+            trace!("sending packet to {:?}", datagram.addr);
+
+            // Through the socket of the interface it is meant for, if any
+            let iface_sockets = &mut self.iface_sockets;
+            let via = match datagram.via {
+                Some(ref via) => iface_sockets.iter_mut().find(|(name, _)| name == via),
+                None => None,
+            };
+            let socket = match via {
+                Some((_, socket)) => socket,
+                None => self.socket.as_mut().expect("socket not bound"),
+            };
+            let sent = match (datagram.out_of, datagram.source) {
+                (Some(interface), source) => {
+                    socket.poll_send_out_of(cx, &datagram.packet, &datagram.addr, interface, source)
+                }
+                (None, Some(source)) => {
+                    socket.poll_send_from(cx, &datagram.packet, &datagram.addr, source)
+                }
+                (None, None) => socket.poll_send_to(cx, &datagram.packet, &datagram.addr),
+            };
+            let outcome = match sent {
+                Poll::Ready(Ok(v)) if v == datagram.packet.len() => SendOutcome::Sent,
+                Poll::Ready(Ok(_)) => {
+                    warn!("failed to send entire datagram");
+                    SendOutcome::Failed
+                }
+                Poll::Ready(Err(ref ioerr)) if ioerr.kind() == WouldBlock => {
+                    SendOutcome::WouldBlock
+                }
+                Poll::Ready(Err(err)) => {
+                    warn!("error sending packet {:?}", err);
+                    SendOutcome::Failed
+                }
+                // Stays queued, we get woken once the socket is writable
+                Poll::Pending => break,
+            };
+
+            let datagram = self.outgoing.remove(next).unwrap();
+            if let (true, Some(budget)) = (multicast, &mut self.multicast_budget) {
+                budget.take();
+            }
+            let now = self.clock.now();
+            for name in &datagram.announces {
+                self.stats.record_announcement(name, outcome, now);
+            }
+        }
+    }
+}
+
+/// Orders instances sharing a name the way RFC 2782 has clients pick among
+/// SRV records: by priority, and within a priority at random in proportion
+/// to their weights, so clients taking the first record spread the load
+fn srv_order<'a>(mut svcs: Vec<&'a ServiceData>, rng: &mut impl Rng) -> Vec<&'a ServiceData> {
+    if svcs.len() < 2 {
+        return svcs;
+    }
+    // Drawing from 1 rather than 0 as in the RFC avoids favouring the first
+    // record, at the cost of weight 0 only being picked once there's nothing
+    // else left at its priority
+    svcs.sort_by_key(|svc| (svc.priority, svc.weight));
+    let mut ordered = Vec::with_capacity(svcs.len());
+    while !svcs.is_empty() {
+        let priority = svcs[0].priority;
+        let len = svcs
+            .iter()
+            .take_while(|svc| svc.priority == priority)
+            .count();
+        let total: u32 = svcs[..len].iter().map(|svc| u32::from(svc.weight)).sum();
+        let pick = if total == 0 {
+            0
+        } else {
+            rng.gen_range(1, total + 1)
+        };
+        let mut sum = 0;
+        let index = svcs[..len]
+            .iter()
+            .position(|svc| {
+                sum += u32::from(svc.weight);
+                sum >= pick
+            })
+            .unwrap_or(0);
+        ordered.push(svcs.remove(index));
+    }
+    ordered
+}
+
+/// The address records to send along with `svcs`: those of the families
+/// any of them is advertised on
+fn address_type(svcs: &[&ServiceData]) -> QueryType {
+    let v4 = svcs.iter().any(|svc| svc.families.allows(false));
+    let v6 = svcs.iter().any(|svc| svc.families.allows(true));
+    match (v4, v6) {
+        (true, false) => QueryType::A,
+        (false, true) => QueryType::AAAA,
+        _ => QueryType::All,
+    }
+}
+
+/// Record data in uncompressed wire format, as compared by the tiebreak
+fn rdata_bytes(data: &RRData) -> Vec<u8> {
+    let mut buf = Vec::new();
+    data.write_to(&mut buf).unwrap();
+    buf
+}
+
+/// Whether a query's header has any of the flags `Config::strict_query_flags`
+/// drops it for
+fn has_disallowed_flags(header: &dns_parser::Header) -> bool {
+    header.authoritative
+        || header.recursion_desired
+        || header.recursion_available
+        || header.opcode != dns_parser::Opcode::StandardQuery
+        || header.response_code != dns_parser::ResponseCode::NoError
+}
+
+/// Whether receiving failed for the moment only, e.g. interrupted by a
+/// signal or, on Windows, reset by an ICMP error about something we sent,
+/// so the next datagram can be received right away
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+    )
+}
+
+impl<AF: Unpin + AddressFamily> Future for FSM<AF> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let pinned = Pin::get_mut(self);
+        while let Poll::Ready(cmd) = Pin::new(&mut pinned.commands).poll_next(cx) {
+            match cmd {
+                Some(Command::Shutdown) => return Poll::Ready(()),
+                Some(Command::Drain(done)) => pinned.drain(done),
+                Some(Command::AnnounceOnce { svc, goodbye_after }) => {
+                    pinned.send_unsolicited(&svc, None, true);
+                    if let Some(after) = goodbye_after {
+                        let key = pinned.next_goodbye;
+                        pinned.next_goodbye += 1;
+                        pinned.goodbyes.insert(key, svc);
+                        pinned.schedule(after, Timer::Goodbye(key));
+                    }
+                }
+                Some(Command::SetObserveOnly(observe_only)) => pinned.observe_only = observe_only,
+                Some(Command::SetHostname { name, previous }) => {
+                    pinned.set_hostname(name, &previous)
+                }
+                Some(Command::SendUnsolicited {
+                    svc,
+                    ttl,
+                    include_ip,
+                }) => {
+                    if ttl != Some(0)
+                        && (pinned.defer_announcement(vec![svc.name.clone()])
+                            || pinned.probe(vec![svc.name.clone()]))
+                    {
+                        continue;
+                    }
+                    pinned.send_unsolicited(&svc, ttl, include_ip);
+                    if ttl != Some(0) {
+                        pinned.schedule_refresh(&svc.name);
+                    }
+                }
+                Some(Command::SendUnsolicitedBatch {
+                    svcs,
+                    ttl,
+                    include_ip,
+                }) => {
+                    let names: Vec<_> = svcs.iter().map(|svc| svc.name.clone()).collect();
+                    if ttl != Some(0)
+                        && (pinned.defer_announcement(names.clone()) || pinned.probe(names))
+                    {
+                        continue;
+                    }
+                    pinned.send_unsolicited_batch(&svcs, ttl, include_ip);
+                    if ttl != Some(0) {
+                        for svc in &svcs {
+                            pinned.schedule_refresh(&svc.name);
+                        }
+                    }
+                }
+                Some(Command::Hide(svc)) => pinned.send_ptr_goodbye(&svc),
+                Some(Command::Reschedule { name, previous }) => {
+                    pinned.reschedule(&name, previous);
+                }
+                // Polling a closed channel only yields `None` again
+                None if pinned.draining.is_some() => break,
+                None if pinned.config.goodbye_on_disconnect => {
+                    warn!("responder disconnected without shutdown, draining");
+                    pinned.drain(oneshot::channel().0);
+                    break;
+                }
+                None => {
+                    warn!("responder disconnected without shutdown");
+                    return Poll::Ready(());
+                }
+            }
+        }
+
+        pinned.fire_timers();
+
+        match pinned.recv_packets(cx) {
+            Ok(_) => (),
+            Err(e) => error!("ResponderRecvPacket Error: {:?}", e),
+        }
+
+        pinned.send_packets(cx);
+        pinned
+            .stats
+            .set_queue_depth(AF::v6(), pinned.outgoing.len());
+
+        let repeating = |(_, timer): &(Instant, Timer)| matches!(timer, Timer::RepeatGoodbye(..));
+        if pinned.draining.is_some()
+            && pinned.outgoing.is_empty()
+            && !pinned.timers.iter().any(repeating)
+        {
+            debug!("drained send queue, shutting down");
+            let _ = pinned.draining.take().unwrap().send(());
+            return Poll::Ready(());
+        }
+
+        pinned.poll_wakeup(cx);
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Command, InterfaceBinder, FSM, MAX_QUERIERS, STALE_ADDRESS_TTL};
+    use crate::address_family::{AddressFamily, Inet, Inet6};
+    use crate::clock::{Clock, MockClock};
+    use crate::config::{
+        Config, Conflict, ConflictObserver, ConflictResolution, HostnameRewrite, NoAddresses,
+        ObservedQuestion, OutgoingFilter, PacketTap, Placement, PtrInstances, PtrResponse,
+        QueryObserver, RateLimit, Schedule, Section, Ttls,
+    };
+    use crate::dns_parser::{
+        self, Name, Packet, QueryClass, QueryType, RRData, ResourceRecord, Type,
+    };
+    use crate::interfaces::MockInterfaces;
+    use crate::services::{
+        self, encode_txt, Families, RawRecord, ServiceData, ServiceRecord, Services, ServicesInner,
+        SharedServices, TxtFn,
+    };
+    use crate::socket::{Join, MockSocket, MulticastIf};
+    use crate::stats::{SendOutcome, SharedStats, Stats};
+    use futures_util::task::noop_waker_ref;
+    use get_if_addrs::{IfAddr, Ifv4Addr, Ifv6Addr, Interface};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+    use std::future::Future;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::Context;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+    use tokio::sync::{mpsc, oneshot};
+
+    pub fn iface(name: &str, ip: &str) -> Interface {
+        let addr = match ip.parse().unwrap() {
+            IpAddr::V4(ip) => IfAddr::V4(Ifv4Addr {
+                ip,
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                broadcast: None,
+            }),
+            IpAddr::V6(ip) => IfAddr::V6(Ifv6Addr {
+                ip,
+                netmask: Ipv6Addr::new(0xffff, 0xffff, 0xffff, 0xffff, 0, 0, 0, 0),
+                broadcast: None,
+            }),
+        };
+        Interface {
+            name: name.to_owned(),
+            addr,
+        }
+    }
+
+    pub struct Harness<AF: AddressFamily> {
+        pub fsm: FSM<AF>,
+        pub socket: MockSocket,
+        pub clock: Arc<MockClock>,
+        pub services: Services,
+        pub stats: SharedStats,
+        pub interfaces: MockInterfaces,
+        /// The socket of each interface, with `Config::socket_per_interface`
+        pub iface_sockets: Arc<Mutex<HashMap<String, MockSocket>>>,
+        /// Keeps the FSM from seeing a disconnected responder
+        pub commands: mpsc::UnboundedSender<Command>,
+    }
+
+    impl<AF: AddressFamily + Unpin> Harness<AF> {
+        pub fn new(config: Config, interfaces: Vec<Interface>) -> Self {
+            Self::with_bind_failures(config, interfaces, 0)
+        }
+
+        /// Builds a harness whose first `failures` attempts to bind fail
+        pub fn with_bind_failures(
+            config: Config,
+            interfaces: Vec<Interface>,
+            failures: usize,
+        ) -> Self {
+            Self::with_mock_socket(config, interfaces, failures, MockSocket::new())
+        }
+
+        /// Builds a harness around `socket`, e.g. one set up to fail
+        pub fn with_mock_socket(
+            config: Config,
+            interfaces: Vec<Interface>,
+            failures: usize,
+            socket: MockSocket,
+        ) -> Self {
+            let clock = Arc::new(MockClock::new());
+            let services = Arc::new(SharedServices::new(ServicesInner::new(
+                "test-host.local".to_owned(),
+            )));
+            let stats = Arc::new(Stats::new(&config));
+            let interfaces = MockInterfaces::new(interfaces);
+            let bound = socket.clone();
+            let attempts = AtomicUsize::new(0);
+            let iface_sockets = Arc::new(Mutex::new(HashMap::new()));
+            let iface_binder = if config.socket_per_interface {
+                let sockets = iface_sockets.clone();
+                let binder: InterfaceBinder = Box::new(move |iface: &Interface| {
+                    let socket = MockSocket::new();
+                    let mut sockets = sockets.lock().unwrap();
+                    sockets.insert(iface.name.clone(), socket.clone());
+                    Ok(Box::new(socket))
+                });
+                Some(binder)
+            } else {
+                None
+            };
+            let (fsm, commands) = FSM::with_binder(
+                Box::new(move || {
+                    if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                        Err(io::Error::from(io::ErrorKind::AddrNotAvailable))
+                    } else {
+                        Ok(Box::new(bound.clone()))
+                    }
+                }),
+                iface_binder,
+                &services,
+                &Arc::new(config),
+                &stats,
+                clock.clone(),
+                Box::new(interfaces.clone()),
+            )
+            .unwrap();
+
+            Harness {
+                fsm,
+                socket,
+                clock,
+                services,
+                stats,
+                interfaces,
+                iface_sockets,
+                commands,
+            }
+        }
+
+        pub fn register(&self, name: &str, typ: &str) -> usize {
+            services::write(&self.services).register(service(name, typ))
+        }
+
+        pub fn receive(&mut self, packet: &[u8], from: &str) {
+            self.fsm
+                .handle_packet(packet, from.parse().unwrap(), None, None);
+        }
+
+        pub fn take_outgoing(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+            self.fsm
+                .outgoing
+                .drain(..)
+                .map(|datagram| (datagram.packet, datagram.addr))
+                .collect()
+        }
+
+        pub fn poll(&mut self) {
+            let mut cx = Context::from_waker(noop_waker_ref());
+            let _ = Pin::new(&mut self.fsm).poll(&mut cx);
+        }
+    }
+
+    pub fn service(name: &str, typ: &str) -> ServiceData {
+        ServiceData {
+            name: Name::from_str(format!("{}.{}.local", name, typ)).unwrap(),
+            typ: Name::from_str(format!("{}.local", typ)).unwrap(),
+            port: 80,
+            txt: encode_txt(&["path=/"]),
+            txt_fn: None,
+            subnets: None,
+            records: Vec::new(),
+            private_txt: Vec::new(),
+            priority: 0,
+            weight: 0,
+            enabled: true,
+            schedule: None,
+            discoverable: true,
+            suppressed: Vec::new(),
+            families: Families::Both,
+            queries: None,
+        }
+    }
+
+    pub fn query(questions: &[(&str, QueryType)]) -> Vec<u8> {
+        let mut builder = dns_parser::Builder::new_query(0, false);
+        builder.set_max_size(None);
+        for &(name, qtype) in questions {
+            let name = Name::from_str(name.to_owned()).unwrap();
+            builder = builder.add_question(&name, qtype, QueryClass::IN);
+        }
+        builder.build().unwrap()
+    }
+
+    /// Types of the records in the answer section of a response
+    pub fn answer_types(packet: &[u8]) -> Vec<Type> {
+        let packet = Packet::parse(packet).unwrap();
+        packet.answers.iter().map(|rr| rr.data.typ()).collect()
+    }
+
+    /// Announces newly registered services right away
+    fn without_jitter() -> Config {
+        Config {
+            announce_jitter: Duration::from_secs(0),
+            ..Config::default()
+        }
+    }
+
+    /// Types of the records in the answer and then the additional section
+    pub fn record_types(packet: &[u8]) -> Vec<Type> {
+        let packet = Packet::parse(packet).unwrap();
+        let records = packet.answers.iter().chain(&packet.additional);
+        records.map(|rr| rr.data.typ()).collect()
+    }
+
+    fn group_v4() -> Ipv4Addr {
+        Ipv4Addr::new(224, 0, 0, 251)
+    }
+
+    #[test]
+    fn joins_every_interface() {
+        let harness = Harness::<Inet>::new(
+            Config::default(),
+            vec![
+                iface("lo", "127.0.0.1"),
+                iface("eth0", "10.0.0.2"),
+                iface("eth0", "fe80::1"),
+                iface("wlan0", "192.168.1.2"),
+            ],
+        );
+
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![
+                Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2)),
+                Join::V4(group_v4(), Ipv4Addr::new(192, 168, 1, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn joins_only_configured_interfaces() {
+        let config = Config {
+            interfaces: Some(vec!["wlan0".to_owned()]),
+            ..Config::default()
+        };
+        let harness = Harness::<Inet>::new(
+            config,
+            vec![iface("eth0", "10.0.0.2"), iface("wlan0", "192.168.1.2")],
+        );
+
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![Join::V4(group_v4(), Ipv4Addr::new(192, 168, 1, 2))]
+        );
+    }
+
+    #[test]
+    fn leaves_out_excluded_interfaces() {
+        let config = Config {
+            excluded_interfaces: Some(vec!["tun0".to_owned()]),
+            ..Config::default()
+        };
+        let interfaces = vec![iface("eth0", "10.0.0.2"), iface("tun0", "10.8.0.2")];
+        let mut harness = Harness::<Inet>::new(config, interfaces);
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2))]
+        );
+
+        harness.receive(
+            &query(&[("test-host.local", QueryType::A)]),
+            "10.0.0.5:5353",
+        );
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let addresses: Vec<_> = response
+            .answers
+            .iter()
+            .map(|rr| match rr.data {
+                RRData::A(ip) => ip,
+                ref data => panic!("unexpected {:?}", data),
+            })
+            .collect();
+        assert_eq!(addresses, vec![Ipv4Addr::new(10, 0, 0, 2)]);
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn leaves_out_special_interfaces_by_default() {
+        let interfaces = vec![iface("en0", "10.0.0.2"), iface("awdl0", "169.254.7.1")];
+        let harness = Harness::<Inet>::new(Config::default(), interfaces.clone());
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2))]
+        );
+
+        let config = Config {
+            include_special_interfaces: true,
+            ..Config::default()
+        };
+        let harness = Harness::<Inet>::new(config, interfaces);
+        assert_eq!(harness.socket.state().joins.len(), 2);
+    }
+
+    #[test]
+    fn answers_from_socket_of_arrival_interface() {
+        let config = Config {
+            socket_per_interface: true,
+            ..without_jitter()
+        };
+        let interfaces = vec![iface("eth0", "10.0.0.2"), iface("eth1", "10.1.0.2")];
+        let mut harness = Harness::<Inet>::new(config, interfaces);
+        assert!(harness.socket.state().joins.is_empty());
+        let sockets = harness.iface_sockets.lock().unwrap().clone();
+        for (name, ip) in &[
+            ("eth0", Ipv4Addr::new(10, 0, 0, 2)),
+            ("eth1", Ipv4Addr::new(10, 1, 0, 2)),
+        ] {
+            let state = sockets[*name].state();
+            assert_eq!(state.joins, vec![Join::V4(group_v4(), *ip)]);
+            assert_eq!(state.multicast_if, Some(MulticastIf::V4(*ip)));
+        }
+
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let from = "10.1.0.5:5353".parse().unwrap();
+        sockets["eth1"]
+            .state()
+            .incoming
+            .push_back(Ok((packet, from)));
+        harness.poll();
+        let sent = sockets["eth1"].state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, SocketAddr::new(group_v4().into(), 5353));
+        assert_eq!(answer_types(&sent[0].0), vec![Type::PTR]);
+        assert!(sockets["eth0"].state().sent.is_empty());
+        assert!(harness.socket.state().sent.is_empty());
+
+        // Announcements go out of every interface
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: service("Test", "_http._tcp"),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        assert_eq!(sockets["eth0"].state().sent.len(), 1);
+        assert_eq!(sockets["eth1"].state().sent.len(), 2);
+        assert!(harness.socket.state().sent.is_empty());
+    }
+
+    #[test]
+    fn joins_interfaces_that_appear_later() {
+        let config = Config::default();
+        let interval = config.interface_refresh_interval;
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+
+        harness.interfaces.lock().push(iface("eth1", "10.1.0.2"));
+        harness.poll();
+        assert_eq!(harness.socket.state().joins.len(), 1);
+
+        harness.clock.advance(interval);
+        harness.poll();
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![
+                Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2)),
+                Join::V4(group_v4(), Ipv4Addr::new(10, 1, 0, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_queriers_past_the_most_tracked_as_one() {
+        let config = Config {
+            rate_limit: Some(RateLimit {
+                window: Duration::from_secs(1),
+                soft_limit: 1,
+                hard_limit: 1,
+            }),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        // Legacy queriers, whose unicast responses can't be coalesced
+        for i in 0..MAX_QUERIERS {
+            let addr = format!("10.1.{}.{}:40000", i / 256, i % 256);
+            harness.receive(&packet, &addr);
+        }
+        assert_eq!(harness.take_outgoing().len(), MAX_QUERIERS);
+
+        harness.receive(&packet, "10.2.0.1:40000");
+        assert_eq!(harness.take_outgoing().len(), 1);
+        harness.receive(&packet, "10.2.0.2:40000");
+        assert!(harness.take_outgoing().is_empty());
+
+        // Until the window is over and they can be forgotten
+        harness.clock.advance(Duration::from_secs(1));
+        harness.receive(&packet, "10.2.0.2:40000");
+        assert_eq!(harness.take_outgoing().len(), 1);
+    }
+
+    #[test]
+    fn rate_limited_querier_gets_trimmed_then_dropped() {
+        let config = Config {
+            rate_limit: Some(RateLimit {
+                window: Duration::from_secs(1),
+                soft_limit: 2,
+                hard_limit: 3,
+            }),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let full = vec![Type::PTR, Type::SRV, Type::TXT, Type::A];
+
+        for _ in 0..2 {
+            harness.receive(&packet, "10.0.0.9:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(outgoing.len(), 1);
+            assert_eq!(record_types(&outgoing[0].0), full);
+        }
+
+        harness.receive(&packet, "10.0.0.9:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::PTR]);
+
+        harness.receive(&packet, "10.0.0.9:5353");
+        assert!(harness.take_outgoing().is_empty());
+        // Nor does switching ports help
+        harness.receive(&packet, "10.0.0.9:5354");
+        assert!(harness.take_outgoing().is_empty());
+
+        // Other queriers are unaffected
+        harness.receive(&packet, "10.0.0.10:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(record_types(&outgoing[0].0), full);
+
+        // And the limit resets with the window
+        harness.clock.advance(Duration::from_secs(1));
+        harness.receive(&packet, "10.0.0.9:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(record_types(&outgoing[0].0), full);
+    }
+
+    #[test]
+    fn includes_both_families_when_configured() {
+        let interfaces = vec![iface("eth0", "10.0.0.2"), iface("eth0", "fe80::2")];
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+
+        let mut harness = Harness::<Inet>::new(Config::default(), interfaces.clone());
+        harness.register("Test", "_http._tcp");
+        harness.receive(&packet, "10.0.0.9:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(
+            record_types(&outgoing[0].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+
+        let config = Config {
+            include_both_families: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, interfaces);
+        harness.register("Test", "_http._tcp");
+        harness.receive(&packet, "10.0.0.9:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing[0].1, "224.0.0.251:5353".parse().unwrap());
+        assert_eq!(
+            record_types(&outgoing[0].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A, Type::AAAA]
+        );
+    }
+
+    #[test]
+    fn refreshes_announcements_before_ttl_expires() {
+        let config = Config {
+            refresh_fraction: Some(0.8),
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: service("Test", "_http._tcp"),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+
+        // 80% of the shortest TTL, the SRV and TXT records' 120 seconds
+        harness.clock.advance(Duration::from_secs(95));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+        harness.clock.advance(Duration::from_secs(1));
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(answer_types(&sent[1].0), answer_types(&sent[0].0));
+    }
+
+    #[test]
+    fn announces_once_deferred_bind_succeeds() {
+        let config = Config {
+            bind_retry_interval: Some(Duration::from_secs(5)),
+            ..Config::default()
+        };
+        let mut harness =
+            Harness::<Inet>::with_bind_failures(config, vec![iface("eth0", "10.0.0.2")], 2);
+        harness.register("Test", "_http._tcp");
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: service("Test", "_http._tcp"),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        assert!(harness.socket.state().joins.is_empty());
+        assert!(harness.socket.state().sent.is_empty());
+
+        // Second attempt fails too
+        harness.clock.advance(Duration::from_secs(5));
+        harness.poll();
+        assert!(harness.socket.state().sent.is_empty());
+
+        harness.clock.advance(Duration::from_secs(5));
+        harness.poll();
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2))]
+        );
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, SocketAddr::new(group_v4().into(), 5353));
+        assert_eq!(
+            answer_types(&sent[0].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+    }
+
+    #[test]
+    fn answers_hostname_in_short_and_full_forms() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+
+        for name in &["test-host.local", "test-host.local.", "test-host"] {
+            harness.receive(&query(&[(name, QueryType::A)]), "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(outgoing.len(), 1, "no answer for {}", name);
+            assert_eq!(answer_types(&outgoing[0].0), vec![Type::A]);
+        }
+        // Under the name it was asked for
+        harness.receive(&query(&[("test-host", QueryType::A)]), "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert_eq!(response.answers[0].name.to_string(), "test-host");
+
+        for name in &["test", "test-host.lan", "other.test-host.local"] {
+            harness.receive(&query(&[(name, QueryType::A)]), "10.0.0.5:5353");
+            assert!(harness.take_outgoing().is_empty(), "answered {}", name);
+        }
+    }
+
+    #[test]
+    fn defers_first_announcement_by_jitter() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.fsm.rng = RefCell::new(StdRng::seed_from_u64(7));
+        let delay = Duration::from_millis(StdRng::seed_from_u64(7).gen_range(0, 251));
+        harness.register("Test", "_http._tcp");
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: service("Test", "_http._tcp"),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        assert!(harness.socket.state().sent.is_empty());
+
+        harness.clock.advance(delay - Duration::from_millis(1));
+        harness.poll();
+        assert!(harness.socket.state().sent.is_empty());
+        harness.clock.advance(Duration::from_millis(1));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+
+        // Goodbyes are never held back
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: service("Test", "_http._tcp"),
+                ttl: Some(0),
+                include_ip: false,
+            })
+            .unwrap();
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 2);
+    }
+
+    #[test]
+    fn coalesces_batch_announcements() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        let svcs: Vec<_> = (0..5)
+            .map(|i| service(&format!("Test {}", i), "_http._tcp"))
+            .collect();
+        harness
+            .commands
+            .send(Command::SendUnsolicitedBatch {
+                svcs,
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        let types = answer_types(&sent[0].0);
+        assert_eq!(types.iter().filter(|t| **t == Type::PTR).count(), 5);
+        assert_eq!(types.last(), Some(&Type::A));
+    }
+
+    #[test]
+    fn splits_batch_announcements_that_dont_fit() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        let svcs: Vec<_> = (0..40)
+            .map(|i| service(&format!("Test {}", i), "_http._tcp"))
+            .collect();
+        harness
+            .commands
+            .send(Command::SendUnsolicitedBatch {
+                svcs,
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+
+        let sent = harness.socket.state().sent.clone();
+        assert!(sent.len() > 1 && sent.len() < 40);
+        assert!(sent.iter().all(|(packet, _)| packet.len() <= 1440));
+        let ptrs: usize = sent
+            .iter()
+            .map(|(packet, _)| {
+                let types = answer_types(packet);
+                types.iter().filter(|t| **t == Type::PTR).count()
+            })
+            .sum();
+        assert_eq!(ptrs, 40);
+    }
+
+    #[test]
+    fn keeps_answering_after_services_lock_is_poisoned() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let id = harness.register("Test", "_http._tcp");
+
+        let services = harness.services.clone();
+        let _ = std::thread::spawn(move || {
+            let mut guard = services.write();
+            guard.set_enabled(id, false);
+            panic!("poisoning the services lock");
+        })
+        .join();
+        assert!(harness.services.is_poisoned());
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(answer_types(&outgoing[0].0)[0], Type::PTR);
+    }
+
+    #[test]
+    fn drain_says_goodbye_before_signalling() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+
+        let (done, mut drained) = oneshot::channel();
+        harness.commands.send(Command::Drain(done)).unwrap();
+        assert!(drained.try_recv().is_err());
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut harness.fsm).poll(&mut cx).is_ready());
+        assert!(drained.try_recv().is_ok());
+
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        let packet = Packet::parse(&sent[0].0).unwrap();
+        assert!(!packet.answers.is_empty());
+        assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+    }
+
+    #[test]
+    fn says_goodbye_when_responder_disconnects() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.poll();
+        harness.socket.state().sent.clear();
+
+        let (commands, _) = mpsc::unbounded_channel();
+        drop(std::mem::replace(&mut harness.commands, commands));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut harness.fsm).poll(&mut cx).is_ready());
+
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        let packet = Packet::parse(&sent[0].0).unwrap();
+        assert!(!packet.answers.is_empty());
+        assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+    }
+
+    #[test]
+    fn stops_right_away_on_disconnect_if_configured() {
+        let config = Config {
+            goodbye_on_disconnect: false,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.poll();
+        harness.socket.state().sent.clear();
+
+        let (commands, _) = mpsc::unbounded_channel();
+        drop(std::mem::replace(&mut harness.commands, commands));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut harness.fsm).poll(&mut cx).is_ready());
+        assert!(harness.socket.state().sent.is_empty());
+    }
+
+    #[test]
+    fn repeats_goodbyes_as_configured() {
+        let config = Config {
+            goodbye_count: 3,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let id = harness.register("Test", "_http._tcp");
+        let svc = services::write(&harness.services).unregister(id);
+        harness.fsm.send_unsolicited(&svc, Some(0), false);
+        assert_eq!(harness.take_outgoing().len(), 1);
+
+        let sent = |harness: &Harness<Inet>| harness.socket.state().sent.clone();
+        for repeat in 1..=2 {
+            harness.clock.advance(Duration::from_millis(200));
+            harness.poll();
+            assert_eq!(sent(&harness).len(), repeat - 1);
+            harness.clock.advance(Duration::from_millis(50));
+            harness.poll();
+            let sent = sent(&harness);
+            assert_eq!(sent.len(), repeat);
+            let packet = Packet::parse(&sent[repeat - 1].0).unwrap();
+            assert!(!packet.answers.is_empty());
+            assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+        }
+
+        harness.clock.advance(Duration::from_secs(1));
+        harness.poll();
+        assert_eq!(sent(&harness).len(), 2);
+    }
+
+    #[test]
+    fn announcing_again_cancels_repeated_goodbyes() {
+        let config = Config {
+            goodbye_count: 3,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let id = harness.register("Test", "_http._tcp");
+        let svc = services::write(&harness.services).unregister(id);
+        harness.fsm.send_unsolicited(&svc, Some(0), false);
+        harness.fsm.send_unsolicited(&svc, None, true);
+        assert_eq!(harness.take_outgoing().len(), 2);
+
+        harness.clock.advance(Duration::from_secs(2));
+        harness.poll();
+        for (packet, _) in &harness.socket.state().sent {
+            let packet = Packet::parse(packet).unwrap();
+            assert!(packet.answers.iter().all(|rr| rr.ttl != 0));
+        }
+    }
+
+    #[test]
+    fn drain_waits_for_repeated_goodbyes() {
+        let config = Config {
+            goodbye_count: 2,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.poll();
+        harness.socket.state().sent.clear();
+
+        let (done, mut drained) = oneshot::channel();
+        harness.commands.send(Command::Drain(done)).unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut harness.fsm).poll(&mut cx).is_pending());
+        assert!(drained.try_recv().is_err());
+        assert_eq!(harness.socket.state().sent.len(), 1);
+
+        harness.clock.advance(Duration::from_millis(250));
+        assert!(Pin::new(&mut harness.fsm).poll(&mut cx).is_ready());
+        assert!(drained.try_recv().is_ok());
+        assert_eq!(harness.socket.state().sent.len(), 2);
+    }
+
+    #[test]
+    fn clamps_ttls_for_legacy_unicast_queriers() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+
+        harness.receive(&packet, "10.0.0.5:49152");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].1, "10.0.0.5:49152".parse().unwrap());
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(
+            record_types(&outgoing[0].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+        assert!(response.answers.iter().all(|rr| rr.ttl == 10));
+
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].1, SocketAddr::new(group_v4().into(), 5353));
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let ttls: Vec<_> = response.answers.iter().map(|rr| rr.ttl).collect();
+        assert_eq!(ttls, vec![Ttls::default().ptr]);
+
+        // However long legacy queriers may cache, never past our own TTLs
+        let config = Config {
+            legacy_unicast_ttl: 60,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.receive(&packet, "10.0.0.5:49152");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert!(response
+            .answers
+            .iter()
+            .chain(&response.additional)
+            .all(|rr| rr.ttl == 60));
+    }
+
+    #[test]
+    fn echoes_questions_to_legacy_queriers_only() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let qm = dns_parser::Builder::new_query(4242, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        // The same with the QU bit set on the question's class
+        let mut qu = qm.clone();
+        let class = qu.len() - 2;
+        qu[class] |= 0x80;
+
+        harness.receive(&qm, "10.0.0.5:49152");
+        let legacy = harness.take_outgoing();
+        let response = Packet::parse(&legacy[0].0).unwrap();
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(
+            response.questions[0].qname,
+            Name::from_str("_http._tcp.local").unwrap()
+        );
+        assert_eq!(response.questions[0].qtype, QueryType::PTR);
+
+        for (packet, to) in [
+            (qm, SocketAddr::new(group_v4().into(), 5353)),
+            (qu, "10.0.0.5:5353".parse().unwrap()),
+        ] {
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(outgoing[0].1, to);
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            assert_eq!(response.header.id, 0);
+            assert!(response.questions.is_empty());
+            assert!(!response.answers.is_empty());
+        }
+    }
+
+    #[test]
+    fn tracks_recent_queriers() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let first: SocketAddr = "10.0.0.5:5353".parse().unwrap();
+        let second: SocketAddr = "10.0.0.6:5353".parse().unwrap();
+
+        harness.receive(&packet, "10.0.0.5:5353");
+        harness.clock.advance(Duration::from_secs(30));
+        harness.receive(&packet, "10.0.0.5:5353");
+        harness.receive(&packet, "10.0.0.6:5353");
+        let now = harness.clock.now();
+        assert_eq!(
+            harness.stats.recent_queriers(now),
+            vec![(first, 2, now), (second, 1, now)]
+        );
+
+        harness.clock.advance(Duration::from_secs(45));
+        harness.receive(&packet, "10.0.0.6:5353");
+        let later = harness.clock.now();
+        assert_eq!(
+            harness.stats.recent_queriers(later),
+            vec![(first, 2, now), (second, 2, later)]
+        );
+
+        // Both quiet for longer than the window
+        harness.clock.advance(Duration::from_secs(61));
+        assert_eq!(harness.stats.recent_queriers(harness.clock.now()), vec![]);
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_queriers() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+
+        // Each from a port of its own, all within the window
+        for port in 10000..12000 {
+            harness.receive(&packet, &format!("10.0.0.5:{}", port));
+            harness.clock.advance(Duration::from_millis(1));
+        }
+
+        let recent = harness.stats.recent_queriers(harness.clock.now());
+        assert_eq!(recent.len(), 1024);
+        let newest: SocketAddr = "10.0.0.5:11999".parse().unwrap();
+        let oldest_kept: SocketAddr = "10.0.0.5:10976".parse().unwrap();
+        assert_eq!(recent[0].0, oldest_kept);
+        assert_eq!(recent[1024 - 1].0, newest);
+    }
+
+    #[test]
+    fn ignores_trailing_dot_mismatch() {
+        let registered_with_dot = ServiceData {
+            name: Name::from_str("Test._http._tcp.local.").unwrap(),
+            typ: Name::from_str("_http._tcp.local.").unwrap(),
+            ..service("Test", "_http._tcp")
+        };
+        let cases = vec![
+            // Registered with the root label spelled out, queried without
+            (registered_with_dot, ""),
+            // and the other way round
+            (service("Test", "_http._tcp"), "."),
+        ];
+        for (svc, suffix) in cases {
+            let mut harness =
+                Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+            harness.services.write().register(svc);
+
+            let ptr = format!("_http._tcp.local{}", suffix);
+            harness.receive(&query(&[(&ptr, QueryType::PTR)]), "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(answer_types(&outgoing[0].0)[0], Type::PTR);
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            match response.answers[0].data {
+                RRData::PTR(ref name) => assert_eq!(name.to_string(), "Test._http._tcp.local"),
+                ref data => panic!("unexpected {:?}", data),
+            }
+
+            let srv = format!("Test._http._tcp.local{}", suffix);
+            harness.receive(&query(&[(&srv, QueryType::SRV)]), "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(answer_types(&outgoing[0].0)[0], Type::SRV);
+        }
+    }
+
+    #[test]
+    fn suppresses_records_known_from_additionals() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let name = Name::from_str("Test._http._tcp.local").unwrap();
+        let srv = RRData::SRV {
+            priority: 0,
+            weight: 0,
+            port: 80,
+            target: Name::from_str("test-host.local").unwrap(),
+        };
+        let known = |ttl| {
+            dns_parser::Builder::new_query(0, false)
+                .add_question(&name, QueryType::SRV, QueryClass::IN)
+                .move_to::<dns_parser::Additional>()
+                .add_additional(&name, QueryClass::IN, ttl, &srv)
+                .build()
+                .unwrap()
+        };
+
+        let full = Ttls::default().srv;
+        harness.receive(&known(full), "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+
+        // The querier's copy is about to expire, so it gets a fresh one
+        harness.receive(&known(full / 2 - 1), "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0)[0], Type::SRV);
+    }
+
+    #[test]
+    fn picks_shared_name_instances_by_weight() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.fsm.rng = RefCell::new(StdRng::seed_from_u64(7));
+        for &(port, weight) in &[(8001, 1), (8003, 3)] {
+            let mut svc = service("Test", "_http._tcp");
+            svc.port = port;
+            svc.weight = weight;
+            harness.services.write().register(svc);
+        }
+
+        let packet = query(&[("Test._http._tcp.local", QueryType::SRV)]);
+        let mut heavier_first = 0;
+        for _ in 0..4000 {
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            let ports: Vec<_> = response
+                .answers
+                .iter()
+                .filter_map(|rr| match rr.data {
+                    RRData::SRV { port, .. } => Some(port),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(ports.len(), 2);
+            if ports[0] == 8003 {
+                heavier_first += 1;
+            }
+        }
+        // 3000 expected, with a standard deviation of about 27
+        assert!((2800..3200).contains(&heavier_first), "{}", heavier_first);
+    }
+
+    #[test]
+    fn shared_name_instances_dont_conflict_with_each_other() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        for &port in &[8001, 8003] {
+            let mut svc = service("Test", "_http._tcp");
+            svc.port = port;
+            harness.services.write().register(svc);
+        }
+        let svcs: Vec<_> = {
+            let services = harness.services.read();
+            services.all().cloned().collect()
+        };
+        for svc in &svcs {
+            harness.fsm.send_unsolicited(svc, None, true);
+        }
+        for (packet, _) in harness.take_outgoing() {
+            harness.receive(&packet, "10.0.0.2:5353");
+        }
+        assert!(harness.take_outgoing().is_empty());
+        assert_eq!(harness.services.read().all().count(), 2);
+        let name = Name::from_str("Test._http._tcp.local").unwrap();
+        assert_eq!(harness.services.read().find_all_by_name(&name).count(), 2);
+    }
+
+    #[test]
+    fn ignores_zone_probes() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        for &(name, qtype) in &[("local.", QueryType::SOA), ("local", QueryType::NS)] {
+            harness.receive(&query(&[(name, qtype)]), "10.0.0.5:5353");
+            assert!(harness.take_outgoing().is_empty());
+        }
+
+        let packet = query(&[
+            ("local", QueryType::SOA),
+            ("_http._tcp.local", QueryType::PTR),
+        ]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0)[0], Type::PTR);
+    }
+
+    #[test]
+    fn composes_ptr_responses_as_configured() {
+        let answers = |ptr_response| {
+            let config = Config {
+                ptr_response,
+                ..Config::default()
+            };
+            let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+            harness.register("Test", "_http._tcp");
+            let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+            harness.receive(&packet, "10.0.0.5:5353");
+            record_types(&harness.take_outgoing()[0].0)
+        };
+        assert_eq!(
+            answers(PtrResponse::Bundled),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+        assert_eq!(answers(PtrResponse::Minimal), vec![Type::PTR]);
+    }
+
+    #[test]
+    fn places_records_in_configured_sections() {
+        let sections = |placement| {
+            let config = Config {
+                placement,
+                ..Config::default()
+            };
+            let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+            harness.register("Test", "_http._tcp");
+            let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            let types = |records: &[ResourceRecord]| {
+                records.iter().map(|rr| rr.data.typ()).collect::<Vec<_>>()
+            };
+            (types(&response.answers), types(&response.additional))
+        };
+        assert_eq!(
+            sections(Placement::default()),
+            (vec![Type::PTR], vec![Type::SRV, Type::TXT, Type::A])
+        );
+        let placement = Placement {
+            srv: Section::Answer,
+            txt: Section::Answer,
+            addresses: Section::Additional,
+        };
+        assert_eq!(
+            sections(placement),
+            (vec![Type::PTR, Type::SRV, Type::TXT], vec![Type::A])
+        );
+    }
+
+    #[test]
+    fn skips_disabled_services() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let mut svc = service("Test", "_http._tcp");
+        svc.enabled = false;
+        harness.services.write().register(svc.clone());
+
+        for (name, qtype) in &[
+            ("_http._tcp.local", QueryType::PTR),
+            ("Test._http._tcp.local", QueryType::SRV),
+            ("Test._http._tcp.local", QueryType::TXT),
+        ] {
+            harness.receive(&query(&[(name, *qtype)]), "10.0.0.5:5353");
+            assert!(harness.take_outgoing().is_empty());
+        }
+
+        // Only goodbyes go out for it
+        harness.fsm.send_unsolicited(&svc, None, true);
+        assert!(harness.take_outgoing().is_empty());
+        harness.fsm.send_unsolicited(&svc, Some(0), false);
+        assert_eq!(harness.take_outgoing().len(), 1);
+    }
+
+    #[test]
+    fn lists_instance_types_in_nsec_for_any_queries() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("Test._http._tcp.local", QueryType::All)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::SRV, Type::TXT]);
+        assert_eq!(response.additional.len(), 1);
+        let nsec = &response.additional[0];
+        assert_eq!(nsec.name.to_string(), "Test._http._tcp.local");
+        match nsec.data {
+            RRData::NSEC {
+                ref next,
+                ref types,
+            } => {
+                assert_eq!(next.to_string(), "Test._http._tcp.local");
+                assert_eq!(*types, vec![Type::TXT as u16, Type::SRV as u16]);
+            }
+            ref data => panic!("unexpected {:?}", data),
+        }
+    }
+
+    #[test]
+    fn advertises_only_within_schedule() {
+        let hours = |h: u64| Duration::from_secs(h * 60 * 60);
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let mut svc = service("Test", "_http._tcp");
+        svc.schedule = Some(Schedule {
+            start: hours(8),
+            end: hours(18),
+        });
+        harness.services.write().register(svc);
+        let ttls = |harness: &mut Harness<Inet>| -> Vec<u32> {
+            let sent = std::mem::take(&mut harness.socket.state().sent);
+            let packets = sent
+                .iter()
+                .map(|(packet, _)| Packet::parse(packet).unwrap());
+            packets.map(|packet| packet.answers[0].ttl).collect()
+        };
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+
+        // Registered at midnight, before its hours, and never announced
+        harness
+            .commands
+            .send(Command::Reschedule {
+                name: Name::from_str("Test._http._tcp.local").unwrap(),
+                previous: None,
+            })
+            .unwrap();
+        harness.poll();
+        assert_eq!(ttls(&mut harness), vec![]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+
+        harness.clock.advance(hours(8));
+        harness.poll();
+        assert_eq!(ttls(&mut harness), vec![Ttls::default().ptr]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert_eq!(harness.take_outgoing().len(), 1);
+
+        harness.clock.advance(hours(10));
+        harness.poll();
+        assert_eq!(ttls(&mut harness).last(), Some(&0));
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn answers_hostname_queries_with_the_family_asked_for() {
+        let config = Config {
+            include_both_families: true,
+            ..Config::default()
+        };
+        let interfaces = vec![iface("eth0", "10.0.0.2"), iface("eth0", "fe80::2")];
+        let mut harness = Harness::<Inet>::new(config, interfaces);
+        let expected = vec![
+            (QueryType::A, vec![Type::A]),
+            (QueryType::AAAA, vec![Type::AAAA]),
+            (QueryType::All, vec![Type::A, Type::AAAA]),
+        ];
+        for (qtype, types) in expected {
+            harness.receive(&query(&[("test-host.local", qtype)]), "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(answer_types(&outgoing[0].0), types, "{:?}", qtype);
+        }
+    }
+
+    #[test]
+    fn gives_addresses_their_own_ttls() {
+        let config = Config {
+            include_both_families: true,
+            ttls: Ttls {
+                aaaa: 30,
+                ..Ttls::default()
+            },
+            ..Config::default()
+        };
+        let interfaces = vec![iface("eth0", "10.0.0.2"), iface("eth0", "fe80::2")];
+        let mut harness = Harness::<Inet>::new(config, interfaces);
+        let packet = query(&[("test-host.local", QueryType::All)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let ttls: Vec<_> = response
+            .answers
+            .iter()
+            .map(|rr| (rr.data.typ(), rr.ttl))
+            .collect();
+        assert_eq!(ttls, vec![(Type::A, 120), (Type::AAAA, 30)]);
+
+        // Legacy queriers still get them capped
+        harness.receive(&packet, "10.0.0.5:40000");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert!(response.answers.iter().all(|rr| rr.ttl == 10));
+    }
+
+    #[test]
+    fn gives_records_ttls_by_type() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let ttls: Vec<_> = response
+            .answers
+            .iter()
+            .chain(&response.additional)
+            .map(|rr| (rr.data.typ(), rr.ttl))
+            .collect();
+        assert_eq!(
+            ttls,
+            vec![
+                (Type::PTR, 4500),
+                (Type::SRV, 120),
+                (Type::TXT, 120),
+                (Type::A, 120)
+            ]
+        );
+    }
+
+    #[test]
+    fn coalesces_identical_multicast_responses() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        harness.receive(&packet, "10.0.0.6:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].1, "224.0.0.251:5353".parse().unwrap());
+
+        // Once sent, the next query gets a response of its own
+        harness.receive(&packet, "10.0.0.7:5353");
+        assert_eq!(harness.take_outgoing().len(), 1);
+    }
+
+    #[test]
+    fn sends_unicast_out_of_arrival_interface() {
+        let sent_out_of = |unicast_via_arrival_interface| {
+            let config = Config {
+                unicast_via_arrival_interface,
+                ..Config::default()
+            };
+            let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+            harness.register("Test", "_http._tcp");
+            harness.poll();
+            harness.socket.state().sent.clear();
+
+            let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+            {
+                let mut state = harness.socket.state();
+                state.arrival = Some(3);
+                let legacy = "10.0.0.9:40000".parse().unwrap();
+                state.incoming.push_back(Ok((packet.clone(), legacy)));
+                let multicast = "10.0.0.5:5353".parse().unwrap();
+                state.incoming.push_back(Ok((packet, multicast)));
+            }
+            harness.poll();
+            let state = harness.socket.state();
+            assert_eq!(state.sent.len(), 2);
+            state.sent_out_of.clone()
+        };
+
+        // Only the unicast response is pinned, multicast leaves as ever
+        assert_eq!(
+            sent_out_of(true),
+            vec![("10.0.0.9:40000".parse().unwrap(), 3)]
+        );
+        assert!(sent_out_of(false).is_empty());
+    }
+
+    #[test]
+    fn sends_unicast_from_address_on_querier_subnet() {
+        let config = Config {
+            unicast_source_from_subnet: true,
+            ..Config::default()
+        };
+        let interfaces = vec![iface("eth0", "10.0.0.2"), iface("eth1", "192.168.1.2")];
+        let mut harness = Harness::<Inet>::new(config, interfaces);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+
+        harness.receive(&packet, "192.168.1.9:40000");
+        harness.receive(&packet, "10.0.0.9:40000");
+        // Nothing to pick off-link
+        harness.receive(&packet, "172.16.0.9:40000");
+        harness.poll();
+        let state = harness.socket.state();
+        assert_eq!(state.sent.len(), 3);
+        assert_eq!(
+            state.sent_from,
+            vec![
+                (
+                    "192.168.1.9:40000".parse().unwrap(),
+                    "192.168.1.2".parse().unwrap()
+                ),
+                (
+                    "10.0.0.9:40000".parse().unwrap(),
+                    "10.0.0.2".parse().unwrap()
+                ),
+            ]
+        );
+        // Out of the interface of the source, which link-local ones need
+        assert_eq!(
+            state.sent_out_of,
+            vec![
+                ("192.168.1.9:40000".parse().unwrap(), 2),
+                ("10.0.0.9:40000".parse().unwrap(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn paces_multicast_to_configured_rate() {
+        let config = Config {
+            max_multicast_rate: Some(2),
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        for i in 0..10 {
+            let name = format!("Test {}", i);
+            harness.register(&name, "_http._tcp");
+            harness
+                .commands
+                .send(Command::SendUnsolicited {
+                    svc: service(&name, "_http._tcp"),
+                    ttl: None,
+                    include_ip: true,
+                })
+                .unwrap();
+        }
+        // A legacy querier isn't held up by them
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness
+            .socket
+            .state()
+            .incoming
+            .push_back(Ok((packet, "10.0.0.9:40000".parse().unwrap())));
+
+        // A burst of two, then two a second
+        let mut sent = Vec::new();
+        for _ in 0..10 {
+            harness.poll();
+            sent.push(harness.socket.state().sent.len());
+            harness.clock.advance(Duration::from_millis(500));
+        }
+        assert_eq!(sent, vec![3, 4, 5, 6, 7, 8, 9, 10, 11, 11]);
+        let unicast = harness.socket.state().sent[2].1;
+        assert_eq!(unicast, "10.0.0.9:40000".parse().unwrap());
+    }
+
+    #[test]
+    fn ignores_queries_without_questions() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        for from in &["10.0.0.5:5353", "10.0.0.5:40000"] {
+            harness.receive(&query(&[]), from);
+            assert!(harness.take_outgoing().is_empty());
+        }
+    }
+
+    #[test]
+    fn dedups_queued_datagrams_as_configured() {
+        let queued = |dedup_outgoing| {
+            let config = Config {
+                dedup_outgoing,
+                ..Config::default()
+            };
+            let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+            harness.register("Test", "_http._tcp");
+            let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+            harness.receive(&packet, "10.0.0.5:40000");
+            harness.receive(&packet, "10.0.0.5:40000");
+            harness.take_outgoing().len()
+        };
+        assert_eq!(queued(true), 1);
+        assert_eq!(queued(false), 2);
+    }
+
+    #[test]
+    fn never_dedups_announcements() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let svc = service("Test", "_http._tcp");
+        harness.fsm.send_unsolicited(&svc, None, true);
+        harness.fsm.send_unsolicited(&svc, None, true);
+        // Both count as announcements once sent
+        assert!(harness
+            .fsm
+            .outgoing
+            .iter()
+            .all(|datagram| datagram.announces == [svc.name.clone()]));
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 2);
+        assert_eq!(outgoing[0].0, outgoing[1].0);
+    }
+
+    #[test]
+    fn streams_queries_to_their_services() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let (tx, mut rx) = mpsc::channel(16);
+        let id = harness.register("Test", "_http._tcp");
+        harness.services.write().set_queries(id, tx);
+        harness.register("Other", "_ipp._tcp");
+
+        harness.receive(
+            &query(&[("_http._tcp.local", QueryType::PTR)]),
+            "10.0.0.5:5353",
+        );
+        harness.receive(
+            &query(&[("_ipp._tcp.local", QueryType::PTR)]),
+            "10.0.0.5:5353",
+        );
+        harness.receive(
+            &query(&[("Test._http._tcp.local", QueryType::SRV)]),
+            "10.0.0.6:40000",
+        );
+
+        let (from, question) = rx.try_recv().unwrap();
+        assert_eq!(from, "10.0.0.5:5353".parse().unwrap());
+        assert_eq!(question.name, "_http._tcp.local");
+        let (from, question) = rx.try_recv().unwrap();
+        assert_eq!(from, "10.0.0.6:40000".parse().unwrap());
+        assert_eq!(question.name, "Test._http._tcp.local");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn drops_queries_while_their_stream_isnt_read() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let (tx, mut rx) = mpsc::channel(1);
+        let id = harness.register("Test", "_http._tcp");
+        harness.services.write().set_queries(id, tx);
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        harness.receive(&packet, "10.0.0.6:5353");
+        let (from, _) = rx.try_recv().unwrap();
+        assert_eq!(from, "10.0.0.5:5353".parse().unwrap());
+        assert!(rx.try_recv().is_err());
+
+        // There's room again once read
+        harness.receive(&packet, "10.0.0.7:5353");
+        let (from, _) = rx.try_recv().unwrap();
+        assert_eq!(from, "10.0.0.7:5353".parse().unwrap());
+    }
+
+    #[test]
+    fn sends_nothing_unsolicited_when_passive() {
+        let config = Config {
+            passive_only: true,
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let svc = service("Test", "_http._tcp");
+        harness.services.write().register(svc.clone());
+        for &ttl in &[None, Some(0)] {
+            harness
+                .commands
+                .send(Command::SendUnsolicited {
+                    svc: svc.clone(),
+                    ttl,
+                    include_ip: true,
+                })
+                .unwrap();
+        }
+        harness.poll();
+        assert!(harness.socket.state().sent.is_empty());
+
+        harness.receive(
+            &query(&[("_http._tcp.local", QueryType::PTR)]),
+            "10.0.0.5:5353",
+        );
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::PTR]);
+    }
+
+    #[test]
+    fn outgoing_filter_can_veto_and_rewrite() {
+        let querier: SocketAddr = "10.0.0.5:40000".parse().unwrap();
+        let config = Config {
+            outgoing_filter: Some(OutgoingFilter::new(move |mut packet, to| {
+                if to == querier {
+                    return None;
+                }
+                // Clears the authoritative answer bit
+                packet[2] &= !0x04;
+                Some(packet)
+            })),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:40000");
+        assert!(harness.take_outgoing().is_empty());
+
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert!(!response.header.authoritative);
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::PTR]);
+    }
+
+    #[test]
+    fn enumerates_service_types_sorted() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Printer", "_ipp._tcp");
+        harness.register("Web", "_http._tcp");
+        harness.register("Files", "_smb._tcp");
+        harness.register("Other web", "_http._tcp");
+        harness.register("Display", "_airplay._tcp");
+
+        let packet = query(&[("_services._dns-sd._udp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let types: Vec<_> = response
+            .answers
+            .iter()
+            .map(|rr| match rr.data {
+                RRData::PTR(ref typ) => typ.to_string(),
+                ref data => panic!("unexpected {:?}", data),
+            })
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                "_airplay._tcp.local",
+                "_http._tcp.local",
+                "_ipp._tcp.local",
+                "_smb._tcp.local",
+            ]
+        );
+        assert!(response.additional.is_empty());
+    }
+
+    #[test]
+    fn reuses_prebuilt_responses_until_services_change() {
+        let config = Config {
+            prebuilt_responses: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let first = harness.take_outgoing();
+        harness.receive(&packet, "10.0.0.6:5353");
+        assert_eq!(harness.take_outgoing(), first);
+        assert_eq!(harness.stats.reused_responses(), 1);
+
+        // Legacy queriers get their own ID back all the same
+        let mut legacy = query(&[("_http._tcp.local", QueryType::PTR)]);
+        for id in [0x1234u16, 0x5678].iter() {
+            legacy[..2].copy_from_slice(&id.to_be_bytes());
+            harness.receive(&legacy, "10.0.0.5:40000");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(Packet::parse(&outgoing[0].0).unwrap().header.id, *id);
+        }
+        assert_eq!(harness.stats.reused_responses(), 2);
+
+        harness.register("Other", "_http._tcp");
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(Packet::parse(&outgoing[0].0).unwrap().answers.len(), 2);
+        assert_eq!(harness.stats.reused_responses(), 2);
+    }
+
+    #[test]
+    fn prebuilt_responses_follow_interface_refreshes() {
+        let config = Config {
+            prebuilt_responses: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let first = harness.take_outgoing();
+
+        harness.interfaces.lock()[0] = iface("eth0", "10.0.0.3");
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert_eq!(harness.take_outgoing(), first);
+
+        harness
+            .clock
+            .advance(harness.fsm.config.interface_refresh_interval);
+        harness.poll();
+        harness.take_outgoing();
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert_ne!(harness.take_outgoing(), first);
+        assert_eq!(harness.stats.reused_responses(), 1);
+    }
+
+    #[test]
+    fn never_prebuilds_instances_sharing_a_name() {
+        let config = Config {
+            prebuilt_responses: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        for &(port, weight) in &[(8001, 1), (8003, 3)] {
+            let mut svc = service("Test", "_http._tcp");
+            svc.port = port;
+            svc.weight = weight;
+            harness.services.write().register(svc);
+        }
+        let packet = query(&[("Test._http._tcp.local", QueryType::SRV)]);
+        for _ in 0..2 {
+            harness.receive(&packet, "10.0.0.5:5353");
+            assert_eq!(harness.take_outgoing().len(), 1);
+        }
+        assert_eq!(harness.stats.reused_responses(), 0);
+    }
+
+    #[test]
+    fn rejoins_multicast_group_at_configured_interval() {
+        let interval = Duration::from_secs(60);
+        let config = Config {
+            rejoin_interval: Some(interval),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let membership = Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(harness.socket.state().joins, vec![membership.clone()]);
+
+        harness.clock.advance(interval - Duration::from_secs(1));
+        harness.poll();
+        assert!(harness.socket.state().leaves.is_empty());
+
+        harness.clock.advance(Duration::from_secs(1));
+        harness.poll();
+        assert_eq!(harness.socket.state().leaves, vec![membership.clone()]);
+        assert_eq!(harness.socket.state().joins, vec![membership.clone(); 2]);
+
+        harness.clock.advance(interval);
+        harness.poll();
+        assert_eq!(harness.socket.state().leaves, vec![membership.clone(); 2]);
+        assert_eq!(harness.socket.state().joins, vec![membership; 3]);
+    }
+
+    #[test]
+    fn announces_once_then_says_goodbye() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        let after = Duration::from_secs(10);
+        harness
+            .commands
+            .send(Command::AnnounceOnce {
+                svc: service("Here", "_presence._tcp"),
+                goodbye_after: Some(after),
+            })
+            .unwrap();
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+        assert_eq!(services::read(&harness.services).all().count(), 0);
+
+        harness.clock.advance(after - Duration::from_secs(1));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+
+        harness.clock.advance(Duration::from_secs(1));
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 2);
+        let goodbye = Packet::parse(&sent[1].0).unwrap();
+        assert!(goodbye.answers.iter().all(|rr| rr.ttl == 0));
+
+        harness.clock.advance(after);
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 2);
+    }
+
+    #[test]
+    fn resolves_hidden_services_by_name_alone() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Shown", "_http._tcp");
+        let mut hidden = service("Hidden", "_http._tcp");
+        hidden.discoverable = false;
+        harness.services.write().register(hidden);
+
+        harness.receive(
+            &query(&[("_http._tcp.local", QueryType::PTR)]),
+            "10.0.0.5:5353",
+        );
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let instances: Vec<_> = response
+            .answers
+            .iter()
+            .map(|rr| match rr.data {
+                RRData::PTR(ref name) => name.to_string(),
+                ref data => panic!("unexpected {:?}", data),
+            })
+            .collect();
+        assert_eq!(instances, vec!["Shown._http._tcp.local"]);
+
+        for &(qtype, typ) in [(QueryType::SRV, Type::SRV), (QueryType::TXT, Type::TXT)].iter() {
+            harness.receive(
+                &query(&[("Hidden._http._tcp.local", qtype)]),
+                "10.0.0.5:5353",
+            );
+            let outgoing = harness.take_outgoing();
+            assert_eq!(answer_types(&outgoing[0].0), vec![typ]);
+        }
+    }
+
+    #[test]
+    fn says_goodbye_for_the_ptr_record_of_hidden_services() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        let mut svc = service("Test", "_http._tcp");
+        svc.discoverable = false;
+        harness.commands.send(Command::Hide(svc.clone())).unwrap();
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        let packet = Packet::parse(&sent[0].0).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].ttl, 0);
+        assert_eq!(packet.answers[0].name.to_string(), "_http._tcp.local");
+
+        // Nothing to say for those never announced
+        svc.enabled = false;
+        harness.commands.send(Command::Hide(svc)).unwrap();
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+    }
+
+    #[test]
+    fn stays_quiet_about_services_until_probed_for() {
+        let config = Config {
+            probe_before_announcing: true,
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: service("Test", "_http._tcp"),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        let srv_query = query(&[("Test._http._tcp.local", QueryType::SRV)]);
+
+        for probes in 1..=3 {
+            harness.poll();
+            let sent = harness.socket.state().sent.clone();
+            assert_eq!(sent.len(), probes);
+            let probe = Packet::parse(&sent[probes - 1].0).unwrap();
+            assert!(probe.header.query);
+            assert_eq!(probe.questions[0].qtype, QueryType::All);
+            assert_eq!(probe.nameservers.len(), 2);
+            assert_eq!(
+                probe.nameservers[0].name,
+                Name::from_str("Test._http._tcp.local").unwrap()
+            );
+
+            harness.receive(&srv_query, "10.0.0.5:5353");
+            assert!(harness.take_outgoing().is_empty());
+            harness.clock.advance(Duration::from_millis(250));
+        }
+
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 4);
+        assert!(!Packet::parse(&sent[3].0).unwrap().header.query);
+        harness.receive(&srv_query, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::SRV]);
+    }
+
+    /// Another host's probe for the name of `svc`, with the same TXT record
+    /// and, given a `port`, an SRV record of it
+    fn competing_probe(harness: &mut Harness<Inet>, svc: &ServiceData, port: Option<u16>) {
+        let hostname = Name::from_str("test-host.local").unwrap();
+        let txt = svc.txt_rdata(false);
+        let mut builder = dns_parser::Builder::new_query(0, false)
+            .add_question(&svc.name, QueryType::All, QueryClass::IN)
+            .move_to::<dns_parser::Nameservers>()
+            .add_nameserver(&svc.name, QueryClass::IN, 4500, &RRData::TXT(&txt));
+        if let Some(port) = port {
+            let srv = ServiceData {
+                port,
+                ..svc.clone()
+            }
+            .srv_rdata(&hostname);
+            builder = builder.add_nameserver(&svc.name, QueryClass::IN, 120, &srv);
+        }
+        harness.receive(&builder.build().unwrap(), "10.0.0.9:5353");
+    }
+
+    #[test]
+    fn defers_to_simultaneous_probes_that_win_the_tiebreak() {
+        let config = Config {
+            probe_before_announcing: true,
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let svc = service("Test", "_http._tcp");
+        harness.services.write().register(svc.clone());
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: svc.clone(),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+
+        // The same TXT record without an SRV one loses to ours, and our own
+        // probe looped back is no contest either
+        competing_probe(&mut harness, &svc, None);
+        let own = harness.socket.state().sent[0].0.clone();
+        harness.receive(&own, "10.0.0.2:5353");
+        harness.clock.advance(Duration::from_millis(250));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 2);
+
+        // Port 81 sorts after our 80
+        competing_probe(&mut harness, &svc, Some(81));
+
+        // Nothing is sent until a second after, when probing starts over
+        for _ in 0..3 {
+            harness.clock.advance(Duration::from_millis(250));
+            harness.poll();
+        }
+        assert_eq!(harness.socket.state().sent.len(), 2);
+        harness.clock.advance(Duration::from_millis(250));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 3);
+        for probes in 4..=5 {
+            harness.clock.advance(Duration::from_millis(250));
+            harness.poll();
+            let sent = harness.socket.state().sent.clone();
+            assert_eq!(sent.len(), probes);
+            assert!(Packet::parse(&sent[probes - 1].0).unwrap().header.query);
+        }
+        harness.clock.advance(Duration::from_millis(250));
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 6);
+        assert!(!Packet::parse(&sent[5].0).unwrap().header.query);
+    }
+
+    #[test]
+    fn probes_for_batches_together() {
+        let config = Config {
+            probe_before_announcing: true,
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let names = ["A", "B", "C", "D", "E"];
+        let svcs: Vec<_> = names
+            .iter()
+            .map(|name| service(name, "_http._tcp"))
+            .collect();
+        for svc in &svcs {
+            harness.services.write().register(svc.clone());
+        }
+        harness
+            .commands
+            .send(Command::SendUnsolicitedBatch {
+                svcs,
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+
+        for probes in 1..=3 {
+            harness.poll();
+            let sent = harness.socket.state().sent.clone();
+            assert_eq!(sent.len(), probes);
+            let probe = Packet::parse(&sent[probes - 1].0).unwrap();
+            assert_eq!(probe.questions.len(), names.len());
+            harness.clock.advance(Duration::from_millis(250));
+        }
+
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 4);
+        let announcement = Packet::parse(&sent[3].0).unwrap();
+        assert_eq!(
+            announcement
+                .answers
+                .iter()
+                .filter(|rr| matches!(rr.data, RRData::SRV { .. }))
+                .count(),
+            names.len()
+        );
+    }
+
+    #[test]
+    fn answers_aliases_with_records_of_their_service() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let id = harness.register("Test", "_http._tcp");
+        let alias = Name::from_str("Alias._http._tcp.local").unwrap();
+        assert!(services::write(&harness.services).add_alias(id, alias));
+
+        let packet = query(&[
+            ("Alias._http._tcp.local", QueryType::SRV),
+            ("Alias._http._tcp.local", QueryType::TXT),
+        ]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        let primary = service("Test", "_http._tcp");
+        let records: Vec<_> = response
+            .answers
+            .iter()
+            .map(|rr| {
+                assert_eq!(rr.name.to_string(), "Alias._http._tcp.local");
+                match rr.data {
+                    RRData::SRV { port, .. } => assert_eq!(port, primary.port),
+                    RRData::TXT(txt) => assert_eq!(txt, &primary.txt[..]),
+                    ref data => panic!("unexpected {:?}", data),
+                }
+                rr.data.typ()
+            })
+            .collect();
+        assert_eq!(records, vec![Type::SRV, Type::TXT]);
+
+        harness.receive(
+            &query(&[("_http._tcp.local", QueryType::PTR)]),
+            "10.0.0.5:5353",
+        );
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::PTR, Type::PTR]);
+
+        // Whatever happens to the service happens to its aliases
+        services::write(&harness.services).set_enabled(id, false);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn carries_on_receiving_after_transient_errors() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.poll();
+        harness.take_outgoing();
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let from = "10.0.0.5:5353".parse().unwrap();
+        {
+            let mut state = harness.socket.state();
+            state
+                .incoming
+                .push_back(Err(io::ErrorKind::Interrupted.into()));
+            state.incoming.push_back(Ok((packet, from)));
+        }
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(harness.fsm.recv_packets(&mut cx).is_ok());
+        assert_eq!(harness.take_outgoing().len(), 1);
+        assert!(harness.stats.is_healthy());
+
+        // Anything else still gives up on this poll
+        harness
+            .socket
+            .state()
+            .incoming
+            .push_back(Err(io::ErrorKind::PermissionDenied.into()));
+        assert!(harness.fsm.recv_packets(&mut cx).is_err());
+    }
+
+    #[test]
+    fn builds_but_withholds_responses_while_observing_only() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness
+            .commands
+            .send(Command::SetObserveOnly(true))
+            .unwrap();
+        harness.poll();
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let from = "10.0.0.5:5353".parse().unwrap();
+        harness
+            .socket
+            .state()
+            .incoming
+            .push_back(Ok((packet.clone(), from)));
+        harness.poll();
+        assert!(harness.socket.state().sent.is_empty());
+        assert!(harness.fsm.outgoing.is_empty());
+        assert_eq!(harness.stats.withheld_datagrams(), 1);
+
+        harness
+            .commands
+            .send(Command::SetObserveOnly(false))
+            .unwrap();
+        harness
+            .socket
+            .state()
+            .incoming
+            .push_back(Ok((packet, from)));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 1);
+        assert_eq!(harness.stats.withheld_datagrams(), 1);
+    }
+
+    #[test]
+    fn never_sends_suppressed_records() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let id = harness.register("Test", "_http._tcp");
+        services::write(&harness.services).set_suppressed(id, vec![ServiceRecord::Txt]);
+
+        let packet = query(&[("Test._http._tcp.local", QueryType::TXT)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+
+        let packet = query(&[("Test._http._tcp.local", QueryType::SRV)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::SRV]);
+
+        // Nor is it sent along with the PTR record
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert!(!record_types(&outgoing[0].0).contains(&Type::TXT));
+    }
+
+    #[test]
+    fn advertises_payload_size_when_configured() {
+        let config = Config {
+            advertised_payload_size: Some(1440),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        // Parsing stops at the OPT record, which is last
+        assert_eq!(
+            response.header.additional as usize,
+            response.additional.len() + 1
+        );
+        let opt = &outgoing[0].0[outgoing[0].0.len() - 11..];
+        assert_eq!(opt, &[0, 0, 41, 0x05, 0xa0, 0, 0, 0, 0, 0, 0]);
+
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert_eq!(
+            response.header.additional as usize,
+            response.additional.len()
+        );
+    }
+
+    #[test]
+    fn lists_addresses_of_preferred_interfaces_first() {
+        let ifaces = vec![iface("wlan0", "10.0.0.2"), iface("eth0", "192.168.1.2")];
+        let config = Config {
+            interface_preference: vec![("eth0".to_owned(), 10)].into_iter().collect(),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, ifaces.clone());
+        let packet = query(&[("test-host.local", QueryType::A)]);
+        let addrs = |harness: &mut Harness<Inet>| {
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            let addrs: Vec<_> = response
+                .answers
+                .iter()
+                .filter_map(|rr| match rr.data {
+                    RRData::A(ip) => Some(ip),
+                    _ => None,
+                })
+                .collect();
+            addrs
+        };
+        assert_eq!(
+            addrs(&mut harness),
+            vec![Ipv4Addr::new(192, 168, 1, 2), Ipv4Addr::new(10, 0, 0, 2)]
+        );
+
+        let mut harness = Harness::<Inet>::new(Config::default(), ifaces);
+        assert_eq!(
+            addrs(&mut harness),
+            vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(192, 168, 1, 2)]
+        );
+    }
+
+    #[test]
+    fn rewrites_hostname_per_querier() {
+        let config = Config {
+            hostname_rewrite: Some(HostnameRewrite::new(|querier: &SocketAddr| {
+                match querier.ip() {
+                    IpAddr::V4(ip) if ip.octets()[0] == 10 => Some("internal.local".to_owned()),
+                    _ => None,
+                }
+            })),
+            ..Config::default()
+        };
+        let ifaces = vec![iface("eth0", "10.0.0.2"), iface("eth1", "192.168.1.2")];
+        let mut harness = Harness::<Inet>::new(config, ifaces);
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("Test._http._tcp.local", QueryType::SRV)]);
+        let target = |harness: &mut Harness<Inet>, from| {
+            harness.receive(&packet, from);
+            let outgoing = harness.take_outgoing();
+            assert_eq!(outgoing.len(), 1);
+            // Only those with a name of their own get it, by unicast
+            let unicast = outgoing[0].1 == from.parse().unwrap();
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            let target = match response.answers[0].data {
+                RRData::SRV { ref target, .. } => target.to_string(),
+                ref data => panic!("unexpected {:?}", data),
+            };
+            let addresses: Vec<_> = response
+                .additional
+                .iter()
+                .map(|rr| rr.name.to_string())
+                .collect();
+            assert_eq!(unicast, target == "internal.local");
+            (target, addresses)
+        };
+
+        let (name, addresses) = target(&mut harness, "10.0.0.5:5353");
+        assert_eq!(name, "internal.local");
+        assert!(!addresses.is_empty());
+        assert!(addresses.iter().all(|n| n == "internal.local"));
+        let (name, addresses) = target(&mut harness, "192.168.1.5:5353");
+        assert_eq!(name, "test-host.local");
+        assert!(addresses.iter().all(|n| n == "test-host.local"));
+
+        // The rewritten name resolves
+        let packet = query(&[("internal.local", QueryType::A)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::A, Type::A]);
+    }
+
+    #[test]
+    fn ignores_hostnames_rewritten_past_the_label_limit() {
+        let config = Config {
+            hostname_rewrite: Some(HostnameRewrite::new(|_: &SocketAddr| {
+                Some(format!("{}.local", "x".repeat(63)))
+            })),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+
+        harness.receive(
+            &query(&[("Test._http._tcp.local", QueryType::SRV)]),
+            "10.0.0.5:5353",
+        );
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].1, SocketAddr::new(group_v4().into(), 5353));
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        match response.answers[0].data {
+            RRData::SRV { ref target, .. } => assert_eq!(target.to_string(), "test-host.local"),
+            ref data => panic!("unexpected {:?}", data),
+        }
+    }
+
+    #[test]
+    fn answers_with_stale_addresses_while_interfaces_cant_be_listed() {
+        let config = Config {
+            stale_address_window: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let packet = query(&[("test-host.local", QueryType::A)]);
+        let addrs = |harness: &mut Harness<Inet>| {
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            let response = match outgoing.first() {
+                Some((packet, _)) => Packet::parse(packet).unwrap(),
+                None => return Vec::new(),
+            };
+            let addrs: Vec<_> = response
+                .answers
+                .iter()
+                .filter_map(|rr| match rr.data {
+                    RRData::A(ip) => Some((ip, rr.ttl)),
+                    _ => None,
+                })
+                .collect();
+            addrs
+        };
+        assert_eq!(
+            addrs(&mut harness),
+            vec![(Ipv4Addr::new(10, 0, 0, 2), Ttls::default().a)]
+        );
+
+        harness.interfaces.set_failing(true);
+        harness.clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            addrs(&mut harness),
+            vec![(Ipv4Addr::new(10, 0, 0, 2), STALE_ADDRESS_TTL)]
+        );
+
+        // Until they are too old to vouch for
+        harness.clock.advance(Duration::from_secs(31));
+        assert!(addrs(&mut harness).is_empty());
+    }
+
+    #[test]
+    fn answers_queries_asking_for_recursion_unless_strict() {
+        let mut builder = dns_parser::Builder::new_query(0, true);
+        let name = Name::from_str("_http._tcp.local").unwrap();
+        builder = builder.add_question(&name, QueryType::PTR, QueryClass::IN);
+        let packet = builder.build().unwrap();
+
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert_eq!(harness.take_outgoing().len(), 1);
+
+        let config = Config {
+            strict_query_flags: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+
+        // Queries without any of the flags are answered alike
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert_eq!(harness.take_outgoing().len(), 1);
+    }
+
+    #[test]
+    fn gives_addresses_of_a_services_families_alone() {
+        let config = Config {
+            include_both_families: true,
+            ..Config::default()
+        };
+        let ifaces = vec![iface("eth0", "10.0.0.2"), iface("eth0", "fe80::2")];
+        let mut harness = Harness::<Inet>::new(config.clone(), ifaces.clone());
+        let id = harness.register("Test", "_http._tcp");
+        services::write(&harness.services).set_families(id, Families::V4);
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        let types = record_types(&outgoing[0].0);
+        assert!(types.contains(&Type::A));
+        assert!(!types.contains(&Type::AAAA));
+
+        // Nor is it answered for over IPv6
+        let mut harness = Harness::<Inet6>::new(config, ifaces);
+        let id = harness.register("Test", "_http._tcp");
+        services::write(&harness.services).set_families(id, Families::V4);
+        harness.receive(&packet, "[fe80::5]:5353");
+        assert!(harness.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn generates_txt_when_answering() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let counter = AtomicUsize::new(0);
+        let mut svc = service("Test", "_http._tcp");
+        svc.txt_fn = Some(TxtFn::new(move || {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            vec![format!("count={}", n)]
+        }));
+        harness.services.write().register(svc);
+
+        let packet = query(&[("Test._http._tcp.local", QueryType::TXT)]);
+        for expected in &[b"\x07count=1", b"\x07count=2"] {
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            match response.answers[0].data {
+                RRData::TXT(txt) => assert_eq!(txt, &expected[..]),
+                ref data => panic!("unexpected {:?}", data),
+            }
+        }
+    }
+
+    #[test]
+    fn sends_private_txt_by_unicast_only() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let mut svc = service("Test", "_http._tcp");
+        svc.private_txt = encode_txt(&["token=x"]);
+        harness.services.write().register(svc);
+
+        let qm = query(&[("Test._http._tcp.local", QueryType::TXT)]);
+        let mut qu = qm.clone();
+        let class = qu.len() - 2;
+        qu[class] |= 0x80;
+        let group = SocketAddr::new(group_v4().into(), 5353);
+        for (packet, to, expected) in [
+            (qm, group, &b"\x06path=/"[..]),
+            (
+                qu,
+                "10.0.0.5:5353".parse().unwrap(),
+                b"\x06path=/\x07token=x",
+            ),
+        ] {
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(outgoing[0].1, to);
+            let response = Packet::parse(&outgoing[0].0).unwrap();
+            match response.answers[0].data {
+                RRData::TXT(txt) => assert_eq!(txt, expected),
+                ref data => panic!("unexpected {:?}", data),
+            }
+        }
+    }
+
+    #[test]
+    fn answers_device_info_with_txt_alone() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.services.write().register(ServiceData {
+            name: Name::from_str("test-host._device-info._tcp.local").unwrap(),
+            typ: Name::from_str("_device-info._tcp.local").unwrap(),
+            port: 0,
+            txt: encode_txt(&["model=Xserve"]),
+            txt_fn: None,
+            subnets: None,
+            records: Vec::new(),
+            private_txt: Vec::new(),
+            priority: 0,
+            weight: 0,
+            enabled: true,
+            schedule: None,
+            discoverable: true,
+            suppressed: Vec::new(),
+            families: Families::Both,
+            queries: None,
+        });
+
+        let packet = query(&[("test-host._device-info._tcp.local", QueryType::TXT)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        match response.answers[0].data {
+            RRData::TXT(txt) => assert_eq!(txt, b"\x0cmodel=Xserve"),
+            ref data => panic!("unexpected {:?}", data),
+        }
+
+        let packet = query(&[("_device-info._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(record_types(&outgoing[0].0), vec![Type::PTR, Type::TXT]);
+
+        let packet = query(&[("test-host._device-info._tcp.local", QueryType::SRV)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn answers_known_questions_next_to_unknown_qtypes() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+
+        let packet = query(&[
+            ("_http._tcp.local", QueryType::PTR),
+            ("_http._tcp.local", QueryType::Unknown(65280)),
+        ]);
+        let parsed = Packet::parse(&packet).unwrap();
+        assert_eq!(parsed.questions[1].qtype, QueryType::Unknown(65280));
+
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(
+            record_types(&outgoing[0].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+    }
+
+    #[test]
+    fn answers_txt_only_services_without_srv() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        harness.services.write().register(ServiceData {
+            port: 0,
+            ..service("Meta", "_meta._tcp")
+        });
+
+        let packet = query(&[("_meta._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(record_types(&outgoing[0].0), vec![Type::PTR, Type::TXT]);
+
+        let packet = query(&[("Meta._meta._tcp.local", QueryType::SRV)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        assert!(harness.take_outgoing().is_empty());
+
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: ServiceData {
+                    port: 0,
+                    ..service("Meta", "_meta._tcp")
+                },
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(answer_types(&sent[0].0), vec![Type::PTR, Type::TXT]);
+    }
+
+    #[test]
+    fn advertises_periodically_when_configured() {
+        let config = Config {
+            advertise_interval: Some(Duration::from_secs(20)),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("One", "_http._tcp");
+        harness.register("Two", "_http._tcp");
+        harness.poll();
+        assert!(harness.socket.state().sent.is_empty());
+
+        for round in 1..=3 {
+            harness.clock.advance(Duration::from_secs(20));
+            harness.poll();
+            let sent = harness.socket.state().sent.clone();
+            assert_eq!(sent.len(), round);
+            assert_eq!(sent[round - 1].1, SocketAddr::new(group_v4().into(), 5353));
+            let types = answer_types(&sent[round - 1].0);
+            assert_eq!(types.iter().filter(|t| **t == Type::PTR).count(), 2);
+            assert_eq!(types.iter().filter(|t| **t == Type::SRV).count(), 2);
+            assert_eq!(types.iter().filter(|t| **t == Type::TXT).count(), 2);
+            assert!(types.contains(&Type::A));
+        }
+    }
+
+    #[test]
+    fn ignores_own_goodbyes() {
+        let conflicts = Arc::new(Mutex::new(Vec::new()));
+        let sink = conflicts.clone();
+        let config = Config {
+            conflict_observer: Some(ConflictObserver::new(move |conflict| {
+                sink.lock().unwrap().push(conflict.clone());
+            })),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        // Re-registered on another port before the goodbye for the old one
+        // came back around
+        harness.register("Test", "_http._tcp");
+        let old = ServiceData {
+            port: 8080,
+            ..service("Test", "_http._tcp")
+        };
+        harness.fsm.send_unsolicited(&old, Some(0), false);
+        let goodbye = harness.take_outgoing().remove(0).0;
+
+        harness.receive(&goodbye, "10.0.0.2:5353");
+        assert!(harness.take_outgoing().is_empty());
+        assert!(conflicts.lock().unwrap().is_empty());
+        let name = Name::from_str("Test._http._tcp.local").unwrap();
+        assert!(harness.services.read().find_by_name(&name).is_some());
+    }
+
+    #[test]
+    fn reports_conflicting_announcements() {
+        let conflicts = Arc::new(Mutex::new(Vec::new()));
+        let sink = conflicts.clone();
+        let config = Config {
+            conflict_observer: Some(ConflictObserver::new(move |conflict| {
+                sink.lock().unwrap().push(conflict.clone());
+            })),
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let svc = service("Test", "_http._tcp");
+
+        // Our own announcement looped back is no conflict
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: svc.clone(),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+        let own = harness.socket.state().sent[0].0.clone();
+        harness.receive(&own, "10.0.0.2:5353");
+        assert!(conflicts.lock().unwrap().is_empty());
+
+        let hostname = Name::from_str("test-host.local").unwrap();
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        let ttls = Ttls::default();
+        builder = svc.add_ptr_rr(builder, ttls.ptr);
+        builder = ServiceData { port: 8080, ..svc }.add_srv_rr(&hostname, builder, ttls.srv);
+        builder = builder.add_answer(
+            &hostname,
+            QueryClass::IN,
+            ttls.a,
+            &RRData::A(Ipv4Addr::new(10, 0, 0, 9)),
+        );
+        harness.receive(&builder.build().unwrap(), "10.0.0.9:5353");
+
+        let from: SocketAddr = "10.0.0.9:5353".parse().unwrap();
+        assert_eq!(
+            *conflicts.lock().unwrap(),
+            vec![
+                Conflict {
+                    name: "Test._http._tcp.local".to_owned(),
+                    rtype: 33,
+                    from,
+                    resolution: ConflictResolution::Renamed("Test (2)._http._tcp.local".to_owned()),
+                },
+                Conflict {
+                    name: "test-host.local".to_owned(),
+                    rtype: 1,
+                    from,
+                    resolution: ConflictResolution::Unresolved,
+                },
+            ]
+        );
+    }
+
+    /// Feeds a response claiming `svc` with a conflicting SRV port
+    fn conflicting_srv(harness: &mut Harness<Inet>, svc: &ServiceData, port: u16) {
+        let hostname = Name::from_str("test-host.local").unwrap();
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder = ServiceData {
+            port,
+            ..svc.clone()
+        }
+        .add_srv_rr(&hostname, builder, Ttls::default().srv);
+        harness.receive(&builder.build().unwrap(), "10.0.0.9:5353");
+    }
+
+    #[test]
+    fn defends_records_that_win_the_tiebreak() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let svc = service("Test", "_http._tcp");
+
+        // Port 79 sorts before our 80
+        conflicting_srv(&mut harness, &svc, 79);
+
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].1, SocketAddr::new(group_v4().into(), 5353));
+        let packet = Packet::parse(&outgoing[0].0).unwrap();
+        match packet.answers[1].data {
+            RRData::SRV { port, .. } => assert_eq!(port, 80),
+            ref data => panic!("unexpected {:?}", data),
+        }
+        let services = harness.services.read();
+        assert!(services.find_by_name(&svc.name).is_some());
+    }
+
+    #[test]
+    fn renames_services_that_lose_the_tiebreak() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let id = harness.register("Test", "_http._tcp");
+        let svc = service("Test", "_http._tcp");
+
+        conflicting_srv(&mut harness, &svc, 8080);
+
+        let renamed = Name::from_str("Test (2)._http._tcp.local").unwrap();
+        {
+            let services = harness.services.read();
+            assert!(services.find_by_name(&svc.name).is_none());
+            assert_eq!(services.find_by_id(id).unwrap().name, renamed);
+        }
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        let packet = Packet::parse(&outgoing[0].0).unwrap();
+        assert_eq!(packet.answers[1].name, renamed);
+
+        // Losing again moves on to the next number
+        let svc = harness.services.read().find_by_id(id).cloned().unwrap();
+        conflicting_srv(&mut harness, &svc, 8080);
+        let services = harness.services.read();
+        assert_eq!(
+            services.find_by_id(id).unwrap().name,
+            Name::from_str("Test (3)._http._tcp.local").unwrap()
+        );
+    }
+
+    #[test]
+    fn renames_the_longest_instances_within_the_label_limit() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let instance = "x".repeat(62);
+        let id = harness.register(&instance, "_http._tcp");
+        let svc = service(&instance, "_http._tcp");
+
+        conflicting_srv(&mut harness, &svc, 8080);
+
+        let renamed = format!("{} (2)._http._tcp.local", "x".repeat(58));
+        {
+            let services = harness.services.read();
+            let name = &services.find_by_id(id).unwrap().name;
+            assert_eq!(*name, Name::from_str(renamed.clone()).unwrap());
+        }
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        let packet = Packet::parse(&outgoing[0].0).unwrap();
+        assert_eq!(packet.answers[1].name.to_string(), renamed);
+    }
+
+    #[test]
+    fn answers_each_address_once() {
+        let mut harness = Harness::<Inet>::new(
+            Config::default(),
+            vec![
+                iface("eth0", "10.0.0.2"),
+                iface("eth0:1", "10.0.0.2"),
+                iface("wlan0", "192.168.1.2"),
+            ],
+        );
+
+        harness.receive(
+            &query(&[("test-host.local", QueryType::A)]),
+            "10.0.0.5:5353",
+        );
+        let outgoing = harness.take_outgoing();
+        let packet = Packet::parse(&outgoing[0].0).unwrap();
+        let addrs: Vec<_> = packet
+            .answers
+            .iter()
+            .map(|rr| match rr.data {
+                RRData::A(ip) => ip,
+                ref data => panic!("unexpected {:?}", data),
+            })
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(192, 168, 1, 2)]
+        );
+    }
+
+    #[test]
+    fn caps_and_rotates_ptr_answers() {
+        let config = Config {
+            ptr_instances: PtrInstances::Capped(100),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        for i in 0..200 {
+            harness.register(&format!("Test {}", i), "_http._tcp");
+        }
+
+        let mut seen = HashSet::new();
+        for _ in 0..2 {
+            let ptrs = ptr_answers(&mut harness);
+            assert_eq!(ptrs.len(), 100);
+            seen.extend(ptrs);
+        }
+        assert_eq!(seen.len(), 200);
+    }
+
+    fn ptr_answers(harness: &mut Harness<Inet>) -> Vec<String> {
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        let response = Packet::parse(&outgoing[0].0).unwrap();
+        response
+            .answers
+            .iter()
+            .filter_map(|rr| match rr.data {
+                RRData::PTR(ref name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn answers_ptr_queries_with_all_instances() {
+        let config = Config {
+            ptr_instances: PtrInstances::All,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        for i in 0..150 {
+            harness.register(&format!("Test {}", i), "_http._tcp");
+        }
+
+        assert_eq!(ptr_answers(&mut harness).len(), 150);
+    }
+
+    #[test]
+    fn answers_ptr_queries_with_one_instance_at_a_time() {
+        let config = Config {
+            ptr_instances: PtrInstances::Single,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        for i in 0..3 {
+            harness.register(&format!("Test {}", i), "_http._tcp");
+        }
+
+        let mut seen = HashSet::new();
+        for _ in 0..3 {
+            let ptrs = ptr_answers(&mut harness);
+            assert_eq!(ptrs.len(), 1);
+            seen.extend(ptrs);
+        }
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[test]
+    fn health_check_notices_lost_self_query() {
+        let config = Config {
+            health_check_interval: Some(Duration::from_secs(10)),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        assert!(harness.stats.is_healthy());
+
+        // The self-query loops back, as it should
+        harness.clock.advance(Duration::from_secs(10));
+        harness.poll();
+        let probe = harness.socket.state().sent[0].clone();
+        assert_eq!(probe.1, SocketAddr::new(group_v4().into(), 5353));
+        harness
+            .socket
+            .state()
+            .incoming
+            .push_back(Ok((probe.0, "10.0.0.2:5353".parse().unwrap())));
+        harness.poll();
+        assert!(harness.stats.is_healthy());
+        // and isn't answered
+        assert_eq!(harness.socket.state().sent.len(), 1);
+
+        // The next one is lost
+        harness.clock.advance(Duration::from_secs(10));
+        harness.poll();
+        assert_eq!(harness.socket.state().sent.len(), 2);
+        assert!(harness.stats.is_healthy());
+        harness.clock.advance(Duration::from_secs(10));
+        harness.poll();
+        assert!(!harness.stats.is_healthy());
+    }
+
+    #[test]
+    fn multicasts_qu_answers_not_multicast_lately() {
+        let mut qu = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let class = qu.len() - 2;
+        qu[class] |= 0x80;
+        let group = SocketAddr::new(group_v4().into(), 5353);
+        let querier: SocketAddr = "10.0.0.5:5353".parse().unwrap();
+
+        let config = Config {
+            upgrade_stale_qu: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let destination = |harness: &mut Harness<Inet>| {
+            harness.receive(&qu, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(outgoing.len(), 1);
+            outgoing[0].1
+        };
+        assert_eq!(destination(&mut harness), group);
+        // Just went out by multicast, so the unicast request is honoured
+        // until a quarter of the PTR's TTL has passed
+        let quarter = u64::from(Ttls::default().ptr) / 4;
+        harness.clock.advance(Duration::from_secs(quarter - 1));
+        assert_eq!(destination(&mut harness), querier);
+        harness.clock.advance(Duration::from_secs(1));
+        assert_eq!(destination(&mut harness), group);
+
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        assert_eq!(destination(&mut harness), querier);
+
+        // Announcements count as multicast too
+        let config = Config {
+            upgrade_stale_qu: true,
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness
+            .fsm
+            .send_unsolicited(&service("Test", "_http._tcp"), None, true);
+        harness.take_outgoing();
+        assert_eq!(destination(&mut harness), querier);
+    }
+
+    #[test]
+    fn answers_with_raw_records() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        let uri = RawRecord {
+            rtype: 256,
+            class: 1,
+            ttl: 120,
+            rdata: b"\x00\x01\x00\x01http://10.0.0.2/".to_vec(),
+        };
+        let svc = ServiceData {
+            records: vec![uri.clone()],
+            private_txt: Vec::new(),
+            priority: 0,
+            weight: 0,
+            enabled: true,
+            schedule: None,
+            discoverable: true,
+            suppressed: Vec::new(),
+            families: Families::Both,
+            queries: None,
+            ..service("Test", "_http._tcp")
+        };
+        harness.services.write().register(svc);
+
+        let packet = query(&[("Test._http._tcp.local", QueryType::Unknown(256))]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        // The parser knows no URI records, so look at the bytes instead
+        let response = &outgoing[0].0;
+        assert_eq!(&response[6..8], b"\x00\x01");
+        let mut record = b"\x01\x00\x00\x01\x00\x00\x00\x78".to_vec();
+        record.extend_from_slice(&(uri.rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(&uri.rdata);
+        assert!(response.ends_with(&record));
+
+        // Not sent for other types
+        let packet = query(&[("Test._http._tcp.local", QueryType::SRV)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(record_types(&outgoing[0].0), vec![Type::SRV, Type::A]);
+    }
+
+    #[test]
+    fn answers_over_provided_socket() {
+        let socket = MockSocket::new();
+        let services = Arc::new(SharedServices::new(ServicesInner::new(
+            "test-host.local".to_owned(),
+        )));
+        services::write(&services).register(service("Test", "_http._tcp"));
+        let config = Arc::new(Config::default());
+        let stats = Arc::new(Stats::new(&config));
+        let (mut fsm, _commands) = FSM::<Inet>::with_socket(
+            Box::new(socket.clone()),
+            &services,
+            &config,
+            &stats,
+            Arc::new(MockClock::new()),
+            Box::new(MockInterfaces::new(vec![iface("eth0", "10.0.0.2")])),
+        );
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let from = "10.0.0.5:5353".parse().unwrap();
+        socket.state().incoming.push_back(Ok((packet, from)));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut fsm).poll(&mut cx).is_pending());
+
+        let state = socket.state();
+        // Memberships are up to whoever provided the socket
+        assert!(state.joins.is_empty());
+        assert_eq!(state.sent.len(), 1);
+        assert_eq!(state.sent[0].1, SocketAddr::new(group_v4().into(), 5353));
+    }
+
+    #[test]
+    fn refuses_sockets_off_the_mdns_port() {
+        let rt = Runtime::new().unwrap();
+        rt.enter(|| {
+            let services = Arc::new(SharedServices::new(ServicesInner::new(
+                "test-host.local".to_owned(),
+            )));
+            let config = Arc::new(Config::default());
+            let stats = Arc::new(Stats::new(&config));
+
+            let ephemeral = Inet::bind_port(0, &config).unwrap();
+            assert!(FSM::<Inet>::from_socket(ephemeral, &services, &config, &stats).is_err());
+            let socket = Inet::bind(&config).unwrap();
+            let v4 = socket.try_clone().unwrap();
+            assert!(FSM::<Inet6>::from_socket(v4, &services, &config, &stats).is_err());
+            assert!(FSM::<Inet>::from_socket(socket, &services, &config, &stats).is_ok());
+        });
+    }
+
+    #[test]
+    fn restricts_services_to_subnets() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Open", "_http._tcp");
+        let restricted = ServiceData {
+            subnets: Some(vec!["10.0.0.0/24".parse().unwrap()]),
+            ..service("Restricted", "_http._tcp")
+        };
+        harness.services.write().register(restricted.clone());
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let instances = |packet: &[u8]| -> Vec<String> {
+            let packet = Packet::parse(packet).unwrap();
+            packet
+                .answers
+                .iter()
+                .filter_map(|rr| match rr.data {
+                    RRData::PTR(ref name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Outside the subnet, only the open service
+        harness.receive(&packet, "10.1.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].1, SocketAddr::new(group_v4().into(), 5353));
+        assert_eq!(instances(&outgoing[0].0), vec!["Open._http._tcp.local"]);
+
+        // Inside, the restricted one too, but by unicast
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing.len(), 2);
+        assert_eq!(instances(&outgoing[0].0), vec!["Open._http._tcp.local"]);
+        assert_eq!(outgoing[1].1, "10.0.0.5:5353".parse().unwrap());
+        assert_eq!(
+            instances(&outgoing[1].0),
+            vec!["Restricted._http._tcp.local"]
+        );
+
+        // and never announced
+        harness.fsm.send_unsolicited(&restricted, None, true);
+        assert!(harness.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn changing_hostname_says_goodbye_for_old_name() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let previous = Name::from_str("test-host.local").unwrap();
+        let name = Name::from_str("renamed.local").unwrap();
+        harness
+            .commands
+            .send(Command::SetHostname {
+                name: name.clone(),
+                previous: previous.clone(),
+            })
+            .unwrap();
+        harness.poll();
+
+        assert_eq!(harness.services.read().get_hostname(), &name);
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 2);
+        let goodbye = Packet::parse(&sent[0].0).unwrap();
+        assert_eq!(goodbye.answers.len(), 1);
+        assert_eq!(goodbye.answers[0].name, previous);
+        assert_eq!(goodbye.answers[0].ttl, 0);
+        assert_eq!(answer_types(&sent[0].0), vec![Type::A]);
+
+        let announcement = Packet::parse(&sent[1].0).unwrap();
+        assert_eq!(
+            answer_types(&sent[1].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+        match announcement.answers[1].data {
+            RRData::SRV { ref target, .. } => assert_eq!(*target, name),
+            ref data => panic!("unexpected {:?}", data),
+        }
+        assert_eq!(announcement.answers[3].name, name);
+        let ttls: Vec<_> = announcement.answers.iter().map(|rr| rr.ttl).collect();
+        let defaults = Ttls::default();
+        assert_eq!(
+            ttls,
+            vec![defaults.ptr, defaults.srv, defaults.txt, defaults.a]
+        );
+    }
+
+    #[test]
+    fn answers_without_addresses_as_configured() {
+        let modes = vec![
+            (
+                NoAddresses::Answer,
+                Some(vec![Type::PTR, Type::SRV, Type::TXT]),
+            ),
+            (NoAddresses::Suppress, None),
+            (
+                NoAddresses::Static(vec![
+                    "10.9.9.9".parse().unwrap(),
+                    "fe80::9".parse().unwrap(),
+                ]),
+                Some(vec![Type::PTR, Type::SRV, Type::TXT, Type::A]),
+            ),
+        ];
+        for (mode, expected) in modes {
+            let config = Config {
+                when_no_addresses: mode,
+                ..Config::default()
+            };
+            let mut harness = Harness::<Inet>::new(config, vec![]);
+            harness.register("Test", "_http._tcp");
+
+            let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+            harness.receive(&packet, "10.0.0.5:5353");
+            let outgoing = harness.take_outgoing();
+            assert_eq!(
+                outgoing.first().map(|(packet, _)| record_types(packet)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn taps_received_packets() {
+        let (tap, tapped) = PacketTap::new(1);
+        let config = Config {
+            packet_tap: Some(tap),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let from: SocketAddr = "10.0.0.5:5353".parse().unwrap();
+        for _ in 0..2 {
+            harness
+                .socket
+                .state()
+                .incoming
+                .push_back(Ok((packet.clone(), from)));
+        }
+        harness.poll();
+
+        assert_eq!(tapped.try_recv().unwrap(), (packet, from));
+        // The second didn't fit, and was dropped
+        assert!(tapped.try_recv().is_err());
+    }
+
+    #[test]
+    fn reports_queue_depth() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        harness.socket.state().stalled = true;
+
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        let mut depths = Vec::new();
+        for i in 1..=3 {
+            // Legacy queriers, whose unicast responses can't be coalesced
+            let from = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 10 + i).into(), 40000);
+            harness
+                .socket
+                .state()
+                .incoming
+                .push_back(Ok((packet.clone(), from)));
+            harness.poll();
+            depths.push(harness.stats.queue_depth());
+        }
+        assert_eq!(depths, vec![1, 2, 3]);
+
+        harness.socket.state().stalled = false;
+        harness.poll();
+        assert_eq!(harness.stats.queue_depth(), 0);
+    }
+
+    #[test]
+    fn answers_unicast_without_multicast_membership() {
+        let socket = MockSocket::new();
+        socket.state().fail_joins = true;
+        let mut harness = Harness::<Inet6>::with_mock_socket(
+            Config::default(),
+            vec![iface("eth0", "fe80::2")],
+            0,
+            socket,
+        );
+        assert!(harness.socket.state().joins.is_empty());
+        assert!(!harness.stats.is_healthy());
+
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "[fe80::5]:49152");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing[0].1, "[fe80::5]:49152".parse().unwrap());
+    }
+
+    #[test]
+    fn retries_failed_joins_then_announces() {
+        let interval = Duration::from_secs(5);
+        let config = Config {
+            join_retry_interval: Some(interval),
+            ..without_jitter()
+        };
+        let socket = MockSocket::new();
+        socket.state().fail_joins = true;
+        let mut harness =
+            Harness::<Inet>::with_mock_socket(config, vec![iface("eth0", "10.0.0.2")], 0, socket);
+        harness.register("Test", "_http._tcp");
+
+        for _ in 0..2 {
+            harness.clock.advance(interval);
+            harness.poll();
+            assert!(harness.socket.state().joins.is_empty());
+            assert!(harness.socket.state().sent.is_empty());
+        }
+
+        harness.socket.state().fail_joins = false;
+        harness.clock.advance(interval);
+        harness.poll();
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![Join::V4(group_v4(), Ipv4Addr::new(10, 0, 0, 2))]
+        );
+        assert!(harness.stats.is_healthy());
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, "224.0.0.251:5353".parse().unwrap());
+        assert_eq!(
+            record_types(&sent[0].0),
+            vec![Type::PTR, Type::SRV, Type::TXT, Type::A]
+        );
+    }
+
+    #[test]
+    fn receive_errors_mark_socket_unhealthy() {
+        let mut harness = Harness::<Inet>::new(Config::default(), vec![iface("eth0", "10.0.0.2")]);
+        harness
+            .socket
+            .state()
+            .incoming
+            .push_back(Err(io::Error::from(io::ErrorKind::NotConnected)));
+        harness.poll();
+        assert!(!harness.stats.is_healthy());
+    }
+
+    #[test]
+    fn records_announcement_status() {
+        let mut harness = Harness::<Inet>::new(without_jitter(), vec![iface("eth0", "10.0.0.2")]);
+        harness.register("Test", "_http._tcp");
+        let svc = service("Test", "_http._tcp");
+        assert_eq!(
+            harness.stats.announcement_status(&svc.name).last_announced,
+            None
+        );
+
+        harness
+            .commands
+            .send(Command::SendUnsolicited {
+                svc: svc.clone(),
+                ttl: None,
+                include_ip: true,
+            })
+            .unwrap();
+        harness.poll();
+
+        let status = harness.stats.announcement_status(&svc.name);
+        assert_eq!(status.last_announced, Some(harness.clock.now()));
+        assert_eq!(status.announcements, 1);
+        assert_eq!(status.last_outcome, Some(SendOutcome::Sent));
+    }
+
+    #[test]
+    fn pins_v6_multicast_to_configured_interface() {
+        let interfaces = vec![
+            iface("eth0", "fe80::1"),
+            iface("wlan0", "10.0.0.3"),
+            iface("wlan0", "fe80::2"),
+        ];
+
+        let harness = Harness::<Inet6>::new(Config::default(), interfaces.clone());
+        assert_eq!(harness.socket.state().multicast_if, None);
+
+        let config = Config {
+            interfaces: Some(vec!["wlan0".to_owned()]),
+            ..Config::default()
+        };
+        let harness = Harness::<Inet6>::new(config, interfaces);
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+        assert_eq!(harness.socket.state().joins, vec![Join::V6(group, 2)]);
+        assert_eq!(
+            harness.socket.state().multicast_if,
+            Some(MulticastIf::V6(2))
+        );
+    }
+
+    /// Announces, then answers a multicast query, returning where to
+    fn multicast_destinations<AF: AddressFamily + Unpin>(
+        ip: &str,
+        querier: &str,
+    ) -> Vec<SocketAddr> {
+        let mut harness = Harness::<AF>::new(Config::default(), vec![iface("eth0", ip)]);
+        let svc = service("Test", "_http._tcp");
+        harness.services.write().register(svc.clone());
+        harness.fsm.send_unsolicited(&svc, None, true);
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, querier);
+        harness.poll();
+        let sent = harness.socket.state().sent.clone();
+        assert_eq!(sent.len(), 2);
+        sent.into_iter().map(|(_, addr)| addr).collect()
+    }
+
+    #[test]
+    fn multicasts_to_group_of_own_family() {
+        let v4 = SocketAddr::new(group_v4().into(), 5353);
+        assert_eq!(
+            multicast_destinations::<Inet>("10.0.0.2", "10.0.0.5:5353"),
+            vec![v4, v4]
+        );
+        let v6 = "[ff02::fb]:5353".parse().unwrap();
+        assert_eq!(
+            multicast_destinations::<Inet6>("fe80::1", "[fe80::5]:5353"),
+            vec![v6, v6]
+        );
+    }
+
+    #[test]
+    fn joins_and_multicasts_to_configured_group() {
+        let group = Ipv4Addr::new(239, 255, 0, 251);
+        let config = Config {
+            multicast_group_v4: Some(group),
+            ..without_jitter()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        assert_eq!(
+            harness.socket.state().joins,
+            vec![Join::V4(group, Ipv4Addr::new(10, 0, 0, 2))]
+        );
+
+        harness.register("Test", "_http._tcp");
+        let packet = query(&[("_http._tcp.local", QueryType::PTR)]);
+        harness.receive(&packet, "10.0.0.5:5353");
+        let outgoing = harness.take_outgoing();
+        assert_eq!(outgoing[0].1, SocketAddr::new(group.into(), 5353));
+        assert_eq!(answer_types(&outgoing[0].0), vec![Type::PTR]);
+    }
+
+    #[test]
+    fn ignores_questions_past_limit() {
+        let seen = Arc::new(Mutex::new(0));
+        let sink = seen.clone();
+        let config = Config {
+            query_observer: Some(QueryObserver::new(move |_, _| {
+                *sink.lock().unwrap() += 1;
+            })),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("web", "_http._tcp");
+
+        let names: Vec<_> = (0..1000).map(|i| format!("host-{}.local", i)).collect();
+        let questions: Vec<_> = names.iter().map(|n| (&n[..], QueryType::A)).collect();
+        harness.receive(&query(&questions), "10.0.0.5:5353");
+
+        assert_eq!(*seen.lock().unwrap(), 100);
+        assert_eq!(harness.stats.ignored_questions(), 900);
+    }
+
+    #[test]
+    fn observes_every_question() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let config = Config {
+            query_observer: Some(QueryObserver::new(move |from, question| {
+                sink.lock().unwrap().push((from, question.clone()));
+            })),
+            ..Config::default()
+        };
+        let mut harness = Harness::<Inet>::new(config, vec![iface("eth0", "10.0.0.2")]);
+        harness.register("web", "_http._tcp");
+
+        let packet = query(&[
+            ("_http._tcp.local", QueryType::PTR),
+            ("test-host.local", QueryType::A),
+            ("_printer._tcp.local", QueryType::PTR),
+        ]);
+        harness.receive(&packet, "10.0.0.5:5353");
+
+        let from: SocketAddr = "10.0.0.5:5353".parse().unwrap();
+        let observed = |name: &str, qtype| {
+            (
+                from,
+                ObservedQuestion {
+                    name: name.to_owned(),
+                    qtype: u16::from(qtype),
+                    unicast_response: false,
+                },
+            )
+        };
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                observed("_http._tcp.local", QueryType::PTR),
+                observed("test-host.local", QueryType::A),
+                observed("_printer._tcp.local", QueryType::PTR),
+            ]
+        );
+        // Observing doesn't get in the way of answering
+        assert_eq!(harness.take_outgoing().len(), 1);
     }
 }
@@ -0,0 +1,93 @@
+use get_if_addrs::{get_if_addrs, Interface};
+use std::io;
+
+/// Where `fsm` learns about the host's network interfaces
+pub trait Interfaces: Send {
+    fn list(&self) -> io::Result<Vec<Interface>>;
+    /// The OS index of an interface, as needed by the IPv6 multicast options
+    fn index(&self, name: &str) -> io::Result<u32>;
+}
+
+pub struct SystemInterfaces;
+
+impl Interfaces for SystemInterfaces {
+    fn list(&self) -> io::Result<Vec<Interface>> {
+        get_if_addrs()
+    }
+
+    #[cfg(unix)]
+    fn index(&self, name: &str) -> io::Result<u32> {
+        let name = std::ffi::CString::new(name).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "interface name contains NUL")
+        })?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(io::Error::last_os_error()),
+            index => Ok(index),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn index(&self, _name: &str) -> io::Result<u32> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "interface indices are not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+pub use self::mock::MockInterfaces;
+
+#[cfg(test)]
+mod mock {
+    use super::Interfaces;
+    use get_if_addrs::Interface;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    /// A changeable list of interfaces, indexed from 1 in order of first
+    /// appearance, which can be made to fail listing them
+    #[derive(Clone, Default)]
+    pub struct MockInterfaces(Arc<Mutex<Vec<Interface>>>, Arc<AtomicBool>);
+
+    impl MockInterfaces {
+        pub fn new(interfaces: Vec<Interface>) -> Self {
+            MockInterfaces(Arc::new(Mutex::new(interfaces)), Arc::default())
+        }
+
+        pub fn set_failing(&self, failing: bool) {
+            self.1.store(failing, Ordering::SeqCst);
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, Vec<Interface>> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    impl Interfaces for MockInterfaces {
+        fn list(&self) -> io::Result<Vec<Interface>> {
+            if self.1.load(Ordering::SeqCst) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "listing interfaces failed",
+                ));
+            }
+            Ok(self.lock().clone())
+        }
+
+        fn index(&self, name: &str) -> io::Result<u32> {
+            let mut names: Vec<&str> = Vec::new();
+            let interfaces = self.lock();
+            for iface in interfaces.iter() {
+                if !names.contains(&&iface.name[..]) {
+                    names.push(&iface.name);
+                }
+            }
+            match names.iter().position(|n| *n == name) {
+                Some(pos) => Ok(pos as u32 + 1),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such interface")),
+            }
+        }
+    }
+}
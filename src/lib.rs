@@ -1,55 +1,126 @@
-use futures_util::{future, future::FutureExt};
-use log::warn;
+// DNS record and query type mnemonics are spelled as in the RFCs
+#![allow(clippy::upper_case_acronyms)]
+// `io::Error::other` needs Rust 1.74
+#![allow(clippy::io_other_error)]
+// `Option::is_none_or` needs Rust 1.82, and `Option::is_some_and` 1.70
+#![allow(clippy::unnecessary_map_or)]
+
+use futures_util::{future, future::FutureExt, stream::Stream};
+use log::{debug, warn};
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::io;
 use std::marker::Unpin;
-use std::sync::{Arc, RwLock};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 
 use std::thread;
 use tokio::{
     runtime::{Handle, Runtime},
-    sync::mpsc,
+    sync::{mpsc, oneshot},
 };
 
 mod dns_parser;
 use crate::dns_parser::Name;
 
 mod address_family;
+mod browser;
+mod clock;
+mod config;
+mod error;
 mod fsm;
+mod interfaces;
 mod services;
+mod socket;
+mod stats;
+
+pub use crate::browser::{BrowseEvent, BrowserConfig, DiscoveredService, QueryPort};
+pub use crate::config::{
+    Config, Conflict, ConflictObserver, ConflictResolution, HostnameRewrite, NoAddresses,
+    ObservedQuestion, OutgoingFilter, PacketTap, Placement, PtrInstances, PtrResponse,
+    QueryObserver, RateLimit, Schedule, Section, Subnet, SubnetError, TappedPacket, Ttls,
+};
+pub use crate::error::Error;
+pub use crate::services::{Families, RawRecord, ServiceRecord};
+pub use crate::stats::{AnnouncementStatus, SendOutcome};
 
 use crate::address_family::{Inet, Inet6};
+use crate::browser::{Discovered, Query};
 use crate::fsm::{Command, FSM};
-use crate::services::{ServiceData, Services, ServicesInner};
+use crate::services::{
+    check_name, check_txt, encode_txt, ServiceData, Services, ServicesInner, SharedServices, TxtFn,
+    MAX_RAW_RDATA,
+};
+use crate::stats::{SharedStats, Stats};
 
-const DEFAULT_TTL: u32 = 60;
+/// TTL of the records `Config::ttls` has no entry for, i.e. NSEC ones
+const OTHER_TTL: u32 = 60;
 const MDNS_PORT: u16 = 5353;
 
+/// Most changes a `Browser` queues until they are read
+pub const BROWSE_EVENT_QUEUE: usize = 256;
+
+/// Most questions `Service::queries` queues until they are read
+pub const SERVICE_QUERY_QUEUE: usize = 256;
+
 pub struct Responder {
     services: Services,
+    stats: SharedStats,
     commands: RefCell<CommandSender>,
     shutdown: Arc<Shutdown>,
+    /// See `Config::announce_on_register`
+    announce_on_register: bool,
+    /// See `Config::passive_only`
+    passive_only: bool,
+    /// What `inject_packet` answers with
+    #[cfg(feature = "test-util")]
+    config: Arc<Config>,
 }
 
 pub struct Service {
     id: usize,
     services: Services,
+    stats: SharedStats,
     commands: CommandSender,
     _shutdown: Arc<Shutdown>,
 }
 
 type ResponderTask = Box<dyn Future<Output = ()> + Send + Unpin>;
 
+/// Looks for instances of a service type on the network
+///
+/// Besides listing what it found, it is a stream of the changes to that.
+/// They are queued until read, up to `BROWSE_EVENT_QUEUE` of them: while
+/// the queue is full further changes are dropped, though what is listed
+/// stays current.
+pub struct Browser {
+    discovered: Discovered,
+    events: mpsc::Receiver<BrowseEvent>,
+    /// Pass `Browser::reconfirm` on to the query tasks
+    reconfirms: Vec<mpsc::UnboundedSender<String>>,
+    /// Dropping these stops the query tasks
+    _stop: Vec<oneshot::Sender<()>>,
+}
+
+type BrowserTask = Box<dyn Future<Output = ()> + Send + Unpin>;
+
 impl Responder {
     pub fn new() -> io::Result<Responder> {
+        Self::new_with_config(Config::default())
+    }
+
+    pub fn new_with_config(config: Config) -> io::Result<Responder> {
         let (tx, rx) = std::sync::mpsc::sync_channel(0);
         thread::Builder::new()
             .name("mdns-responder".to_owned())
             .spawn(move || {
                 let mut rt = Runtime::new().unwrap();
                 rt.block_on(async {
-                    match Self::with_default_handle() {
+                    match Self::with_config(config) {
                         Ok((responder, task)) => {
                             tx.send(Ok(responder)).expect("tx responder channel closed");
                             task.await;
@@ -62,32 +133,27 @@ impl Responder {
     }
 
     pub fn spawn(handle: &Handle) -> io::Result<Responder> {
-        let (responder, task) = Self::with_default_handle()?;
+        Self::spawn_with_config(handle, Config::default())
+    }
+
+    pub fn spawn_with_config(handle: &Handle, config: Config) -> io::Result<Responder> {
+        let (responder, task) = Self::with_config(config)?;
         handle.spawn(task);
         Ok(responder)
     }
 
     pub fn with_default_handle() -> io::Result<(Responder, ResponderTask)> {
-        let mut hostname = match hostname::get() {
-            Ok(s) => match s.into_string() {
-                Ok(s) => s,
-                Err(_) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Hostname not valid unicode",
-                    ))
-                }
-            },
-            Err(err) => return Err(err),
-        };
-        if !hostname.ends_with(".local") {
-            hostname.push_str(".local");
-        }
+        Self::with_config(Config::default())
+    }
 
-        let services = Arc::new(RwLock::new(ServicesInner::new(hostname)));
+    pub fn with_config(config: Config) -> io::Result<(Responder, ResponderTask)> {
+        config.check()?;
+        let services = local_services()?;
 
-        let v4 = FSM::<Inet>::new(&services);
-        let v6 = FSM::<Inet6>::new(&services);
+        let stats = Arc::new(Stats::new(&config));
+        let config = Arc::new(config);
+        let v4 = FSM::<Inet>::new(&services, &config, &stats);
+        let v6 = FSM::<Inet6>::new(&services, &config, &stats);
 
         let (task, commands): (ResponderTask, _) = match (v4, v6) {
             (Ok((v4_task, v4_command)), Ok((v6_task, v6_command))) => {
@@ -103,59 +169,854 @@ impl Responder {
             (Err(err), _) => return Err(err),
         };
 
+        Ok(Self::assemble(&config, services, stats, task, commands))
+    }
+
+    /// Answers over `socket` alone, rather than binding sockets of its own
+    ///
+    /// The socket must be bound to port 5353 and already be a member of
+    /// the multicast group of its family, e.g. as passed in by systemd's
+    /// socket activation. Memberships are left to the application.
+    // Will panic if called from outside the context of a runtime
+    pub fn from_socket(
+        socket: std::net::UdpSocket,
+        config: Config,
+    ) -> io::Result<(Responder, ResponderTask)> {
+        config.check()?;
+        let services = local_services()?;
+
+        let stats = Arc::new(Stats::new(&config));
+        let config = Arc::new(config);
+        let (task, command): (ResponderTask, _) = if socket.local_addr()?.is_ipv6() {
+            let (fsm, command) = FSM::<Inet6>::from_socket(socket, &services, &config, &stats)?;
+            (Box::new(fsm), command)
+        } else {
+            let (fsm, command) = FSM::<Inet>::from_socket(socket, &services, &config, &stats)?;
+            (Box::new(fsm), command)
+        };
+
+        Ok(Self::assemble(
+            &config,
+            services,
+            stats,
+            task,
+            vec![command],
+        ))
+    }
+
+    fn assemble(
+        config: &Config,
+        services: Services,
+        stats: SharedStats,
+        task: ResponderTask,
+        commands: Vec<mpsc::UnboundedSender<Command>>,
+    ) -> (Responder, ResponderTask) {
         let commands = CommandSender(commands);
         let responder = Responder {
-            services: services,
+            services,
+            stats,
             commands: RefCell::new(commands.clone()),
             shutdown: Arc::new(Shutdown(commands)),
+            announce_on_register: config.announce_on_register,
+            passive_only: config.passive_only,
+            #[cfg(feature = "test-util")]
+            config: Arc::new(config.clone()),
         };
 
-        Ok((responder, task))
+        (responder, task)
     }
 }
 
+/// The services of this host, under its hostname
+fn local_services() -> io::Result<Services> {
+    let hostname = match hostname::get() {
+        Ok(s) => match s.into_string() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::HostnameNotUnicode.into()),
+        },
+        Err(err) => return Err(err),
+    };
+    let hostname = local_hostname(&hostname);
+    check_name(&hostname)?;
+
+    Ok(Arc::new(SharedServices::new(ServicesInner::new(hostname))))
+}
+
 impl Responder {
-    pub fn register(&self, svc_type: String, svc_name: String, port: u16, txt: &[&str]) -> Service {
-        let txt = if txt.is_empty() {
-            vec![0]
-        } else {
-            txt.into_iter()
-                .flat_map(|entry| {
-                    let entry = entry.as_bytes();
-                    if entry.len() > 255 {
-                        panic!("{:?} is too long for a TXT record", entry);
-                    }
-                    std::iter::once(entry.len() as u8).chain(entry.into_iter().cloned())
-                })
-                .collect()
-        };
+    /// Says goodbye for every registered service and stops responding
+    ///
+    /// Completes once the goodbyes have been sent. Dropping the `Service`
+    /// handles afterwards has no further effect.
+    pub async fn shutdown(self) {
+        let drained = self.commands.borrow_mut().send_drain();
+        for rx in drained {
+            // An error means the task is gone already, which is fine too
+            let _ = rx.await;
+        }
+    }
 
-        let svc = ServiceData {
-            typ: Name::from_str(format!("{}.local", svc_type)).unwrap(),
-            name: Name::from_str(format!("{}.{}.local", svc_name, svc_type)).unwrap(),
-            port: port,
-            txt: txt,
-        };
+    /// Completes once every registered service has been announced, after
+    /// probing for its name with `Config::probe_before_announcing`
+    ///
+    /// Services which aren't announced are not waited for: disabled or
+    /// restricted ones, those outside their schedule, and any at all with
+    /// `Config::passive_only`. With `Config::announce_on_register` off, the
+    /// rest are waited for until `Service::announce` is called.
+    ///
+    /// Announcements are counted by service, not by address family, so with
+    /// both IPv4 and IPv6 up a service counts as announced as soon as either
+    /// family has announced it, the other one possibly not having yet.
+    pub async fn ready(&self) {
+        if self.passive_only {
+            return;
+        }
+        let mut announced = self.stats.announced();
+        while !self.announced_all() {
+            if announced.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    fn announced_all(&self) -> bool {
+        let now = SystemTime::now();
+        services::read(&self.services)
+            .all()
+            .filter(|svc| svc.enabled && !svc.is_restricted() && svc.is_scheduled(now))
+            .all(|svc| self.stats.announcement_status(&svc.name).announcements > 0)
+    }
+
+    /// Whether the sockets seem usable: none has failed to receive, and
+    /// with `Config::health_check_interval` set, each received its last
+    /// self-query back
+    pub fn is_healthy(&self) -> bool {
+        self.stats.is_healthy()
+    }
 
+    /// Sources that queried the responder recently, see
+    /// `Config::querier_window`
+    ///
+    /// Each comes with the number of queries it sent since it was last
+    /// quiet for a whole window, and when it sent the last one. At most
+    /// 1024 are kept, the least recent making way for new ones.
+    pub fn recent_queriers(&self) -> Vec<(SocketAddr, u32, Instant)> {
+        self.stats.recent_queriers(Instant::now())
+    }
+
+    /// Questions left unanswered since the responder started, for being past
+    /// `Config::max_questions` in their packet
+    pub fn ignored_questions(&self) -> u64 {
+        self.stats.ignored_questions()
+    }
+
+    /// Queries answered with copies of responses built for earlier ones,
+    /// see `Config::prebuilt_responses`
+    pub fn reused_responses(&self) -> u64 {
+        self.stats.reused_responses()
+    }
+
+    /// Datagrams built but not sent since the responder started, for
+    /// observing only, see `set_observe_only`
+    pub fn withheld_datagrams(&self) -> u64 {
+        self.stats.withheld_datagrams()
+    }
+
+    /// Datagrams waiting to be sent, as of the last time the responder ran
+    ///
+    /// A queue that keeps growing means the sockets can't send fast enough,
+    /// or not at all.
+    pub fn queue_depth(&self) -> usize {
+        self.stats.queue_depth()
+    }
+
+    /// Changes the hostname services are advertised on, e.g. after the user
+    /// renamed the device
+    ///
+    /// Peers are told to forget the addresses of the old name, and every
+    /// service is announced again pointing at the new one.
+    ///
+    /// Fails with `Error::InvalidName` if the hostname can't be used in DNS
+    /// names, changing nothing.
+    pub fn set_hostname(&self, hostname: &str) -> Result<(), Error> {
+        let hostname = local_hostname(hostname);
+        check_name(&hostname)?;
+        let name = Name::from_str(hostname).unwrap();
+        let previous = services::read(&self.services).get_hostname().clone();
+        if name == previous {
+            return Ok(());
+        }
+        self.commands.borrow_mut().send(|| Command::SetHostname {
+            name: name.clone(),
+            previous: previous.clone(),
+        });
+        Ok(())
+    }
+
+    /// Keeps answering queries and announcing services, but drops every
+    /// datagram instead of sending it, e.g. to debug what the responder
+    /// would say without it saying anything
+    pub fn set_observe_only(&self, observe_only: bool) {
         self.commands
             .borrow_mut()
-            .send_unsolicited(svc.clone(), DEFAULT_TTL, true);
+            .send(|| Command::SetObserveOnly(observe_only));
+    }
+
+    /// Registers a service and announces it
+    ///
+    /// A `port` of 0 registers a service which is nothing but metadata,
+    /// which gets a PTR and a TXT record but no SRV record.
+    ///
+    /// # Panics
+    ///
+    /// * `try_register` would fail with the same values.
+    pub fn register(&self, svc_type: String, svc_name: String, port: u16, txt: &[&str]) -> Service {
+        let svc = service_data(&svc_type, &svc_name, port, txt);
+
+        self.announce_registered(&svc);
+        self.handle(svc)
+    }
+
+    /// Registers a service and announces it, like `register` but failing
+    /// rather than panicking if it can't be advertised
+    ///
+    /// Fails with `Error::InvalidName` if the name or type has an empty
+    /// label or one longer than 62 bytes, or if they are too long together,
+    /// and with `Error::TxtEntryTooLong` if an entry of `txt` is longer than
+    /// 255 bytes. The other `register_*` functions panic in these cases.
+    pub fn try_register(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+    ) -> Result<Service, Error> {
+        let svc = try_service_data(&svc_type, &svc_name, port, txt)?;
+
+        self.announce_registered(&svc);
+        Ok(self.handle(svc))
+    }
+
+    /// Registers a service whose TXT entries are produced by `txt` each time
+    /// they are sent, for metadata that should always be current
+    ///
+    /// `txt` runs on the responder task while it answers, so it must be
+    /// cheap and must not block. Entries longer than 255 bytes are dropped.
+    pub fn register_with_txt_fn<F>(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: F,
+    ) -> Service
+    where
+        F: Fn() -> Vec<String> + Send + Sync + 'static,
+    {
+        let mut svc = service_data(&svc_type, &svc_name, port, &[]);
+        svc.txt_fn = Some(TxtFn::new(txt));
+
+        self.announce_registered(&svc);
+        self.handle(svc)
+    }
+
+    /// Registers a service with the given SRV priority and weight
+    ///
+    /// Several services may be registered under the same name and type on
+    /// different ports, e.g. to balance load between them. SRV answers list
+    /// all of them in an order picked by priority and weight (RFC 2782).
+    pub fn register_with_priority(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+        priority: u16,
+        weight: u16,
+    ) -> Service {
+        let mut svc = service_data(&svc_type, &svc_name, port, txt);
+        svc.priority = priority;
+        svc.weight = weight;
+        self.announce_registered(&svc);
+        self.handle(svc)
+    }
+
+    /// Registers a service with TXT entries that are only sent by unicast,
+    /// e.g. details too bulky to multicast to the whole link
+    ///
+    /// Announcements and multicast responses carry `txt` alone. Anyone
+    /// asking with the QU bit or from another port gets the private entries
+    /// though, with no authentication, so they must not hold secrets such
+    /// as session tokens.
+    ///
+    /// # Panics
+    ///
+    /// * An entry is longer than 255 bytes.
+    pub fn register_with_private_txt(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+        private_txt: &[&str],
+    ) -> Service {
+        let mut svc = service_data(&svc_type, &svc_name, port, txt);
+        if !private_txt.is_empty() {
+            svc.private_txt = encode_txt(private_txt);
+        }
+
+        self.announce_registered(&svc);
+        self.handle(svc)
+    }
+
+    /// Registers a service only queriers in `subnets` get to see
+    ///
+    /// As everyone on the link hears multicast, the service is never
+    /// announced and always answered by unicast.
+    pub fn register_for_subnets(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+        subnets: Vec<Subnet>,
+    ) -> Service {
+        let mut svc = service_data(&svc_type, &svc_name, port, txt);
+        svc.subnets = Some(subnets);
+        self.handle(svc)
+    }
+
+    /// Registers a service with records of types the crate doesn't know,
+    /// which are sent in answer to queries for the service's name
+    ///
+    /// Fails with `Error::RdataTooLong` if the rdata of a record is longer
+    /// than 1024 bytes, registering nothing, and like `try_register` for the
+    /// other values.
+    pub fn register_with_records(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+        records: Vec<RawRecord>,
+    ) -> Result<Service, Error> {
+        if let Some(record) = records.iter().find(|r| r.rdata.len() > MAX_RAW_RDATA) {
+            return Err(Error::RdataTooLong(record.rtype, record.rdata.len()));
+        }
+        let mut svc = try_service_data(&svc_type, &svc_name, port, txt)?;
+        svc.records = records;
 
-        let id = self.services.write().unwrap().register(svc);
+        self.announce_registered(&svc);
+        Ok(self.handle(svc))
+    }
+
+    /// Advertises the model of this device, e.g. `"MacBookPro16,1"`
+    ///
+    /// Apple's discovery UIs look this up under `_device-info._tcp` to pick
+    /// an icon, using the same `name` as the device's other services.
+    pub fn register_device_info(&self, name: String, model: &str) -> Service {
+        let model = format!("model={}", model);
+        self.register("_device-info._tcp".to_owned(), name, 0, &[&model])
+    }
+
+    /// Registers several services at once
+    ///
+    /// Their announcements are coalesced into as few packets as they fit in,
+    /// which cuts down on startup traffic compared to calling `register` for
+    /// each of them. With `Config::probe_before_announcing`, they are probed
+    /// for together too, every probe asking about all of them.
+    pub fn register_all(&self, configs: Vec<ServiceConfig>) -> ServiceGroup {
+        let svcs: Vec<_> = configs.iter().map(ServiceConfig::service_data).collect();
+
+        if self.announce_on_register {
+            self.commands
+                .borrow_mut()
+                .send_unsolicited_batch(svcs.clone(), None, true);
+        }
+
+        ServiceGroup {
+            services: svcs.into_iter().map(|svc| self.handle(svc)).collect(),
+        }
+    }
+
+    /// Announces a service once without registering it, e.g. to let others
+    /// know this device is here, taking the same values as `register`
+    ///
+    /// Queries for the service go unanswered, unlike for a registered one.
+    /// With `goodbye_after` set, peers are told to forget it again once
+    /// that long has passed.
+    pub fn announce_once(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+        goodbye_after: Option<Duration>,
+    ) {
+        let svc = service_data(&svc_type, &svc_name, port, txt);
+        self.commands.borrow_mut().send(|| Command::AnnounceOnce {
+            svc: svc.clone(),
+            goodbye_after,
+        });
+    }
+
+    /// Creates an empty `ServiceSet`, for services that come and go in bulk
+    pub fn service_set(&self) -> ServiceSet {
+        ServiceSet {
+            services: self.services.clone(),
+            stats: self.stats.clone(),
+            commands: self.commands.borrow().clone(),
+            registered: BTreeMap::new(),
+            _shutdown: self.shutdown.clone(),
+        }
+    }
+
+    fn announce_registered(&self, svc: &ServiceData) {
+        if self.announce_on_register {
+            self.commands
+                .borrow_mut()
+                .send_unsolicited(svc.clone(), None, true);
+        }
+    }
+
+    fn handle(&self, svc: ServiceData) -> Service {
+        let id = services::write(&self.services).register(svc);
 
         Service {
-            id: id,
+            id,
             commands: self.commands.borrow().clone(),
             services: self.services.clone(),
+            stats: self.stats.clone(),
             _shutdown: self.shutdown.clone(),
         }
     }
 }
 
+/// Qualifies a hostname with ".local", unless it already is
+fn local_hostname(hostname: &str) -> String {
+    let hostname = hostname.trim_end_matches('.');
+    if hostname.ends_with(".local") {
+        hostname.to_owned()
+    } else {
+        format!("{}.local", hostname)
+    }
+}
+
+/// Like `try_service_data`, for the registration functions that panic on
+/// values which can't be advertised
+fn service_data(svc_type: &str, svc_name: &str, port: u16, txt: &[&str]) -> ServiceData {
+    try_service_data(svc_type, svc_name, port, txt).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn try_service_data(
+    svc_type: &str,
+    svc_name: &str,
+    port: u16,
+    txt: &[&str],
+) -> Result<ServiceData, Error> {
+    // Tolerate "_http._tcp." as well as "_http._tcp"
+    let svc_type = svc_type.trim_end_matches('.');
+    let name = format!("{}.{}.local", svc_name, svc_type);
+    // The type being a suffix of the name, it fits if the name does
+    check_name(&name)?;
+    check_txt(txt)?;
+    Ok(ServiceData {
+        typ: Name::from_str(format!("{}.local", svc_type)).unwrap(),
+        name: Name::from_str(name).unwrap(),
+        port,
+        txt: encode_txt(txt),
+        txt_fn: None,
+        subnets: None,
+        records: Vec::new(),
+        private_txt: Vec::new(),
+        priority: 0,
+        weight: 0,
+        enabled: true,
+        schedule: None,
+        discoverable: true,
+        suppressed: Vec::new(),
+        families: Families::Both,
+        queries: None,
+    })
+}
+
+/// A service to register with `Responder::register_all`, taking the same
+/// values as `Responder::register`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceConfig {
+    pub svc_type: String,
+    pub svc_name: String,
+    pub port: u16,
+    pub txt: Vec<String>,
+}
+
+impl ServiceConfig {
+    fn service_data(&self) -> ServiceData {
+        let txt: Vec<&str> = self.txt.iter().map(String::as_str).collect();
+        service_data(&self.svc_type, &self.svc_name, self.port, &txt)
+    }
+}
+
+/// The services registered by one `Responder::register_all` call, which are
+/// all unregistered when this is dropped
+pub struct ServiceGroup {
+    services: Vec<Service>,
+}
+
+impl ServiceGroup {
+    pub fn services(&self) -> &[Service] {
+        &self.services
+    }
+
+    /// Announces every service of the group, coalesced like they would have
+    /// been by `Responder::register_all`
+    pub fn announce(&self) {
+        let svcs: Vec<_> = match self.services.first() {
+            Some(first) => {
+                let services = services::read(&first.services);
+                self.services
+                    .iter()
+                    .filter_map(|svc| services.find_by_id(svc.id).cloned())
+                    .collect()
+            }
+            None => return,
+        };
+        self.services[0]
+            .commands
+            .clone()
+            .send_unsolicited_batch(svcs, None, true);
+    }
+}
+
+impl Browser {
+    /// Starts browsing for `svc_type`, e.g. `"_http._tcp"`, on the runtime
+    /// behind `handle`
+    pub fn spawn(handle: &Handle, svc_type: &str, config: BrowserConfig) -> io::Result<Browser> {
+        let (browser, task) = Self::with_config(svc_type, config)?;
+        handle.spawn(task);
+        Ok(browser)
+    }
+
+    // Will panic if called from outside the context of a runtime
+    pub fn with_config(
+        svc_type: &str,
+        config: BrowserConfig,
+    ) -> io::Result<(Browser, BrowserTask)> {
+        let svc_type = svc_type.trim_end_matches('.');
+        let service_type = Name::from_str(format!("{}.local", svc_type)).unwrap();
+        let discovered = Discovered::default();
+
+        let (v4_stop, v4_stopped) = oneshot::channel();
+        let (v6_stop, v6_stopped) = oneshot::channel();
+        let (events_tx, events) = mpsc::channel(BROWSE_EVENT_QUEUE);
+        let (v4_reconfirm, v4_reconfirms) = mpsc::unbounded_channel();
+        let (v6_reconfirm, v6_reconfirms) = mpsc::unbounded_channel();
+        let v4 = Query::<Inet>::new(
+            service_type.clone(),
+            &config,
+            &discovered,
+            &events_tx,
+            v4_stopped,
+            v4_reconfirms,
+        );
+        let v6 = Query::<Inet6>::new(
+            service_type,
+            &config,
+            &discovered,
+            &events_tx,
+            v6_stopped,
+            v6_reconfirms,
+        );
+
+        let (task, stop, reconfirms): (BrowserTask, _, _) = match (v4, v6) {
+            (Ok(v4_task), Ok(v6_task)) => {
+                let tasks = future::join(v4_task, v6_task).map(|((), ())| ());
+                (
+                    Box::new(tasks),
+                    vec![v4_stop, v6_stop],
+                    vec![v4_reconfirm, v6_reconfirm],
+                )
+            }
+            (Ok(v4_task), Err(err)) => {
+                warn!("Failed to browse over IPv6: {:?}", err);
+                (Box::new(v4_task), vec![v4_stop], vec![v4_reconfirm])
+            }
+            (Err(err), _) => return Err(err),
+        };
+
+        let browser = Browser {
+            discovered,
+            events,
+            reconfirms,
+            _stop: stop,
+        };
+        Ok((browser, task))
+    }
+
+    /// Checks that `instance`, e.g. `"Printer._ipp._tcp.local"`, is still
+    /// there, e.g. after connecting to it failed (RFC 6762 section 10.3)
+    ///
+    /// It is asked for its SRV record twice, and is forgotten with a
+    /// `BrowseEvent::Removed` unless it answers within ten seconds.
+    pub fn reconfirm(&self, instance: &str) {
+        let instance = instance.trim_end_matches('.');
+        for tx in &self.reconfirms {
+            // Only fails once the task is gone, along with the instance
+            let _ = tx.send(instance.to_owned());
+        }
+    }
+
+    /// The instances found and resolved so far, ordered by name
+    pub fn services(&self) -> Vec<DiscoveredService> {
+        let discovered = self.discovered.lock().unwrap();
+        let mut services: Vec<_> = discovered
+            .values()
+            .filter(|cached| cached.is_resolved())
+            .map(|cached| cached.service.clone())
+            .collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        services
+    }
+}
+
+/// Services registered on behalf of others, such as the devices a gateway
+/// bridges from another network, which are all unregistered when this is
+/// dropped
+///
+/// Unlike registering each one as a `Service`, the set is changed as a
+/// whole by `update`, which only sends what changed.
+pub struct ServiceSet {
+    services: Services,
+    stats: SharedStats,
+    commands: CommandSender,
+    /// Ids of the registered services, by type and name
+    registered: BTreeMap<(String, String), (ServiceConfig, usize)>,
+    _shutdown: Arc<Shutdown>,
+}
+
+impl ServiceSet {
+    /// Makes `configs` the registered services
+    ///
+    /// Goodbyes for the services that are gone and announcements for the
+    /// new ones are each coalesced into as few packets as they fit in, and
+    /// nothing is sent for services that stay the same. A service whose
+    /// port or TXT changed gets both, as nothing else would flush its old
+    /// records from caches.
+    pub fn update(&mut self, configs: Vec<ServiceConfig>) {
+        let mut wanted: BTreeMap<_, _> = configs
+            .into_iter()
+            .map(|config| ((config.svc_type.clone(), config.svc_name.clone()), config))
+            .collect();
+        let mut goodbyes = Vec::new();
+        let mut announcements = Vec::new();
+        {
+            let mut services = services::write(&self.services);
+            for (key, (config, id)) in std::mem::take(&mut self.registered) {
+                match wanted.remove(&key) {
+                    Some(ref new) if *new == config => {
+                        self.registered.insert(key, (config, id));
+                    }
+                    // Announced again below
+                    Some(changed) => {
+                        goodbyes.push(services.unregister(id));
+                        wanted.insert(key, changed);
+                    }
+                    None => {
+                        let svc = services.unregister(id);
+                        self.stats.forget(&svc.name);
+                        goodbyes.push(svc);
+                    }
+                }
+            }
+            for (key, config) in wanted {
+                let svc = config.service_data();
+                let id = services.register(svc.clone());
+                announcements.push(svc);
+                self.registered.insert(key, (config, id));
+            }
+        }
+
+        if !goodbyes.is_empty() {
+            self.commands
+                .send_unsolicited_batch(goodbyes, Some(0), false);
+        }
+        if !announcements.is_empty() {
+            self.commands
+                .send_unsolicited_batch(announcements, None, true);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.registered.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registered.is_empty()
+    }
+}
+
+impl Drop for ServiceSet {
+    fn drop(&mut self) {
+        self.update(Vec::new());
+    }
+}
+
+/// The questions about one service, see `Service::queries`
+pub struct ServiceQueries(mpsc::Receiver<(SocketAddr, ObservedQuestion)>);
+
+impl Stream for ServiceQueries {
+    type Item = (SocketAddr, ObservedQuestion);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+impl Stream for Browser {
+    type Item = BrowseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<BrowseEvent>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+impl Service {
+    /// Announces the service, e.g. after registering it with
+    /// `Config::announce_on_register` off
+    pub fn announce(&self) {
+        let svc = services::read(&self.services).find_by_id(self.id).cloned();
+        if let Some(svc) = svc {
+            self.commands.clone().send_unsolicited(svc, None, true);
+        }
+    }
+
+    /// Makes the service discoverable or not, e.g. a printer only while it
+    /// is online
+    ///
+    /// Disabling it says goodbye, and enabling it again announces it.
+    pub fn set_enabled(&self, enabled: bool) {
+        let svc = {
+            let mut services = services::write(&self.services);
+            match services.set_enabled(self.id, enabled) {
+                Some(svc) => svc,
+                None => return,
+            }
+        };
+        let mut commands = self.commands.clone();
+        if enabled {
+            commands.send_unsolicited(svc, None, true);
+        } else {
+            commands.send_unsolicited(svc, Some(0), false);
+        }
+    }
+
+    /// Lists the service when browsing for its type or not, hiding it from
+    /// everyone but those who know its name
+    ///
+    /// Hidden services still answer queries for their SRV and TXT records,
+    /// but are left out of PTR answers and announcements carry no PTR
+    /// record for them. Hiding a service says goodbye for its PTR record,
+    /// so peers which browsed already stop listing it.
+    pub fn set_discoverable(&self, discoverable: bool) {
+        let hidden = {
+            let mut services = services::write(&self.services);
+            let changed = services.set_discoverable(self.id, discoverable);
+            services
+                .find_by_id(self.id)
+                .filter(|_| changed && !discoverable)
+                .cloned()
+        };
+        if let Some(svc) = hidden {
+            self.commands.clone().send(|| Command::Hide(svc.clone()));
+        }
+    }
+
+    /// Never sends `records` for the service, e.g. `ServiceRecord::Txt` to
+    /// keep its metadata from peers, replacing those suppressed before
+    ///
+    /// Queries for a suppressed record go unanswered, and it is left out of
+    /// other answers and announcements alike.
+    pub fn set_suppressed(&self, records: &[ServiceRecord]) {
+        services::write(&self.services).set_suppressed(self.id, records.to_vec());
+    }
+
+    /// Advertises the service over IPv4 or IPv6 alone, e.g. for a legacy
+    /// device only reachable over IPv4
+    ///
+    /// The responder of the other family stops answering for the service,
+    /// and with `Config::include_both_families` responses only carry
+    /// addresses of these families. Peers which learnt of the service
+    /// over the other family keep it until their records expire.
+    pub fn set_families(&self, families: Families) {
+        services::write(&self.services).set_families(self.id, families);
+    }
+
+    /// Answers for the service under the instance name `alias` too, e.g.
+    /// `"Old name"`, with the same records as they change
+    ///
+    /// Returns `Ok(false)` if the name is taken, and fails with
+    /// `Error::InvalidName` like `Responder::try_register` if it can't be
+    /// advertised. Aliases are listed when browsing but never announced,
+    /// and go away with the service.
+    pub fn add_alias(&self, alias: &str) -> Result<bool, Error> {
+        let mut services = services::write(&self.services);
+        let typ = match services.find_by_id(self.id) {
+            Some(svc) => svc.typ.clone(),
+            None => return Ok(false),
+        };
+        let alias = format!("{}.{}", alias, typ);
+        check_name(&alias)?;
+        Ok(services.add_alias(self.id, Name::from_str(alias).unwrap()))
+    }
+
+    /// Advertises the service only within the hours of `schedule` each day,
+    /// or all day for `None`
+    ///
+    /// It says goodbye when the hours end, if it was advertised at all, and
+    /// announces itself when they start again.
+    pub fn set_schedule(&self, schedule: Option<Schedule>) {
+        let changed = services::write(&self.services).set_schedule(self.id, schedule);
+        if let Some((name, previous)) = changed {
+            self.commands.clone().send(|| Command::Reschedule {
+                name: name.clone(),
+                previous,
+            });
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        let services = services::read(&self.services);
+        services
+            .find_by_id(self.id)
+            .map_or(false, |svc| svc.enabled)
+    }
+
+    /// Streams the questions received about this service, i.e. for its
+    /// type or its name, with who asked them
+    ///
+    /// Like `Config::query_observer`, but for this service alone. They are
+    /// queued until read, up to `SERVICE_QUERY_QUEUE` of them, and dropped
+    /// while the queue is full. Calling this again moves them to the new
+    /// stream.
+    pub fn queries(&self) -> ServiceQueries {
+        let (tx, rx) = mpsc::channel(SERVICE_QUERY_QUEUE);
+        services::write(&self.services).set_queries(self.id, tx);
+        ServiceQueries(rx)
+    }
+
+    /// Reports on the unsolicited announcements sent for this service
+    pub fn announcement_status(&self) -> AnnouncementStatus {
+        let services = services::read(&self.services);
+        match services.find_by_id(self.id) {
+            Some(svc) => self.stats.announcement_status(&svc.name),
+            None => AnnouncementStatus::default(),
+        }
+    }
+}
+
 impl Drop for Service {
     fn drop(&mut self) {
-        let svc = self.services.write().unwrap().unregister(self.id);
-        self.commands.send_unsolicited(svc, 0, false);
+        let svc = services::write(&self.services).unregister(self.id);
+        self.stats.forget(&svc.name);
+        self.commands.send_unsolicited(svc, Some(0), false);
     }
 }
 
@@ -168,24 +1029,372 @@ impl Drop for Shutdown {
     }
 }
 
+#[cfg(feature = "test-util")]
+impl Responder {
+    /// Builds a responder which never touches the network, for testing
+    /// services against packets passed to `inject_packet`
+    pub fn detached(config: Config) -> io::Result<Responder> {
+        config.check()?;
+        let services = local_services()?;
+        let stats = Arc::new(Stats::new(&config));
+        let task = Box::new(future::ready(()));
+        let (responder, _) = Self::assemble(&config, services, stats, task, Vec::new());
+        Ok(responder)
+    }
+
+    /// Handles `packet` as if it was received from `from`, returning the
+    /// datagrams that would be sent in response and where to
+    ///
+    /// Each packet is handled by a fresh state machine, so nothing carries
+    /// over between calls besides the registered services, e.g. rate limits
+    /// never kick in.
+    ///
+    /// ```
+    /// use libmdns::{Config, Responder};
+    ///
+    /// let responder = Responder::detached(Config::default()).unwrap();
+    /// let _svc = responder.register("_http._tcp".into(), "Example".into(), 80, &["path=/"]);
+    ///
+    /// // A PTR query for _http._tcp.local, from a legacy querier
+    /// let mut query = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    /// query.extend_from_slice(b"\x05_http\x04_tcp\x05local\x00\x00\x0c\x00\x01");
+    /// let from = "127.0.0.1:40000".parse().unwrap();
+    ///
+    /// let responses = responder.inject_packet(&query, from);
+    /// assert_eq!(responses.len(), 1);
+    /// let (response, to) = &responses[0];
+    /// assert_eq!(*to, from);
+    /// // Legacy queriers get their query's ID back
+    /// assert_eq!(response[..2], [0x12, 0x34]);
+    /// ```
+    pub fn inject_packet(&self, packet: &[u8], from: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
+        if from.is_ipv6() {
+            FSM::<Inet6>::detached(&self.services, &self.config, &self.stats)
+                .inject_packet(packet, from)
+        } else {
+            FSM::<Inet>::detached(&self.services, &self.config, &self.stats)
+                .inject_packet(packet, from)
+        }
+    }
+}
+
 #[derive(Clone)]
 struct CommandSender(Vec<mpsc::UnboundedSender<Command>>);
 impl CommandSender {
-    fn send(&mut self, cmd: Command) {
+    /// Sends a command made by `cmd` to every `FSM`
+    fn send<F: FnMut() -> Command>(&mut self, mut cmd: F) {
         for tx in self.0.iter_mut() {
-            tx.send(cmd.clone()).expect("responder died");
+            // Only fails after `Responder::shutdown`, which said goodbye
+            // for every service already
+            if tx.send(cmd()).is_err() {
+                debug!("responder already shut down");
+            }
         }
     }
 
-    fn send_unsolicited(&mut self, svc: ServiceData, ttl: u32, include_ip: bool) {
-        self.send(Command::SendUnsolicited {
-            svc: svc,
-            ttl: ttl,
-            include_ip: include_ip,
+    fn send_unsolicited(&mut self, svc: ServiceData, ttl: Option<u32>, include_ip: bool) {
+        self.send(|| Command::SendUnsolicited {
+            svc: svc.clone(),
+            ttl,
+            include_ip,
+        });
+    }
+
+    fn send_unsolicited_batch(
+        &mut self,
+        svcs: Vec<ServiceData>,
+        ttl: Option<u32>,
+        include_ip: bool,
+    ) {
+        self.send(|| Command::SendUnsolicitedBatch {
+            svcs: svcs.clone(),
+            ttl,
+            include_ip,
         });
     }
 
     fn send_shutdown(&mut self) {
-        self.send(Command::Shutdown);
+        self.send(|| Command::Shutdown);
+    }
+
+    /// Asks every `FSM` to drain, returning one receiver per `FSM` which
+    /// completes once it has
+    fn send_drain(&mut self) -> Vec<oneshot::Receiver<()>> {
+        let mut drained = Vec::new();
+        self.send(|| {
+            let (done, rx) = oneshot::channel();
+            drained.push(rx);
+            Command::Drain(done)
+        });
+        drained
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandSender, Error, RawRecord, Responder, ServiceConfig, ServiceSet, Shutdown};
+    use crate::config::Config;
+    use crate::dns_parser::Name;
+    use crate::fsm::Command;
+    use crate::services::{ServicesInner, SharedServices};
+    use crate::stats::{SendOutcome, Stats};
+    use futures_util::task::noop_waker_ref;
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::Context;
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+
+    fn service_set() -> (ServiceSet, mpsc::UnboundedReceiver<Command>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let commands = CommandSender(vec![tx]);
+        let set = ServiceSet {
+            services: Arc::new(SharedServices::new(ServicesInner::new(
+                "host.local".to_owned(),
+            ))),
+            stats: Arc::new(Stats::new(&Config::default())),
+            commands: commands.clone(),
+            registered: BTreeMap::new(),
+            _shutdown: Arc::new(Shutdown(commands)),
+        };
+        (set, rx)
+    }
+
+    fn responder(config: &Config) -> (Responder, mpsc::UnboundedReceiver<Command>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let services = Arc::new(SharedServices::new(ServicesInner::new(
+            "host.local".to_owned(),
+        )));
+        let stats = Arc::new(Stats::new(config));
+        let task = Box::new(futures_util::future::pending());
+        let (responder, _) = Responder::assemble(config, services, stats, task, vec![tx]);
+        (responder, rx)
+    }
+
+    fn config(name: &str, port: u16) -> ServiceConfig {
+        ServiceConfig {
+            svc_type: "_http._tcp".to_owned(),
+            svc_name: name.to_owned(),
+            port,
+            txt: Vec::new(),
+        }
+    }
+
+    /// The batches sent, as TTL and instance names
+    fn batches(rx: &mut mpsc::UnboundedReceiver<Command>) -> Vec<(Option<u32>, Vec<String>)> {
+        let mut batches = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            match cmd {
+                Command::SendUnsolicitedBatch { svcs, ttl, .. } => {
+                    let names = svcs.iter().map(|svc| svc.name.to_string()).collect();
+                    batches.push((ttl, names));
+                }
+                _ => panic!("unexpected command"),
+            }
+        }
+        batches
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names
+            .iter()
+            .map(|name| format!("{}._http._tcp.local", name))
+            .collect()
+    }
+
+    #[test]
+    fn service_set_sends_only_changes() {
+        let (mut set, mut rx) = service_set();
+        set.update(vec![config("A", 80), config("B", 80), config("C", 80)]);
+        assert_eq!(batches(&mut rx), vec![(None, names(&["A", "B", "C"]))]);
+
+        // Add three, remove two
+        set.update(vec![
+            config("A", 80),
+            config("D", 80),
+            config("E", 80),
+            config("F", 80),
+        ]);
+        assert_eq!(
+            batches(&mut rx),
+            vec![
+                (Some(0), names(&["B", "C"])),
+                (None, names(&["D", "E", "F"]))
+            ]
+        );
+        assert_eq!(set.len(), 4);
+
+        set.update(vec![
+            config("A", 8080),
+            config("D", 80),
+            config("E", 80),
+            config("F", 80),
+        ]);
+        assert_eq!(
+            batches(&mut rx),
+            vec![(Some(0), names(&["A"])), (None, names(&["A"]))]
+        );
+    }
+
+    #[test]
+    fn registers_silently_until_announced() {
+        let (responder, mut rx) = responder(&Config {
+            announce_on_register: false,
+            ..Config::default()
+        });
+        let svc = responder.register("_http._tcp".to_owned(), "A".to_owned(), 80, &[]);
+        let group = responder.register_all(vec![config("B", 80), config("C", 80)]);
+        assert!(rx.try_recv().is_err());
+
+        svc.announce();
+        match rx.try_recv() {
+            Ok(Command::SendUnsolicited { svc, ttl, .. }) => {
+                assert_eq!(svc.name.to_string(), "A._http._tcp.local");
+                assert_eq!(ttl, None);
+            }
+            _ => panic!("expected an announcement"),
+        }
+        group.announce();
+        assert_eq!(batches(&mut rx), vec![(None, names(&["B", "C"]))]);
+    }
+
+    #[test]
+    fn ready_once_every_service_announced() {
+        let (responder, _rx) = responder(&Config::default());
+        let a = responder.register("_http._tcp".to_owned(), "A".to_owned(), 80, &[]);
+        let _b = responder.register("_http._tcp".to_owned(), "B".to_owned(), 80, &[]);
+        let announce = |name: &str| {
+            let name = Name::from_str(format!("{}._http._tcp.local", name)).unwrap();
+            responder
+                .stats
+                .record_announcement(&name, SendOutcome::Sent, Instant::now());
+        };
+
+        let ready = responder.ready();
+        futures_util::pin_mut!(ready);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(ready.as_mut().poll(&mut cx).is_pending());
+        announce("A");
+        assert!(ready.as_mut().poll(&mut cx).is_pending());
+        announce("B");
+        assert!(ready.as_mut().poll(&mut cx).is_ready());
+
+        // Disabled services aren't announced, so aren't waited for
+        let _c = responder.register("_http._tcp".to_owned(), "C".to_owned(), 80, &[]);
+        a.set_enabled(false);
+        let ready = responder.ready();
+        futures_util::pin_mut!(ready);
+        assert!(ready.as_mut().poll(&mut cx).is_pending());
+        announce("C");
+        assert!(ready.poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn toggling_service_says_goodbye_then_announces() {
+        let (responder, mut rx) = responder(&Config {
+            announce_on_register: false,
+            ..Config::default()
+        });
+        let svc = responder.register("_http._tcp".to_owned(), "A".to_owned(), 80, &[]);
+        let mut ttls = || {
+            let mut ttls = Vec::new();
+            while let Ok(cmd) = rx.try_recv() {
+                match cmd {
+                    Command::SendUnsolicited { ttl, .. } => ttls.push(ttl),
+                    _ => panic!("unexpected command"),
+                }
+            }
+            ttls
+        };
+
+        svc.set_enabled(false);
+        assert!(!svc.is_enabled());
+        assert_eq!(ttls(), vec![Some(0)]);
+        // Nothing changes, so nothing is sent
+        svc.set_enabled(false);
+        assert!(ttls().is_empty());
+
+        svc.set_enabled(true);
+        assert!(svc.is_enabled());
+        assert_eq!(ttls(), vec![None]);
+    }
+
+    #[test]
+    fn refuses_records_too_long_to_send() {
+        let (responder, mut rx) = responder(&Config::default());
+        let record = |len| RawRecord {
+            rtype: 256,
+            class: 1,
+            ttl: 60,
+            rdata: vec![0; len],
+        };
+        let registered = responder.register_with_records(
+            "_http._tcp".to_owned(),
+            "A".to_owned(),
+            80,
+            &[],
+            vec![record(16), record(2000)],
+        );
+        match registered {
+            Err(Error::RdataTooLong(256, 2000)) => (),
+            Err(err) => panic!("unexpected {}", err),
+            Ok(_) => panic!("registered anyway"),
+        }
+        assert!(rx.try_recv().is_err());
+
+        let registered = responder.register_with_records(
+            "_http._tcp".to_owned(),
+            "A".to_owned(),
+            80,
+            &[],
+            vec![record(1024)],
+        );
+        assert!(registered.is_ok());
+    }
+
+    #[test]
+    fn refuses_services_that_cant_be_advertised() {
+        let (responder, mut rx) = responder(&Config::default());
+        let long = "a".repeat(256);
+        let register = |name: &str, txt: &[&str]| {
+            responder.try_register("_http._tcp".to_owned(), name.to_owned(), 80, txt)
+        };
+        match register("", &[]) {
+            Err(Error::InvalidName(ref name)) if name == "._http._tcp.local" => (),
+            Err(err) => panic!("unexpected {}", err),
+            Ok(_) => panic!("registered anyway"),
+        }
+        match register(&long[..63], &[]) {
+            Err(Error::InvalidName(_)) => (),
+            Err(err) => panic!("unexpected {}", err),
+            Ok(_) => panic!("registered anyway"),
+        }
+        match register("A", &["a=b", &long]) {
+            Err(Error::TxtEntryTooLong(256)) => (),
+            Err(err) => panic!("unexpected {}", err),
+            Ok(_) => panic!("registered anyway"),
+        }
+        assert!(rx.try_recv().is_err());
+
+        assert!(register(&long[..62], &[&long[..255]]).is_ok());
+        assert!(responder.set_hostname(&long[..63]).is_err());
+        assert!(responder.set_hostname("other-host").is_ok());
+    }
+
+    #[test]
+    fn refuses_aliases_that_cant_be_advertised() {
+        let (responder, _rx) = responder(&Config::default());
+        let svc = responder.register("_http._tcp".to_owned(), "A".to_owned(), 80, &[]);
+        let long = "a".repeat(63);
+        let expected = format!("{}._http._tcp.local", long);
+        match svc.add_alias(&long) {
+            Err(Error::InvalidName(ref name)) if *name == expected => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(svc.add_alias("").is_err());
+        assert!(svc.add_alias(&long[..62]).unwrap());
+        // Taken now
+        assert!(!svc.add_alias(&long[..62]).unwrap());
     }
 }
@@ -1,23 +1,157 @@
+use crate::config::{ObservedQuestion, Schedule, Subnet};
 use crate::dns_parser::{self, Name, QueryClass, RRData};
+use crate::error::Error;
+use log::warn;
 use multimap::MultiMap;
 use rand::{thread_rng, Rng};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Deref, DerefMut};
 use std::slice;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
 
 pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
 
 /// A collection of registered services is shared between threads.
-pub type Services = Arc<RwLock<ServicesInner>>;
+pub type Services = Arc<SharedServices>;
 
+/// The service table, published as immutable snapshots
+///
+/// Readers take a snapshot and answer from it without holding a lock, so a
+/// query being answered never waits for a registration in progress and
+/// always sees the table either wholly before or wholly after a write.
+/// Writers are serialized, edit a copy of the current table and swap it in
+/// when done.
+pub struct SharedServices {
+    current: RwLock<Arc<ServicesInner>>,
+    writer: Mutex<()>,
+}
+
+impl SharedServices {
+    pub fn new(inner: ServicesInner) -> Self {
+        SharedServices {
+            current: RwLock::new(Arc::new(inner)),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Takes a snapshot of the current table
+    ///
+    /// The lock is only held to clone the pointer, and only ever waits for
+    /// a writer swapping in its table.
+    pub fn read(&self) -> Arc<ServicesInner> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Starts a write, published when the returned guard is dropped
+    ///
+    /// The table is only copied once the write needs to change it, and only
+    /// published if it did change. A write abandoned by a panic is never
+    /// published, so the table stays as it was before the write. The writer
+    /// lock is still poisoned, which is ignored by later writes. Writes
+    /// started while a panic unwinds already, e.g. by a `Service` dropped
+    /// on the way, are published as usual.
+    pub fn write(&self) -> ServicesWriteGuard<'_> {
+        let lock = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        ServicesWriteGuard {
+            shared: self,
+            snapshot: self.read(),
+            table: None,
+            panicking: thread::panicking(),
+            _lock: lock,
+        }
+    }
+
+    /// Whether a writer panicked while holding the writer lock
+    #[cfg(test)]
+    pub fn is_poisoned(&self) -> bool {
+        self.writer.is_poisoned()
+    }
+}
+
+/// An ongoing write of [`SharedServices`]
+pub struct ServicesWriteGuard<'a> {
+    shared: &'a SharedServices,
+    /// The table as it was when the write started
+    snapshot: Arc<ServicesInner>,
+    /// The copy being written, once there was a mutable borrow
+    table: Option<ServicesInner>,
+    /// Whether the write started during a panic, like the poison flag of
+    /// `MutexGuard`, so only a panic in the write itself abandons it
+    panicking: bool,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl Deref for ServicesWriteGuard<'_> {
+    type Target = ServicesInner;
+
+    fn deref(&self) -> &ServicesInner {
+        self.table.as_ref().unwrap_or(&self.snapshot)
+    }
+}
+
+impl DerefMut for ServicesWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ServicesInner {
+        let snapshot = &self.snapshot;
+        self.table.get_or_insert_with(|| (**snapshot).clone())
+    }
+}
+
+impl Drop for ServicesWriteGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() && !self.panicking {
+            return;
+        }
+        let table = match self.table.take() {
+            Some(table) if table.generation != self.snapshot.generation => table,
+            _ => return,
+        };
+        *self
+            .shared
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(table);
+    }
+}
+
+/// Takes a snapshot of the services, see [`SharedServices::read`]
+pub fn read(services: &Services) -> Arc<ServicesInner> {
+    services.read()
+}
+
+/// Starts a write of the services, see [`SharedServices::write`]
+pub fn write(services: &Services) -> ServicesWriteGuard<'_> {
+    services.write()
+}
+
+/// The registered services, as snapshots of `SharedServices`
+///
+/// Every method changing anything must bump `generation`, as a write is
+/// only published if it did.
+#[derive(Clone)]
 pub struct ServicesInner {
     hostname: Name<'static>,
     /// main index
     by_id: HashMap<usize, ServiceData>,
     /// maps to id
     by_type: MultiMap<Name<'static>, usize>,
-    /// maps to id
-    by_name: HashMap<Name<'static>, usize>,
+    /// maps to ids, usually just one unless instances share a name for
+    /// load balancing
+    by_name: MultiMap<Name<'static>, usize>,
+    /// maps alias instance names to the id of the service they stand for
+    aliases: HashMap<Name<'static>, usize>,
+    /// Bumped by every change, to what is advertised or otherwise, so a
+    /// write changing nothing isn't published. A change not bumping it is
+    /// lost, along with the rest of its write.
+    generation: u64,
 }
 
 impl ServicesInner {
@@ -26,7 +160,9 @@ impl ServicesInner {
             hostname: Name::from_str(hostname).unwrap(),
             by_id: HashMap::new(),
             by_type: MultiMap::new(),
-            by_name: HashMap::new(),
+            by_name: MultiMap::new(),
+            aliases: HashMap::new(),
+            generation: 0,
         }
     }
 
@@ -34,16 +170,50 @@ impl ServicesInner {
         &self.hostname
     }
 
-    pub fn find_by_name<'a>(&'a self, name: &'a Name<'a>) -> Option<&ServiceData> {
+    /// Counts the changes to what is advertised, so that whatever is built
+    /// from it can tell when it's out of date
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Changes the hostname, returning the previous one
+    pub fn set_hostname(&mut self, hostname: Name<'static>) -> Name<'static> {
+        self.generation += 1;
+        std::mem::replace(&mut self.hostname, hostname)
+    }
+
+    pub fn find_by_id(&self, id: usize) -> Option<&ServiceData> {
+        self.by_id.get(&id)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &ServiceData> {
+        self.by_id.values()
+    }
+
+    pub fn find_by_name<'a>(&'a self, name: &'a Name<'a>) -> Option<&'a ServiceData> {
         self.by_name.get(name).and_then(|id| self.by_id.get(id))
     }
 
+    /// Every service registered under `name`, see `find_by_name` for the
+    /// usual case of there being one at most
+    pub fn find_all_by_name<'a>(
+        &'a self,
+        name: &'a Name<'a>,
+    ) -> impl Iterator<Item = &'a ServiceData> + 'a {
+        let ids = self.by_name.get_vec(name).map(|ids| ids.iter());
+        ids.into_iter()
+            .flatten()
+            .map(move |id| self.by_id.get(id).expect("missing service"))
+    }
+
+    /// The discoverable services of type `ty`, unlike `find_by_name` which
+    /// finds hidden ones too
     pub fn find_by_type<'a>(&'a self, ty: &'a Name<'a>) -> FindByType<'a> {
         let ids = self.by_type.get_vec(ty).map(|ids| ids.iter());
 
         FindByType {
             services: self,
-            ids: ids,
+            ids,
         }
     }
 
@@ -56,31 +226,167 @@ impl ServicesInner {
         self.by_type.insert(svc.typ.clone(), id);
         self.by_name.insert(svc.name.clone(), id);
         self.by_id.insert(id, svc);
+        self.generation += 1;
 
         id
     }
 
-    pub fn unregister(&mut self, id: usize) -> ServiceData {
-        use std::collections::hash_map::Entry;
+    /// Changes the name of a service, keeping its id
+    pub fn rename(&mut self, name: &Name<'static>, new_name: Name<'static>) -> Option<ServiceData> {
+        let id = *self.by_name.get(name)?;
+        self.remove_name(name, id);
+        self.by_name.insert(new_name.clone(), id);
+        let svc = self.by_id.get_mut(&id).expect("missing service");
+        svc.name = new_name;
+        self.generation += 1;
+        Some(svc.clone())
+    }
+
+    /// Enables or disables a service, returning it if that changed anything
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> Option<ServiceData> {
+        let svc = self.by_id.get_mut(&id)?;
+        if svc.enabled == enabled {
+            return None;
+        }
+        svc.enabled = enabled;
+        self.generation += 1;
+        Some(svc.clone())
+    }
+
+    /// Makes `alias` another instance name of the service `id`, returning
+    /// `false` if that name is taken already
+    pub fn add_alias(&mut self, id: usize, alias: Name<'static>) -> bool {
+        let taken = self.by_name.contains_key(&alias) || self.aliases.contains_key(&alias);
+        if taken || !self.by_id.contains_key(&id) {
+            return false;
+        }
+        self.aliases.insert(alias, id);
+        self.generation += 1;
+        true
+    }
+
+    /// The service `name` is an alias of, as if it was registered under
+    /// that name, with whatever records the service has now
+    pub fn find_alias(&self, name: &Name) -> Option<ServiceData> {
+        let (alias, id) = self.aliases.iter().find(|(alias, _)| *alias == name)?;
+        let svc = self.by_id.get(id).expect("alias of missing service");
+        Some(ServiceData {
+            name: alias.clone(),
+            ..svc.clone()
+        })
+    }
+
+    /// The aliases of the discoverable services of type `ty`, like
+    /// `find_alias` gives them
+    pub fn find_aliases_by_type(&self, ty: &Name) -> Vec<ServiceData> {
+        self.aliases
+            .keys()
+            .filter_map(|alias| self.find_alias(alias))
+            .filter(|svc| svc.typ == *ty && svc.discoverable)
+            .collect()
+    }
+
+    /// Lists a service when browsing for its type or not, returning whether
+    /// that changed anything
+    pub fn set_discoverable(&mut self, id: usize, discoverable: bool) -> bool {
+        let svc = match self.by_id.get_mut(&id) {
+            Some(svc) => svc,
+            None => return false,
+        };
+        if svc.discoverable == discoverable {
+            return false;
+        }
+        svc.discoverable = discoverable;
+        self.generation += 1;
+        true
+    }
+
+    /// Replaces the records never sent for a service, returning whether
+    /// that changed anything
+    pub fn set_suppressed(&mut self, id: usize, suppressed: Vec<ServiceRecord>) -> bool {
+        let svc = match self.by_id.get_mut(&id) {
+            Some(svc) => svc,
+            None => return false,
+        };
+        if svc.suppressed == suppressed {
+            return false;
+        }
+        svc.suppressed = suppressed;
+        self.generation += 1;
+        true
+    }
+
+    /// Changes the address families a service is advertised on, returning
+    /// whether that changed anything
+    pub fn set_families(&mut self, id: usize, families: Families) -> bool {
+        let svc = match self.by_id.get_mut(&id) {
+            Some(svc) => svc,
+            None => return false,
+        };
+        if svc.families == families {
+            return false;
+        }
+        svc.families = families;
+        self.generation += 1;
+        true
+    }
+
+    /// Replaces the schedule of a service, returning its name and the
+    /// previous schedule
+    pub fn set_schedule(
+        &mut self,
+        id: usize,
+        schedule: Option<Schedule>,
+    ) -> Option<(Name<'static>, Option<Schedule>)> {
+        let svc = self.by_id.get_mut(&id)?;
+        let previous = std::mem::replace(&mut svc.schedule, schedule);
+        self.generation += 1;
+        Some((svc.name.clone(), previous))
+    }
+
+    /// Sends the questions about a service to `queries` from now on
+    pub fn set_queries(
+        &mut self,
+        id: usize,
+        queries: mpsc::Sender<(SocketAddr, ObservedQuestion)>,
+    ) {
+        if let Some(svc) = self.by_id.get_mut(&id) {
+            svc.queries = Some(queries);
+            self.generation += 1;
+        }
+    }
 
+    pub fn unregister(&mut self, id: usize) -> ServiceData {
         let svc = self.by_id.remove(&id).expect("unknown service");
+        self.aliases.retain(|_, primary| *primary != id);
+        self.generation += 1;
 
         if let Some(entries) = self.by_type.get_vec_mut(&svc.typ) {
             entries.retain(|&e| e != id);
         }
 
-        match self.by_name.entry(svc.name.clone()) {
-            Entry::Occupied(entry) => {
-                assert_eq!(*entry.get(), id);
-                entry.remove();
-            }
-            _ => {
-                panic!("unknown/wrong service for id {}", id);
-            }
+        if !self.remove_name(&svc.name, id) {
+            panic!("unknown/wrong service for id {}", id);
         }
 
         svc
     }
+
+    /// Removes `id` from the services named `name`, `false` if it wasn't
+    /// one of them
+    fn remove_name(&mut self, name: &Name<'static>, id: usize) -> bool {
+        let ids = match self.by_name.get_vec_mut(name) {
+            Some(ids) => ids,
+            None => return false,
+        };
+        let len = ids.len();
+        ids.retain(|&e| e != id);
+        let removed = ids.len() < len;
+        if ids.is_empty() {
+            self.by_name.remove(name);
+        }
+        removed
+    }
 }
 
 /// Returned by [`ServicesInner.find_by_type`](struct.ServicesInner.html#method.find_by_type)
@@ -93,10 +399,11 @@ impl<'a> Iterator for FindByType<'a> {
     type Item = &'a ServiceData;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.ids.as_mut().and_then(Iterator::next).map(|id| {
-            let svc = self.services.by_id.get(id);
-            svc.expect("missing service")
-        })
+        let services = self.services;
+        self.ids
+            .as_mut()?
+            .map(|id| services.by_id.get(id).expect("missing service"))
+            .find(|svc| svc.discoverable)
     }
 }
 
@@ -106,10 +413,152 @@ pub struct ServiceData {
     pub typ: Name<'static>,
     pub port: u16,
     pub txt: Vec<u8>,
+    /// Produces the TXT entries each time they are sent, instead of `txt`
+    pub txt_fn: Option<TxtFn>,
+    /// Only queriers in these subnets see the service, and they only ever
+    /// get it by unicast. Such services are never announced.
+    pub subnets: Option<Vec<Subnet>>,
+    /// Further records of the service's name, see `RawRecord`
+    pub records: Vec<RawRecord>,
+    /// Encoded TXT entries sent along with `txt` by unicast only, so they
+    /// aren't multicast to the whole link, although any querier can still
+    /// ask for them
+    pub private_txt: Vec<u8>,
+    /// Priority and weight of the SRV record (RFC 2782), for instances
+    /// sharing a name
+    pub priority: u16,
+    pub weight: u16,
+    /// Disabled services are neither answered for nor announced, see
+    /// `Service::set_enabled`
+    pub enabled: bool,
+    /// Hours of the day the service is advertised in, all day if `None`
+    pub schedule: Option<Schedule>,
+    /// Listed when browsing for its type. Hidden services are only answered
+    /// for by name, see `Service::set_discoverable`
+    pub discoverable: bool,
+    /// Records never sent for the service, neither in answers nor in
+    /// announcements, see `Service::set_suppressed`
+    pub suppressed: Vec<ServiceRecord>,
+    /// Address families the service is advertised over and with addresses
+    /// of, see `Service::set_families`
+    pub families: Families,
+    /// Gets the questions about the service, see `Service::queries`
+    pub queries: Option<mpsc::Sender<(SocketAddr, ObservedQuestion)>>,
+}
+
+/// One of the records every service has, see `Service::set_suppressed`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceRecord {
+    Ptr,
+    Srv,
+    Txt,
+}
+
+/// Address families a service is advertised on, see `Service::set_families`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Families {
+    V4,
+    V6,
+    Both,
+}
+
+impl Families {
+    /// Whether IPv6, or IPv4 if not `v6`, is one of them
+    pub fn allows(self, v6: bool) -> bool {
+        match self {
+            Families::V4 => !v6,
+            Families::V6 => v6,
+            Families::Both => true,
+        }
+    }
+}
+
+/// A record of a type the crate has no support for, e.g. URI, sent along
+/// with a service when asked for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawRecord {
+    /// Numeric TYPE, e.g. 256 for URI
+    pub rtype: u16,
+    /// Numeric CLASS, e.g. 1 for IN
+    pub class: u16,
+    pub ttl: u32,
+    /// The record data in wire format
+    pub rdata: Vec<u8>,
+}
+
+/// Longest rdata of a `RawRecord`, keeping it well within a datagram
+pub const MAX_RAW_RDATA: usize = 1024;
+
+type TxtGenerator = dyn Fn() -> Vec<String> + Send + Sync;
+
+/// A TXT record evaluated when answering, see
+/// `Responder::register_with_txt_fn`
+#[derive(Clone)]
+pub struct TxtFn(Arc<TxtGenerator>);
+
+impl TxtFn {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn() -> Vec<String> + Send + Sync + 'static,
+    {
+        TxtFn(Arc::new(f))
+    }
+
+    /// Encodes the current entries, dropping any that don't fit
+    fn encode(&self) -> Vec<u8> {
+        let entries = (self.0)();
+        let entries: Vec<&str> = entries
+            .iter()
+            .map(String::as_str)
+            .filter(|entry| {
+                if entry.len() > 255 {
+                    warn!("dropping {:?}, too long for a TXT record", entry);
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect();
+        encode_txt(&entries)
+    }
+}
+
+impl fmt::Debug for TxtFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TxtFn")
+    }
 }
 
 /// Packet building helpers for `fsm` to respond with `ServiceData`
 impl ServiceData {
+    /// Whether the service runs on a port. Services registered with port 0,
+    /// such as device info, only carry metadata and get no SRV record.
+    pub fn has_srv(&self) -> bool {
+        self.port != 0
+    }
+
+    /// Whether the service is advertised at `at`, see `schedule`
+    pub fn is_scheduled(&self, at: SystemTime) -> bool {
+        self.schedule.map_or(true, |schedule| schedule.contains(at))
+    }
+
+    /// Whether `record` may be sent for the service, see `suppressed`
+    pub fn sends(&self, record: ServiceRecord) -> bool {
+        !self.suppressed.contains(&record)
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.subnets.is_some()
+    }
+
+    /// Whether a querier at `ip` may see the service
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        match self.subnets {
+            Some(ref subnets) => subnets.iter().any(|subnet| subnet.contains(ip)),
+            None => true,
+        }
+    }
+
     pub fn add_ptr_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
         builder.add_answer(
             &self.typ,
@@ -120,20 +569,340 @@ impl ServiceData {
     }
 
     pub fn add_srv_rr(&self, hostname: &Name, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
+        builder.add_answer(&self.name, QueryClass::IN, ttl, &self.srv_rdata(hostname))
+    }
+
+    pub fn srv_rdata<'a>(&self, hostname: &Name<'a>) -> RRData<'a> {
+        RRData::SRV {
+            priority: self.priority,
+            weight: self.weight,
+            port: self.port,
+            target: hostname.clone(),
+        }
+    }
+
+    /// The instance part of the name, e.g. "Printer" for
+    /// "Printer._ipp._tcp.local"
+    pub fn instance(&self) -> Option<String> {
+        let name = self.name.to_string();
+        let suffix = format!(".{}", self.typ);
+        name.strip_suffix(&suffix[..]).map(str::to_owned)
+    }
+
+    /// Adds the raw records of type `rtype`, or all of them for `None`,
+    /// with their TTLs capped at `max_ttl`
+    pub fn add_raw_rrs(
+        &self,
+        mut builder: AnswerBuilder,
+        rtype: Option<u16>,
+        max_ttl: u32,
+    ) -> AnswerBuilder {
+        for record in &self.records {
+            if rtype.map_or(true, |rtype| rtype == record.rtype) {
+                builder = builder.add_raw_answer(
+                    &self.name,
+                    record.rtype,
+                    record.class,
+                    record.ttl.min(max_ttl),
+                    &record.rdata,
+                );
+            }
+        }
+        builder
+    }
+
+    /// Adds the TXT record, with the private entries if `private` and the
+    /// response goes to the querier alone
+    pub fn add_txt_rr(&self, builder: AnswerBuilder, ttl: u32, private: bool) -> AnswerBuilder {
         builder.add_answer(
             &self.name,
             QueryClass::IN,
             ttl,
-            &RRData::SRV {
-                priority: 0,
-                weight: 0,
-                port: self.port,
-                target: hostname.clone(),
-            },
+            &RRData::TXT(&self.txt_rdata(private)),
         )
     }
 
-    pub fn add_txt_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(&self.name, QueryClass::IN, ttl, &RRData::TXT(&self.txt))
+    /// The TXT record data as sent, generated anew if the service has a
+    /// `txt_fn`, and with the private entries if `private`
+    pub fn txt_rdata(&self, private: bool) -> Cow<'_, [u8]> {
+        let public = match self.txt_fn {
+            Some(ref txt_fn) => Cow::Owned(txt_fn.encode()),
+            None => Cow::Borrowed(&self.txt[..]),
+        };
+        if private && !self.private_txt.is_empty() {
+            // Leave out the lone empty string standing in for no entries
+            let mut rdata = match &public[..] {
+                [] | [0] => Vec::new(),
+                public => public.to_vec(),
+            };
+            rdata.extend_from_slice(&self.private_txt);
+            return Cow::Owned(rdata);
+        }
+        // A TXT record must contain at least one (possibly empty) string
+        if public.is_empty() {
+            Cow::Borrowed(&[0])
+        } else {
+            public
+        }
+    }
+}
+
+/// Encodes TXT entries as a sequence of length-prefixed character-strings
+///
+/// Entries keep the order given, which some clients depend on.
+///
+/// A service without entries gets a single empty string, as required by
+/// RFC 6763 section 6.1.
+///
+/// # Panics
+///
+/// * An entry is longer than 255 bytes, see `check_txt`.
+pub fn encode_txt(entries: &[&str]) -> Vec<u8> {
+    if entries.is_empty() {
+        return vec![0];
+    }
+
+    entries
+        .iter()
+        .flat_map(|entry| {
+            let entry = entry.as_bytes();
+            if entry.len() > 255 {
+                panic!("{:?} is too long for a TXT record", entry);
+            }
+            std::iter::once(entry.len() as u8).chain(entry.iter().cloned())
+        })
+        .collect()
+}
+
+/// Fails with `Error::TxtEntryTooLong` where `encode_txt` would panic
+pub fn check_txt(entries: &[&str]) -> Result<(), Error> {
+    match entries.iter().find(|entry| entry.len() > 255) {
+        Some(entry) => Err(Error::TxtEntryTooLong(entry.len())),
+        None => Ok(()),
+    }
+}
+
+/// Fails with `Error::InvalidName` unless `name` can be written to packets
+///
+/// Labels are limited to 62 bytes, as that's what the packet builder takes.
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    let labels_fit = trimmed
+        .split('.')
+        .all(|label| !label.is_empty() && label.len() < 63);
+    if !labels_fit || trimmed.len() > 253 {
+        return Err(Error::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_name, check_txt, encode_txt, Families, ServiceData, ServicesInner, SharedServices,
+        TxtFn,
+    };
+    use crate::dns_parser::{self, Name, Packet, RRData};
+    use crate::error::Error;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn txt_rdata(svc: &ServiceData) -> Vec<u8> {
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder = svc.add_txt_rr(builder, 60, false);
+        let packet = builder.build().unwrap();
+        let parsed = Packet::parse(&packet).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        match parsed.answers[0].data {
+            RRData::TXT(data) => data.to_vec(),
+            ref x => panic!("Wrong rdata {:?}", x),
+        }
+    }
+
+    fn service(txt: Vec<u8>) -> ServiceData {
+        ServiceData {
+            name: Name::from_str("Test._http._tcp.local").unwrap(),
+            typ: Name::from_str("_http._tcp.local").unwrap(),
+            port: 80,
+            txt,
+            txt_fn: None,
+            subnets: None,
+            records: Vec::new(),
+            private_txt: Vec::new(),
+            priority: 0,
+            weight: 0,
+            enabled: true,
+            schedule: None,
+            discoverable: true,
+            suppressed: Vec::new(),
+            families: Families::Both,
+            queries: None,
+        }
+    }
+
+    #[test]
+    fn encode_empty_txt() {
+        assert_eq!(encode_txt(&[]), vec![0]);
+        assert_eq!(encode_txt(&["a=b", "c"]), b"\x03a=b\x01c".to_vec());
+    }
+
+    #[test]
+    fn checks_txt_entries_fit() {
+        let long = "a".repeat(256);
+        assert!(check_txt(&["a=b", &long[..255]]).is_ok());
+        match check_txt(&["a=b", &long]) {
+            Err(Error::TxtEntryTooLong(256)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn checks_names_fit_packets() {
+        let label = "a".repeat(62);
+        assert!(check_name("Test._http._tcp.local").is_ok());
+        assert!(check_name("test-host.local.").is_ok());
+        assert!(check_name(&format!("{}._http._tcp.local", label)).is_ok());
+        let too_long = [&label[..]; 5].join(".");
+        for name in &[
+            "",
+            "Test.._tcp.local",
+            &format!("a{}._http._tcp.local", label),
+            &too_long,
+        ] {
+            match check_name(name) {
+                Err(Error::InvalidName(ref invalid)) if invalid == name => (),
+                other => panic!("unexpected {:?} for {:?}", other, name),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_txt_is_single_empty_string() {
+        assert_eq!(txt_rdata(&service(encode_txt(&[]))), vec![0]);
+        // Never emit a zero-length rdata, even if the service was built by hand
+        assert_eq!(txt_rdata(&service(Vec::new())), vec![0]);
+    }
+
+    #[test]
+    fn private_txt_only_in_private_rdata() {
+        let mut svc = service(encode_txt(&["a=b"]));
+        svc.private_txt = encode_txt(&["token=x"]);
+        assert_eq!(svc.txt_rdata(false).to_vec(), b"\x03a=b".to_vec());
+        assert_eq!(svc.txt_rdata(true).to_vec(), b"\x03a=b\x07token=x".to_vec());
+
+        svc.txt = Vec::new();
+        assert_eq!(svc.txt_rdata(false).to_vec(), vec![0]);
+        assert_eq!(svc.txt_rdata(true).to_vec(), b"\x07token=x".to_vec());
+    }
+
+    #[test]
+    fn txt_keeps_registration_order() {
+        fn entries(mut rdata: &[u8]) -> Vec<&[u8]> {
+            let mut entries = Vec::new();
+            while let Some((&len, rest)) = rdata.split_first() {
+                let (entry, rest) = rest.split_at(len as usize);
+                entries.push(entry);
+                rdata = rest;
+            }
+            entries
+        }
+
+        let keys = ["zeta=1", "alpha=2", "mid=3"];
+        let expected: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+        let mut svc = service(encode_txt(&keys));
+        for _ in 0..3 {
+            assert_eq!(entries(&txt_rdata(&svc)), expected);
+        }
+        svc.txt_fn = Some(TxtFn::new(move || {
+            keys.iter().map(|&k| k.to_owned()).collect()
+        }));
+        assert_eq!(entries(&txt_rdata(&svc)), expected);
+    }
+
+    #[test]
+    fn txt_fn_drops_oversized_entries() {
+        let mut svc = service(Vec::new());
+        svc.txt_fn = Some(TxtFn::new(|| vec!["a".repeat(256), "b=c".to_owned()]));
+        assert_eq!(txt_rdata(&svc), b"\x03b=c".to_vec());
+    }
+
+    #[test]
+    fn readers_see_whole_writes() {
+        let shared = Arc::new(SharedServices::new(ServicesInner::new(
+            "host.local".to_owned(),
+        )));
+        let done = Arc::new(AtomicBool::new(false));
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+
+        let writer = {
+            let (shared, done) = (shared.clone(), done.clone());
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    let mut guard = shared.write();
+                    let a = guard.register(service(Vec::new()));
+                    let b = guard.register(service(Vec::new()));
+                    drop(guard);
+
+                    let mut guard = shared.write();
+                    guard.unregister(a);
+                    guard.unregister(b);
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+
+        while !done.load(Ordering::SeqCst) {
+            let snapshot = shared.read();
+            let count = snapshot.all().count();
+            assert!(count == 0 || count == 2, "saw {} services", count);
+            thread::yield_now();
+            // A snapshot doesn't change under its reader
+            assert_eq!(snapshot.all().count(), count);
+            // The indexes agree with each other in every snapshot
+            assert_eq!(snapshot.find_by_type(&typ).count(), count);
+        }
+        writer.join().unwrap();
+        assert_eq!(shared.read().all().count(), 0);
+    }
+
+    #[test]
+    fn publishes_writes_started_while_unwinding() {
+        struct Unregister(Arc<SharedServices>, usize);
+
+        impl Drop for Unregister {
+            fn drop(&mut self) {
+                self.0.write().unregister(self.1);
+            }
+        }
+
+        let shared = Arc::new(SharedServices::new(ServicesInner::new(
+            "host.local".to_owned(),
+        )));
+        let id = shared.write().register(service(Vec::new()));
+
+        let unregister = Unregister(shared.clone(), id);
+        let _ = thread::spawn(move || {
+            let _unregister = unregister;
+            panic!("unwinding past a service");
+        })
+        .join();
+        assert!(shared.read().find_by_id(id).is_none());
+        assert!(!shared.is_poisoned());
+    }
+
+    #[test]
+    fn writes_changing_nothing_arent_published() {
+        let shared = SharedServices::new(ServicesInner::new("host.local".to_owned()));
+        let id = shared.write().register(service(Vec::new()));
+        let before = shared.read();
+
+        assert!(!shared.write().set_discoverable(id, true));
+        assert!(shared.write().set_enabled(id, true).is_none());
+        assert!(Arc::ptr_eq(&before, &shared.read()));
+
+        assert!(shared.write().set_discoverable(id, false));
+        assert!(!Arc::ptr_eq(&before, &shared.read()));
     }
 }
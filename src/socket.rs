@@ -0,0 +1,772 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::task::{Context, Poll};
+
+use tokio::net::UdpSocket;
+
+/// The operations `fsm` needs from its UDP socket
+///
+/// Implemented for tokio's `UdpSocket`, and by a mock in tests so the state
+/// machine can be driven without touching the network.
+pub trait Socket: Send {
+    fn poll_recv_from(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>>;
+    /// Receives like `poll_recv_from`, along with the index of the interface
+    /// the datagram arrived on where the platform tells, see `enable_pktinfo`
+    fn poll_recv_from_iface(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, Option<u32>)>> {
+        self.poll_recv_from(cx, buf)
+            .map_ok(|(len, addr)| (len, addr, None))
+    }
+    fn poll_send_to(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        target: &SocketAddr,
+    ) -> Poll<io::Result<usize>>;
+    /// Sends from our local address `source`, where the platform supports
+    /// picking it, rather than the one the routing table picks
+    ///
+    /// A link-local IPv6 `source` needs its interface too, given to
+    /// `poll_send_out_of` instead.
+    fn poll_send_from(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        target: &SocketAddr,
+        source: IpAddr,
+    ) -> Poll<io::Result<usize>> {
+        let _ = source;
+        self.poll_send_to(cx, buf, target)
+    }
+    /// Sends out of the interface with index `interface`, and from our local
+    /// address `source` if given, where the platform supports picking them
+    fn poll_send_out_of(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        target: &SocketAddr,
+        interface: u32,
+        source: Option<IpAddr>,
+    ) -> Poll<io::Result<usize>> {
+        let _ = interface;
+        match source {
+            Some(source) => self.poll_send_from(cx, buf, target, source),
+            None => self.poll_send_to(cx, buf, target),
+        }
+    }
+    fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()>;
+    fn join_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()>;
+    fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()>;
+    fn leave_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()>;
+    fn set_multicast_if_v4(&self, interface: Ipv4Addr) -> io::Result<()>;
+    fn set_multicast_if_v6(&self, interface: u32) -> io::Result<()>;
+}
+
+impl Socket for UdpSocket {
+    fn poll_recv_from(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_from_iface(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, Option<u32>)>> {
+        match recv_from_iface(self, buf) {
+            // Only tokio can wait for the socket to become readable, so what
+            // it receives meanwhile comes without its interface
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                UdpSocket::poll_recv_from(self, cx, buf).map_ok(|(len, addr)| (len, addr, None))
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn poll_send_to(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        target: &SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_from(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        target: &SocketAddr,
+        source: IpAddr,
+    ) -> Poll<io::Result<usize>> {
+        match send_from(self, buf, target, Some(source), 0) {
+            // Only tokio can wait for the socket to become writable, so the
+            // datagram goes out from wherever the OS picks if it's busy
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                UdpSocket::poll_send_to(self, cx, buf, target)
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_out_of(
+        &mut self,
+        cx: &mut Context,
+        buf: &[u8],
+        target: &SocketAddr,
+        interface: u32,
+        source: Option<IpAddr>,
+    ) -> Poll<io::Result<usize>> {
+        match send_from(self, buf, target, source, interface) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                UdpSocket::poll_send_to(self, cx, buf, target)
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        UdpSocket::join_multicast_v4(self, multiaddr, interface)
+    }
+
+    fn join_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+        UdpSocket::join_multicast_v6(self, multiaddr, interface)
+    }
+
+    fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        UdpSocket::leave_multicast_v4(self, multiaddr, interface)
+    }
+
+    fn leave_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+        UdpSocket::leave_multicast_v6(self, multiaddr, interface)
+    }
+
+    #[cfg(unix)]
+    fn set_multicast_if_v4(&self, interface: Ipv4Addr) -> io::Result<()> {
+        let addr = libc::in_addr {
+            s_addr: u32::from(interface).to_be(),
+        };
+        setsockopt(self, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, addr)
+    }
+
+    #[cfg(unix)]
+    fn set_multicast_if_v6(&self, interface: u32) -> io::Result<()> {
+        setsockopt(
+            self,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_IF,
+            interface as libc::c_uint,
+        )
+    }
+
+    #[cfg(not(unix))]
+    fn set_multicast_if_v4(&self, _interface: Ipv4Addr) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    #[cfg(not(unix))]
+    fn set_multicast_if_v6(&self, _interface: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(unix)]
+fn setsockopt<T>(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Has the kernel tell which interface every datagram arrives on, for
+/// `Socket::poll_recv_from_iface`
+#[cfg(target_os = "linux")]
+pub fn enable_pktinfo(socket: &impl AsRawFd, v6: bool) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    if v6 {
+        setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, on)
+    } else {
+        setsockopt(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, on)
+    }
+}
+
+/// Has the kernel only deliver multicast for the groups joined on `socket`
+/// itself, rather than for any joined on the host, so that the sockets of
+/// `Config::socket_per_interface` don't all receive each datagram
+#[cfg(target_os = "linux")]
+pub fn disable_multicast_all(socket: &impl AsRawFd, v6: bool) -> io::Result<()> {
+    // Missing from libc, as of Linux 4.20
+    const IPV6_MULTICAST_ALL: libc::c_int = 29;
+
+    let off: libc::c_int = 0;
+    if v6 {
+        setsockopt(socket, libc::IPPROTO_IPV6, IPV6_MULTICAST_ALL, off)
+    } else {
+        setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_ALL, off)
+    }
+}
+
+/// Receives into `buf` through `recvmsg`, along with the index of the
+/// interface the datagram arrived on if `enable_pktinfo` was called
+///
+/// Fails with `Error::BufferTooSmall` for a datagram longer than `buf`.
+#[cfg(target_os = "linux")]
+fn recv_from_iface(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u32>)> {
+    use std::mem;
+    use std::net::SocketAddrV6;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // Room for either kind of pktinfo, aligned for a cmsghdr
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control);
+
+    // With MSG_TRUNC, the length returned is that of the whole datagram
+    let flags = libc::MSG_DONTWAIT | libc::MSG_TRUNC;
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if msg.msg_flags & libc::MSG_TRUNC != 0 {
+        let err = crate::error::Error::BufferTooSmall(received as usize, buf.len());
+        return Err(err.into());
+    }
+
+    let mut interface = None;
+    // What didn't fit may be the pktinfo, so there's none to go by
+    if msg.msg_flags & libc::MSG_CTRUNC == 0 {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let info = (data as *const libc::in_pktinfo).read_unaligned();
+                        interface = Some(info.ipi_ifindex as u32);
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let info = (data as *const libc::in6_pktinfo).read_unaligned();
+                        interface = Some(info.ipi6_ifindex);
+                    }
+                    _ => (),
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+    }
+
+    let from = match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            SocketAddr::new(IpAddr::V4(ip), u16::from_be(sin.sin_port))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in6) };
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            ))
+        }
+        family => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("datagram from address family {}", family),
+            ))
+        }
+    };
+    Ok((received as usize, from, interface.filter(|&i| i != 0)))
+}
+
+/// Sends `buf` to `target` through `sendmsg`, setting its source address
+/// and outgoing interface with `IP_PKTINFO` or `IPV6_PKTINFO`
+///
+/// Where `source` is `None` or `interface` is 0, the OS picks that one.
+#[cfg(target_os = "linux")]
+fn send_from(
+    socket: &UdpSocket,
+    buf: &[u8],
+    target: &SocketAddr,
+    source: Option<IpAddr>,
+    interface: u32,
+) -> io::Result<usize> {
+    use std::mem;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let addr_len = match *target {
+        SocketAddr::V4(target) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: target.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*target.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { *(&mut addr as *mut _ as *mut libc::sockaddr_in) = sin };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(target) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: target.port().to_be(),
+                sin6_flowinfo: target.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: target.ip().octets(),
+                },
+                sin6_scope_id: target.scope_id(),
+            };
+            unsafe { *(&mut addr as *mut _ as *mut libc::sockaddr_in6) = sin6 };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    // Room for either kind of pktinfo, aligned for a cmsghdr
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = addr_len as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+
+    unsafe {
+        let (level, typ, len) = match target {
+            SocketAddr::V4(_) => (
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                mem::size_of::<libc::in_pktinfo>(),
+            ),
+            SocketAddr::V6(_) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                mem::size_of::<libc::in6_pktinfo>(),
+            ),
+        };
+        msg.msg_controllen = libc::CMSG_SPACE(len as u32) as usize;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = typ;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as usize;
+        match target {
+            SocketAddr::V4(_) => {
+                let source = match source {
+                    Some(IpAddr::V4(source)) => u32::from(source).to_be(),
+                    _ => 0,
+                };
+                let info = libc::in_pktinfo {
+                    ipi_ifindex: interface as libc::c_int,
+                    ipi_spec_dst: libc::in_addr { s_addr: source },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                *(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo) = info;
+            }
+            SocketAddr::V6(_) => {
+                let source = match source {
+                    Some(IpAddr::V6(source)) => source.octets(),
+                    _ => [0; 16],
+                };
+                let info = libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr { s6_addr: source },
+                    ipi6_ifindex: interface,
+                };
+                *(libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo) = info;
+            }
+        }
+    }
+
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "socket option not supported on this platform",
+    )
+}
+
+/// A socket nothing arrives on and nothing leaves, for state machines whose
+/// packets are passed in and taken out directly
+#[cfg(feature = "test-util")]
+pub struct DetachedSocket;
+
+#[cfg(feature = "test-util")]
+impl Socket for DetachedSocket {
+    fn poll_recv_from(
+        &mut self,
+        _cx: &mut Context,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        Poll::Pending
+    }
+
+    fn poll_send_to(
+        &mut self,
+        _cx: &mut Context,
+        _buf: &[u8],
+        _target: &SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        Poll::Pending
+    }
+
+    fn join_multicast_v4(&self, _multiaddr: Ipv4Addr, _interface: Ipv4Addr) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn join_multicast_v6(&self, _multiaddr: &Ipv6Addr, _interface: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn leave_multicast_v4(&self, _multiaddr: Ipv4Addr, _interface: Ipv4Addr) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn leave_multicast_v6(&self, _multiaddr: &Ipv6Addr, _interface: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_multicast_if_v4(&self, _interface: Ipv4Addr) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_multicast_if_v6(&self, _interface: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub use self::mock::{Join, MockSocket, MulticastIf};
+
+#[cfg(test)]
+mod mock {
+    use super::Socket;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::task::{Context, Poll};
+
+    /// A multicast membership requested on a `MockSocket`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Join {
+        V4(Ipv4Addr, Ipv4Addr),
+        V6(Ipv6Addr, u32),
+    }
+
+    /// Egress interface set for multicast
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum MulticastIf {
+        V4(Ipv4Addr),
+        V6(u32),
+    }
+
+    #[derive(Default)]
+    pub struct MockState {
+        /// Datagrams (or errors) handed out by `poll_recv_from`, in order
+        pub incoming: VecDeque<io::Result<(Vec<u8>, SocketAddr)>>,
+        /// Everything passed to `poll_send_to`
+        pub sent: Vec<(Vec<u8>, SocketAddr)>,
+        pub joins: Vec<Join>,
+        /// Memberships dropped, recorded like `joins`
+        pub leaves: Vec<Join>,
+        pub multicast_if: Option<MulticastIf>,
+        /// Never ready to send while set, like a socket whose buffer is full
+        pub stalled: bool,
+        /// Fails to join the multicast group while set
+        pub fail_joins: bool,
+        /// Targets and sources of what was sent through `poll_send_from`
+        pub sent_from: Vec<(SocketAddr, IpAddr)>,
+        /// Interface `poll_recv_from_iface` says every datagram arrived on
+        pub arrival: Option<u32>,
+        /// Targets and interfaces of what was sent through `poll_send_out_of`
+        pub sent_out_of: Vec<(SocketAddr, u32)>,
+    }
+
+    /// An in-memory socket whose state stays inspectable after it has been
+    /// moved into an `FSM`
+    #[derive(Clone, Default)]
+    pub struct MockSocket(Arc<Mutex<MockState>>);
+
+    impl MockSocket {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn state(&self) -> MutexGuard<'_, MockState> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    impl Socket for MockSocket {
+        fn poll_recv_from(
+            &mut self,
+            _cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<(usize, SocketAddr)>> {
+            match self.state().incoming.pop_front() {
+                Some(Ok((packet, addr))) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    Poll::Ready(Ok((packet.len(), addr)))
+                }
+                Some(Err(err)) => Poll::Ready(Err(err)),
+                None => Poll::Pending,
+            }
+        }
+
+        fn poll_recv_from_iface(
+            &mut self,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<(usize, SocketAddr, Option<u32>)>> {
+            let arrival = self.state().arrival;
+            self.poll_recv_from(cx, buf)
+                .map_ok(|(len, addr)| (len, addr, arrival))
+        }
+
+        fn poll_send_to(
+            &mut self,
+            _cx: &mut Context,
+            buf: &[u8],
+            target: &SocketAddr,
+        ) -> Poll<io::Result<usize>> {
+            if self.state().stalled {
+                return Poll::Pending;
+            }
+            self.state().sent.push((buf.to_vec(), *target));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_send_from(
+            &mut self,
+            cx: &mut Context,
+            buf: &[u8],
+            target: &SocketAddr,
+            source: IpAddr,
+        ) -> Poll<io::Result<usize>> {
+            let sent = self.poll_send_to(cx, buf, target);
+            if sent.is_ready() {
+                self.state().sent_from.push((*target, source));
+            }
+            sent
+        }
+
+        fn poll_send_out_of(
+            &mut self,
+            cx: &mut Context,
+            buf: &[u8],
+            target: &SocketAddr,
+            interface: u32,
+            source: Option<IpAddr>,
+        ) -> Poll<io::Result<usize>> {
+            let sent = match source {
+                Some(source) => self.poll_send_from(cx, buf, target, source),
+                None => self.poll_send_to(cx, buf, target),
+            };
+            if sent.is_ready() {
+                self.state().sent_out_of.push((*target, interface));
+            }
+            sent
+        }
+
+        fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+            if self.state().fail_joins {
+                return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+            }
+            self.state().joins.push(Join::V4(multiaddr, interface));
+            Ok(())
+        }
+
+        fn join_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+            if self.state().fail_joins {
+                return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+            }
+            self.state().joins.push(Join::V6(*multiaddr, interface));
+            Ok(())
+        }
+
+        fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+            self.state().leaves.push(Join::V4(multiaddr, interface));
+            Ok(())
+        }
+
+        fn leave_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+            self.state().leaves.push(Join::V6(*multiaddr, interface));
+            Ok(())
+        }
+
+        fn set_multicast_if_v4(&self, interface: Ipv4Addr) -> io::Result<()> {
+            self.state().multicast_if = Some(MulticastIf::V4(interface));
+            Ok(())
+        }
+
+        fn set_multicast_if_v6(&self, interface: u32) -> io::Result<()> {
+            self.state().multicast_if = Some(MulticastIf::V6(interface));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{disable_multicast_all, enable_pktinfo, Socket};
+    use crate::error::Error;
+    use crate::interfaces::{Interfaces, SystemInterfaces};
+    use std::net::{IpAddr, SocketAddr};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn sends_from_chosen_source() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = receiver.local_addr().unwrap();
+        // Any address of 127.0.0.0/8 is ours, but not the default source
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let std_socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+            let mut socket = tokio::net::UdpSocket::from_std(std_socket).unwrap();
+            futures_util::future::poll_fn(|cx| socket.poll_send_from(cx, b"hi", &target, source))
+                .await
+                .unwrap();
+        });
+
+        let mut buf = [0; 16];
+        let (len, from): (usize, SocketAddr) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hi");
+        assert_eq!(from.ip(), source);
+    }
+
+    #[test]
+    fn only_receives_multicast_joined_on_the_socket() {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        disable_multicast_all(&socket, false).unwrap();
+        let mut value: libc::c_int = 1;
+        let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_MULTICAST_ALL,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(value, 0);
+    }
+
+    #[test]
+    fn captures_arrival_interface_and_replies_out_of_it() {
+        let querier = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let loopback = SystemInterfaces.index("lo").unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let std_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            enable_pktinfo(&std_socket, false).unwrap();
+            querier
+                .send_to(b"query", std_socket.local_addr().unwrap())
+                .unwrap();
+            let mut socket = tokio::net::UdpSocket::from_std(std_socket).unwrap();
+
+            let mut buf = [0; 16];
+            let (len, from, arrival) =
+                futures_util::future::poll_fn(|cx| socket.poll_recv_from_iface(cx, &mut buf))
+                    .await
+                    .unwrap();
+            assert_eq!(&buf[..len], b"query");
+            assert_eq!(from, querier.local_addr().unwrap());
+            assert_eq!(arrival, Some(loopback));
+
+            futures_util::future::poll_fn(|cx| {
+                socket.poll_send_out_of(cx, b"answer", &from, loopback, None)
+            })
+            .await
+            .unwrap();
+        });
+
+        let mut buf = [0; 16];
+        let (len, _) = querier.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"answer");
+    }
+
+    #[test]
+    fn fails_to_receive_datagrams_longer_than_the_buffer() {
+        let querier = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let std_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            enable_pktinfo(&std_socket, false).unwrap();
+            querier
+                .send_to(&[0; 32], std_socket.local_addr().unwrap())
+                .unwrap();
+            let mut socket = tokio::net::UdpSocket::from_std(std_socket).unwrap();
+
+            let mut buf = [0; 16];
+            let err = futures_util::future::poll_fn(|cx| socket.poll_recv_from_iface(cx, &mut buf))
+                .await
+                .unwrap_err();
+            match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+                Some(Error::BufferTooSmall(32, 16)) => (),
+                other => panic!("unexpected {:?}", other),
+            }
+        });
+    }
+}
@@ -0,0 +1,198 @@
+use crate::config::Config;
+use crate::dns_parser::Name;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// State shared between the `FSM`s and the handles given to users
+pub type SharedStats = Arc<Stats>;
+
+/// Most sources remembered for `Stats::recent_queriers`, the one heard from
+/// longest ago making way for a new one past that
+const MAX_QUERIERS: usize = 1024;
+
+pub struct Stats {
+    announcements: Mutex<HashMap<Name<'static>, AnnouncementStatus>>,
+    queriers: Mutex<HashMap<SocketAddr, QuerierActivity>>,
+    /// How long a querier is remembered after its last query
+    querier_window: Duration,
+    /// Whether the IPv4 and the IPv6 socket (in that order) seem usable
+    healthy: Mutex<[bool; 2]>,
+    /// Questions skipped for being past `Config::max_questions`
+    ignored_questions: AtomicU64,
+    /// Responses sent as copies of earlier ones, see
+    /// `Config::prebuilt_responses`
+    reused_responses: AtomicU64,
+    /// Datagrams dropped from the send queue rather than sent, while
+    /// observing only
+    withheld_datagrams: AtomicU64,
+    /// Signalled with every announcement sent, for `Responder::ready`
+    announced: watch::Sender<()>,
+    announced_rx: watch::Receiver<()>,
+    /// Datagrams waiting in the send queue of the IPv4 and the IPv6 `FSM`
+    queue_depth: [AtomicUsize; 2],
+}
+
+/// Queries seen from one source since it last went quiet for a whole window
+struct QuerierActivity {
+    count: u32,
+    last_seen: Instant,
+}
+
+/// How a queued datagram left the send queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// The socket wasn't ready, so the datagram was dropped
+    WouldBlock,
+    Failed,
+}
+
+/// What is known about the unsolicited announcements of a service
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AnnouncementStatus {
+    /// When an announcement of the service last left the socket
+    pub last_announced: Option<Instant>,
+    /// Announcements sent so far, counting each address family separately
+    pub announcements: u32,
+    /// Outcome of the most recent attempt to send an announcement
+    pub last_outcome: Option<SendOutcome>,
+}
+
+impl Stats {
+    pub fn new(config: &Config) -> Self {
+        let (announced, announced_rx) = watch::channel(());
+        Stats {
+            announcements: Mutex::new(HashMap::new()),
+            queriers: Mutex::new(HashMap::new()),
+            querier_window: config.querier_window,
+            healthy: Mutex::new([true, true]),
+            ignored_questions: AtomicU64::new(0),
+            reused_responses: AtomicU64::new(0),
+            withheld_datagrams: AtomicU64::new(0),
+            announced,
+            announced_rx,
+            queue_depth: [AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    pub fn record_announcement(&self, name: &Name<'static>, outcome: SendOutcome, at: Instant) {
+        {
+            let mut announcements = self.announcements.lock().unwrap();
+            let status = announcements.entry(name.clone()).or_default();
+            if outcome == SendOutcome::Sent {
+                status.last_announced = Some(at);
+                status.announcements += 1;
+            }
+            status.last_outcome = Some(outcome);
+        }
+        if outcome == SendOutcome::Sent {
+            let _ = self.announced.broadcast(());
+        }
+    }
+
+    /// Yields whenever an announcement was sent, and right away when first
+    /// waited on
+    pub fn announced(&self) -> watch::Receiver<()> {
+        self.announced_rx.clone()
+    }
+
+    pub fn announcement_status(&self, name: &Name<'static>) -> AnnouncementStatus {
+        let announcements = self.announcements.lock().unwrap();
+        announcements.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn forget(&self, name: &Name<'static>) {
+        self.announcements.lock().unwrap().remove(name);
+    }
+
+    pub fn record_query(&self, from: SocketAddr, at: Instant) {
+        let mut queriers = self.queriers.lock().unwrap();
+        // Keep the map from growing unbounded under a flood of sources, such
+        // as one-shot resolvers each querying from a port of their own
+        if !queriers.contains_key(&from) && queriers.len() >= MAX_QUERIERS {
+            let window = self.querier_window;
+            queriers.retain(|_, activity| at.duration_since(activity.last_seen) <= window);
+            if queriers.len() >= MAX_QUERIERS {
+                let oldest = queriers
+                    .iter()
+                    .min_by_key(|(_, activity)| activity.last_seen)
+                    .map(|(&addr, _)| addr);
+                if let Some(oldest) = oldest {
+                    queriers.remove(&oldest);
+                }
+            }
+        }
+
+        let activity = queriers.entry(from).or_insert(QuerierActivity {
+            count: 0,
+            last_seen: at,
+        });
+        if at.duration_since(activity.last_seen) > self.querier_window {
+            activity.count = 0;
+        }
+        activity.count += 1;
+        activity.last_seen = at;
+    }
+
+    /// Sources that queried within the window before `now`, with how many
+    /// queries each sent and when it last did, ordered by address
+    pub fn recent_queriers(&self, now: Instant) -> Vec<(SocketAddr, u32, Instant)> {
+        let mut queriers = self.queriers.lock().unwrap();
+        let window = self.querier_window;
+        queriers.retain(|_, activity| now.duration_since(activity.last_seen) <= window);
+
+        let mut recent: Vec<_> = queriers
+            .iter()
+            .map(|(&addr, activity)| (addr, activity.count, activity.last_seen))
+            .collect();
+        recent.sort_by_key(|&(addr, _, _)| addr);
+        recent
+    }
+
+    pub fn set_healthy(&self, v6: bool, healthy: bool) {
+        self.healthy.lock().unwrap()[v6 as usize] = healthy;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.lock().unwrap().iter().all(|&healthy| healthy)
+    }
+
+    pub fn record_ignored_questions(&self, count: u64) {
+        self.ignored_questions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn ignored_questions(&self) -> u64 {
+        self.ignored_questions.load(Ordering::Relaxed)
+    }
+
+    pub fn record_reused_response(&self) {
+        self.reused_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reused_responses(&self) -> u64 {
+        self.reused_responses.load(Ordering::Relaxed)
+    }
+
+    pub fn record_withheld_datagrams(&self, count: u64) {
+        self.withheld_datagrams.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn withheld_datagrams(&self) -> u64 {
+        self.withheld_datagrams.load(Ordering::Relaxed)
+    }
+
+    pub fn set_queue_depth(&self, v6: bool, depth: usize) {
+        self.queue_depth[v6 as usize].store(depth, Ordering::Relaxed);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
+            .iter()
+            .map(|depth| depth.load(Ordering::Relaxed))
+            .sum()
+    }
+}